@@ -9,6 +9,12 @@ fn main() -> io::Result<()> {
         .collect();
     let mut config = prost_build::Config::new();
     config.disable_comments(["."]);
+    // Protobuf maps are encoded in iteration order, so ordered maps make the encoding of equal
+    // messages identical. Resource versions are hashes of their encodings, and must be stable.
+    config.btree_map(["."]);
+    // Every stream sent a resource shares one encoding of it, which Bytes lets responses
+    // reference rather than copy.
+    config.bytes([".google.protobuf.Any.value"]);
     tonic_build::configure()
         .build_server(true)
         .build_client(true)
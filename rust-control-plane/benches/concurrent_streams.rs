@@ -0,0 +1,114 @@
+// Measures how quickly snapshot updates reach many concurrent SotW streams, while one stream is
+// slow to read its responses.
+//
+// cargo bench -p rust-control-plane --bench concurrent_streams
+
+use data_plane_api::envoy::config::cluster::v3::Cluster;
+use data_plane_api::envoy::config::core::v3::Node;
+use data_plane_api::envoy::service::discovery::v3::DiscoveryRequest;
+use futures::future::join_all;
+use rust_control_plane::cache::snapshot::SnapshotCache;
+use rust_control_plane::cache::Cache;
+use rust_control_plane::service::stream_handle::StreamHandle;
+use rust_control_plane::snapshot::type_url::CLUSTER;
+use rust_control_plane::snapshot::{Resource, Resources, Snapshot};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+const STREAMS: usize = 1000;
+const ROUNDS: usize = 20;
+const CLUSTERS: usize = 100;
+const SLOW_STREAM_DELAY: Duration = Duration::from_millis(20);
+
+fn snapshot(version: usize) -> Snapshot {
+    let mut resources = Resources::new(version.to_string());
+    for i in 0..CLUSTERS {
+        let name = format!("cluster-{}", i);
+        resources.insert(
+            name.clone(),
+            Resource::Cluster(Cluster {
+                name,
+                ..Cluster::default()
+            }),
+        );
+    }
+    let mut snapshot = Snapshot::new();
+    snapshot.insert(CLUSTER.to_string(), resources);
+    snapshot
+}
+
+fn node(i: usize) -> String {
+    format!("node-{}", i)
+}
+
+// Acts like a stream, watching for new versions until it has seen the last one. Returns how
+// many responses it received.
+async fn run_stream(cache: Arc<SnapshotCache>, i: usize, delay: Option<Duration>) -> usize {
+    let handle = StreamHandle::new();
+    let last_version = ROUNDS.to_string();
+    let (tx, mut rx) = mpsc::channel(1);
+    let mut req = DiscoveryRequest {
+        node: Some(Node {
+            id: node(i),
+            ..Node::default()
+        }),
+        type_url: CLUSTER.to_string(),
+        ..DiscoveryRequest::default()
+    };
+    let mut responses = 0;
+    while req.version_info != last_version {
+        cache.create_watch(&req, tx.clone(), &handle).await.unwrap();
+        if let Some(delay) = delay {
+            tokio::time::sleep(delay).await;
+        }
+        let (_, rep) = rx.recv().await.unwrap();
+        req.version_info = rep.version_info;
+        responses += 1;
+    }
+    responses
+}
+
+#[tokio::main]
+async fn main() {
+    let cache = Arc::new(SnapshotCache::new(false));
+    let streams: Vec<_> = (0..STREAMS)
+        .map(|i| {
+            // The first stream stands in for a client on a congested connection.
+            let delay = if i == 0 {
+                Some(SLOW_STREAM_DELAY)
+            } else {
+                None
+            };
+            tokio::spawn(run_stream(cache.clone(), i, delay))
+        })
+        .collect();
+
+    let nodes: Vec<String> = (0..STREAMS).map(node).collect();
+    let start = Instant::now();
+    for round in 1..=ROUNDS {
+        let snapshot = snapshot(round);
+        let updates = nodes
+            .iter()
+            .map(|node| cache.set_snapshot(node, snapshot.clone()));
+        join_all(updates).await;
+    }
+    let updated = start.elapsed();
+
+    let mut responses = 0;
+    for stream in streams {
+        responses += stream.await.unwrap();
+    }
+    let elapsed = start.elapsed();
+
+    println!(
+        "streams={} rounds={} clusters={} responses={}",
+        STREAMS, ROUNDS, CLUSTERS, responses
+    );
+    println!(
+        "updates took {:?}, all streams done after {:?} ({:.0} responses/s)",
+        updated,
+        elapsed,
+        responses as f64 / elapsed.as_secs_f64()
+    );
+}
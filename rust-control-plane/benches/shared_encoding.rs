@@ -0,0 +1,115 @@
+// Compares encoding every resource for every stream, with sharing one lazily built encoding
+// between every stream sent the same snapshot, in time and allocations.
+//
+// cargo bench -p rust-control-plane --bench shared_encoding
+
+use data_plane_api::envoy::config::cluster::v3::Cluster;
+use data_plane_api::envoy::config::core::v3::Metadata;
+use data_plane_api::google::protobuf::{value, Any, Struct, Value};
+use rust_control_plane::snapshot::{Resource, Resources};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+const STREAMS: usize = 1000;
+const CLUSTERS: usize = 100;
+
+// Counts allocations, to show how many are saved by sharing encodings.
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+fn resources() -> Resources {
+    let mut resources = Resources::new("1".to_string());
+    for i in 0..CLUSTERS {
+        let name = format!("cluster-{}", i);
+        // Metadata makes the clusters closer in size to real ones.
+        let fields = (0..20)
+            .map(|j| {
+                let value = Value {
+                    kind: Some(value::Kind::StringValue(format!("value-{}", j))),
+                };
+                (format!("key-{}", j), value)
+            })
+            .collect();
+        let metadata = Metadata {
+            filter_metadata: BTreeMap::from([("bench".to_string(), Struct { fields })]),
+            ..Metadata::default()
+        };
+        resources.insert(
+            name.clone(),
+            Resource::Cluster(Cluster {
+                name,
+                metadata: Some(metadata),
+                ..Cluster::default()
+            }),
+        );
+    }
+    resources
+}
+
+// Runs f once per stream, returning how long it took, and how many allocations and bytes it
+// allocated.
+fn measure<F: FnMut() -> Vec<Any>>(mut f: F) -> (Duration, usize, usize) {
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let bytes = ALLOCATED_BYTES.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0..STREAMS {
+        std::hint::black_box(f());
+    }
+    (
+        start.elapsed(),
+        ALLOCATIONS.load(Ordering::Relaxed) - allocations,
+        ALLOCATED_BYTES.load(Ordering::Relaxed) - bytes,
+    )
+}
+
+fn report(name: &str, (elapsed, allocations, bytes): (Duration, usize, usize)) {
+    println!(
+        "{}: {:?}, {} allocations ({} per stream), {} KiB allocated ({} bytes per stream)",
+        name,
+        elapsed,
+        allocations,
+        allocations / STREAMS,
+        bytes / 1024,
+        bytes / STREAMS
+    );
+}
+
+fn main() {
+    let resources = resources();
+    println!("{} streams, {} clusters each", STREAMS, CLUSTERS);
+    report(
+        "encode per stream",
+        measure(|| {
+            resources
+                .items
+                .values()
+                .map(|item| item.resource().into_any())
+                .collect()
+        }),
+    );
+    // Once encoded, each stream only allocates its response's Vec and each Any's type URL, as the
+    // encodings themselves are shared.
+    report(
+        "shared encoding",
+        measure(|| resources.items.values().map(|item| item.to_any()).collect()),
+    );
+}
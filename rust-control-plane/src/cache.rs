@@ -1,3 +1,7 @@
+pub mod linear;
+pub mod mux;
+pub mod node_hash;
+mod response;
 pub mod snapshot;
 
 use crate::service::stream_handle::{DeltaStreamHandle, StreamHandle};
@@ -6,14 +10,28 @@ use data_plane_api::envoy::service::discovery::v3::{
     DeltaDiscoveryRequest, DeltaDiscoveryResponse, DiscoveryRequest, DiscoveryResponse,
 };
 use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use tokio::sync::mpsc;
 
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
 pub struct WatchId {
     pub node_id: String,
+    // Unique across every watch created in the process, and never reused, so that cancelling a
+    // watch which has already fired can't cancel another watch.
     pub index: usize,
 }
 
+static NEXT_WATCH_INDEX: AtomicUsize = AtomicUsize::new(0);
+
+impl WatchId {
+    pub fn new(node_id: &str) -> Self {
+        Self {
+            node_id: node_id.to_string(),
+            index: NEXT_WATCH_INDEX.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+}
+
 pub type KnownResourceNames = HashMap<String, HashSet<String>>;
 
 pub type WatchResponse = (DiscoveryRequest, DiscoveryResponse);
@@ -22,7 +40,9 @@ pub type WatchResponder = mpsc::Sender<WatchResponse>;
 
 pub type NextVersionMap = HashMap<String, String>;
 
-pub type DeltaWatchResponse = (DeltaDiscoveryResponse, NextVersionMap);
+// Along with the ID of the watch which responded, or None if the cache responded as the watch
+// was created, without returning an ID.
+pub type DeltaWatchResponse = (DeltaDiscoveryResponse, NextVersionMap, Option<WatchId>);
 
 pub type DeltaWatchResponder = mpsc::Sender<DeltaWatchResponse>;
 
@@ -31,6 +51,12 @@ pub enum FetchError {
     NotFound,
 }
 
+#[derive(Clone, Debug, PartialEq)]
+pub enum WatchError {
+    // The cache doesn't serve resources of the request's type, so the watch would never fire.
+    UnsupportedType,
+}
+
 #[async_trait]
 pub trait Cache: Sync + Send + 'static {
     async fn create_watch(
@@ -38,13 +64,13 @@ pub trait Cache: Sync + Send + 'static {
         req: &DiscoveryRequest,
         tx: WatchResponder,
         stream: &StreamHandle,
-    ) -> Option<WatchId>;
+    ) -> Result<Option<WatchId>, WatchError>;
     async fn create_delta_watch(
         &self,
         req: &DeltaDiscoveryRequest,
         tx: DeltaWatchResponder,
         stream: &DeltaStreamHandle,
-    ) -> Option<WatchId>;
+    ) -> Result<Option<WatchId>, WatchError>;
     async fn cancel_watch(&self, watch_id: &WatchId);
     async fn cancel_delta_watch(&self, watch_id: &WatchId);
     async fn fetch<'a>(
@@ -0,0 +1,433 @@
+#[cfg(test)]
+mod test;
+
+use crate::cache::node_hash::{IdHash, NodeHash};
+use crate::cache::response::{build_response, PendingResponses};
+use crate::cache::{Cache, DeltaWatchResponder, FetchError, WatchError, WatchId, WatchResponder};
+use crate::service::stream_handle::{DeltaStreamHandle, StreamHandle};
+use crate::snapshot::{type_url, Resource, Resources, VersionedResource};
+use async_trait::async_trait;
+use data_plane_api::envoy::service::discovery::v3::{
+    DeltaDiscoveryRequest, DiscoveryRequest, DiscoveryResponse,
+};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+// A cache holding resources of a single type, shared by every node. Each resource is versioned
+// independently, so updating one resource only wakes the watches interested in it, rather than
+// every watch as a new snapshot would.
+// NB: SotW versions are a counter behind a prefix which is unique to each cache, so that a
+// client holding a version from another cache, e.g. from before the control plane restarted,
+// is always sent the resources rather than mistaken as up to date.
+#[derive(Debug)]
+pub struct LinearCache {
+    type_url: String,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    resources: Resources,
+    // Incremented on every change, and used as the version of SotW responses, after the prefix.
+    version: u64,
+    version_prefix: String,
+    // The cache version at which each resource was last modified.
+    version_vector: HashMap<String, u64>,
+    watches: HashMap<usize, Watch>,
+    watches_by_name: HashMap<String, HashSet<usize>>,
+    wildcard_watches: HashSet<usize>,
+    delta_watches: HashMap<usize, DeltaWatch>,
+}
+
+#[derive(Debug)]
+struct Watch {
+    req: DiscoveryRequest,
+    tx: WatchResponder,
+}
+
+#[derive(Debug)]
+struct DeltaWatch {
+    req: DeltaDiscoveryRequest,
+    tx: DeltaWatchResponder,
+    stream: DeltaStreamHandle,
+}
+
+impl LinearCache {
+    pub fn new(type_url: &str) -> Self {
+        Self {
+            type_url: type_url.to_string(),
+            inner: Mutex::new(Inner::new(instance_prefix())),
+        }
+    }
+
+    // Replaces the prefix of SotW versions, which is otherwise unique to the cache. A fixed
+    // prefix must differ between caches a client may switch between, such as before and after
+    // a restart.
+    pub fn with_version_prefix(mut self, prefix: &str) -> Self {
+        let inner = self.inner.get_mut();
+        inner.version_prefix = prefix.to_string();
+        inner.resources.version = format!("{}{}", prefix, inner.version);
+        self
+    }
+
+    pub fn type_url(&self) -> &str {
+        &self.type_url
+    }
+
+    // Inserts or replaces a single resource, triggering the watches interested in it.
+    pub async fn update_resource(&self, name: &str, resource: Resource) {
+        let mut inner = self.inner.lock().await;
+        let resource = VersionedResource::new(resource);
+        if matches!(inner.resources.items.get(name), Some(current) if current.version == resource.version)
+        {
+            info!("resource unchanged name={}", name);
+            return;
+        }
+        inner.bump_version();
+        let version = inner.version;
+        inner.version_vector.insert(name.to_string(), version);
+        inner.resources.items.insert(name.to_string(), resource);
+        let responses = inner.notify(&HashSet::from([name.to_string()]));
+        drop(inner);
+        responses.send().await;
+    }
+
+    // Deletes a single resource, triggering the watches interested in it.
+    pub async fn delete_resource(&self, name: &str) {
+        let mut inner = self.inner.lock().await;
+        if inner.resources.items.remove(name).is_none() {
+            return;
+        }
+        inner.bump_version();
+        inner.version_vector.remove(name);
+        let responses = inner.notify(&HashSet::from([name.to_string()]));
+        drop(inner);
+        responses.send().await;
+    }
+
+    // Replaces every resource in the cache, triggering the watches interested in the resources
+    // that were added, modified, or removed.
+    pub async fn set_resources(&self, resources: HashMap<String, Resource>) {
+        let mut inner = self.inner.lock().await;
+        let resources: HashMap<String, VersionedResource> = resources
+            .into_iter()
+            .map(|(name, resource)| (name, VersionedResource::new(resource)))
+            .collect();
+        let mut modified = HashSet::new();
+        for name in inner.resources.items.keys() {
+            if !resources.contains_key(name) {
+                modified.insert(name.clone());
+            }
+        }
+        for (name, resource) in &resources {
+            if !matches!(inner.resources.items.get(name), Some(current) if current.version == resource.version)
+            {
+                modified.insert(name.clone());
+            }
+        }
+        if modified.is_empty() {
+            info!("resources unchanged");
+            return;
+        }
+        inner.bump_version();
+        let version = inner.version;
+        for name in &modified {
+            if resources.contains_key(name) {
+                inner.version_vector.insert(name.clone(), version);
+            } else {
+                inner.version_vector.remove(name);
+            }
+        }
+        inner.resources.items = resources;
+        let responses = inner.notify(&modified);
+        drop(inner);
+        responses.send().await;
+    }
+
+    pub async fn get_resource(&self, name: &str) -> Option<Resource> {
+        let inner = self.inner.lock().await;
+        inner.resources.get(name).cloned()
+    }
+
+    pub async fn version(&self) -> String {
+        let inner = self.inner.lock().await;
+        inner.resources.version.clone()
+    }
+}
+
+#[async_trait]
+impl Cache for LinearCache {
+    // Either responds on tx immediately, or sets a watch, returning a watch ID.
+    async fn create_watch(
+        &self,
+        req: &DiscoveryRequest,
+        tx: WatchResponder,
+        stream: &StreamHandle,
+    ) -> Result<Option<WatchId>, WatchError> {
+        if req.type_url != self.type_url {
+            warn!(
+                "linear cache for type_url={} cannot serve type_url={}",
+                self.type_url, req.type_url
+            );
+            return Err(WatchError::UnsupportedType);
+        }
+        let mut inner = self.inner.lock().await;
+        if inner.is_stale(req, stream.known_resource_names(&req.type_url)) {
+            info!("responding: stale version");
+            let mut responses = PendingResponses::new();
+            responses.respond(req, tx, Some(&inner.resources), &inner.resources.version);
+            drop(inner);
+            responses.send().await;
+            return Ok(None);
+        }
+        info!("set watch: latest version");
+        Ok(Some(inner.set_watch(req, tx)))
+    }
+
+    // Deletes a watch previously created with create_watch.
+    async fn cancel_watch(&self, watch_id: &WatchId) {
+        let mut inner = self.inner.lock().await;
+        inner.remove_watch(watch_id.index);
+    }
+
+    // Deletes a watch previously created with create_delta_watch.
+    async fn cancel_delta_watch(&self, watch_id: &WatchId) {
+        let mut inner = self.inner.lock().await;
+        inner.delta_watches.remove(&watch_id.index);
+    }
+
+    async fn fetch<'a>(
+        &'a self,
+        req: &'a DiscoveryRequest,
+        type_url: &'static str,
+    ) -> Result<DiscoveryResponse, FetchError> {
+        if type_url != self.type_url {
+            return Err(FetchError::NotFound);
+        }
+        let inner = self.inner.lock().await;
+        let version = &inner.resources.version;
+        if &req.version_info == version {
+            return Err(FetchError::VersionUpToDate);
+        }
+        Ok(build_response(req, Some(&inner.resources), version))
+    }
+
+    async fn create_delta_watch(
+        &self,
+        req: &DeltaDiscoveryRequest,
+        tx: DeltaWatchResponder,
+        stream: &DeltaStreamHandle,
+    ) -> Result<Option<WatchId>, WatchError> {
+        if req.type_url != self.type_url {
+            warn!(
+                "linear cache for type_url={} cannot serve type_url={}",
+                self.type_url, req.type_url
+            );
+            return Err(WatchError::UnsupportedType);
+        }
+        let mut inner = self.inner.lock().await;
+        let mut responses = PendingResponses::new();
+        if responses.try_respond_delta(req, None, tx.clone(), stream, &inner.resources) {
+            drop(inner);
+            responses.send().await;
+            return Ok(None);
+        }
+        info!("set delta watch");
+        let watch_id = WatchId::new(&IdHash.hash(&req.node));
+        inner.delta_watches.insert(
+            watch_id.index,
+            DeltaWatch {
+                req: req.clone(),
+                tx,
+                stream: stream.clone(),
+            },
+        );
+        Ok(Some(watch_id))
+    }
+}
+
+impl Inner {
+    fn new(version_prefix: String) -> Self {
+        Self {
+            resources: Resources::new(format!("{}0", version_prefix)),
+            version: 0,
+            version_prefix,
+            version_vector: HashMap::new(),
+            watches: HashMap::new(),
+            watches_by_name: HashMap::new(),
+            wildcard_watches: HashSet::new(),
+            delta_watches: HashMap::new(),
+        }
+    }
+
+    fn bump_version(&mut self) {
+        self.version += 1;
+        self.resources.version = format!("{}{}", self.version_prefix, self.version);
+    }
+
+    // A request is stale if the client has never seen a version from this cache, or if any of
+    // the resources it's requesting have changed, or are new to it, since that version.
+    fn is_stale(
+        &self,
+        req: &DiscoveryRequest,
+        type_known_resource_names: Option<&HashSet<String>>,
+    ) -> bool {
+        let last_version = match req
+            .version_info
+            .strip_prefix(&self.version_prefix)
+            .map(str::parse::<u64>)
+        {
+            Some(Ok(version)) if version <= self.version => version,
+            _ => return true,
+        };
+        if req.resource_names.is_empty() {
+            return last_version != self.version;
+        }
+        req.resource_names.iter().any(|name| {
+            let modified = matches!(
+                self.version_vector.get(name),
+                Some(version) if *version > last_version
+            );
+            let known = matches!(
+                type_known_resource_names,
+                Some(known) if known.contains(name)
+            );
+            let unknown = self.resources.items.contains_key(name) && !known;
+            modified || unknown
+        })
+    }
+
+    fn set_watch(&mut self, req: &DiscoveryRequest, tx: WatchResponder) -> WatchId {
+        let watch_id = WatchId::new(&IdHash.hash(&req.node));
+        let index = watch_id.index;
+        self.watches.insert(
+            index,
+            Watch {
+                req: req.clone(),
+                tx,
+            },
+        );
+        if req.resource_names.is_empty() {
+            self.wildcard_watches.insert(index);
+        } else {
+            for name in &req.resource_names {
+                self.watches_by_name
+                    .entry(name.clone())
+                    .or_default()
+                    .insert(index);
+            }
+        }
+        watch_id
+    }
+
+    fn remove_watch(&mut self, index: usize) -> Option<Watch> {
+        let watch = self.watches.remove(&index)?;
+        if watch.req.resource_names.is_empty() {
+            self.wildcard_watches.remove(&index);
+        } else {
+            for name in &watch.req.resource_names {
+                if let Some(indexes) = self.watches_by_name.get_mut(name) {
+                    indexes.remove(&index);
+                    if indexes.is_empty() {
+                        self.watches_by_name.remove(name);
+                    }
+                }
+            }
+        }
+        Some(watch)
+    }
+
+    // Builds responses to the SotW and delta watches interested in any of the modified
+    // resources, removing the watches. The responses must be sent after releasing the lock.
+    fn notify(&mut self, modified: &HashSet<String>) -> PendingResponses {
+        let mut responses = PendingResponses::new();
+        let mut to_respond = self.wildcard_watches.clone();
+        for name in modified {
+            if let Some(indexes) = self.watches_by_name.get(name) {
+                to_respond.extend(indexes);
+            }
+        }
+        for index in to_respond {
+            if let Some(watch) = self.remove_watch(index) {
+                if watch.tx.is_closed() {
+                    // The stream closed without cancelling its watch.
+                    continue;
+                }
+                info!(
+                    "watch triggered version={} type_url={}",
+                    self.resources.version, &watch.req.type_url
+                );
+                responses.respond(
+                    &watch.req,
+                    watch.tx,
+                    Some(&self.resources),
+                    &self.resources.version,
+                );
+            }
+        }
+
+        let mut to_delete = Vec::new();
+        for (index, watch) in &self.delta_watches {
+            if watch.tx.is_closed() {
+                to_delete.push(*index);
+                continue;
+            }
+            let subscribed = watch.stream.subscribed_resource_names();
+            // VHDS subscriptions to names which aren't resources may be aliases, which can
+            // resolve to a modified virtual host of the same route configuration.
+            let interested = watch.stream.is_wildcard()
+                || modified.iter().any(|name| subscribed.contains(name))
+                || (watch.req.type_url == type_url::VIRTUAL_HOST
+                    && subscribed.iter().any(|name| {
+                        !self.resources.items.contains_key(name) && may_alias(name, modified)
+                    }));
+            if !interested {
+                continue;
+            }
+            info!("delta watch triggered type_url={}", &watch.req.type_url);
+            let watch_id = WatchId {
+                node_id: IdHash.hash(&watch.req.node),
+                index: *index,
+            };
+            let responded = responses.try_respond_delta(
+                &watch.req,
+                Some(watch_id),
+                watch.tx.clone(),
+                &watch.stream,
+                &self.resources,
+            );
+            if responded {
+                to_delete.push(*index);
+            }
+        }
+        for index in to_delete {
+            self.delta_watches.remove(&index);
+        }
+        responses
+    }
+}
+
+// A prefix for the versions of a new cache, unique to it within the process, and across
+// processes as it includes the time at which the cache was created.
+fn instance_prefix() -> String {
+    static NEXT_INSTANCE: AtomicUsize = AtomicUsize::new(0);
+    let created = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_nanos());
+    let instance = NEXT_INSTANCE.fetch_add(1, Ordering::Relaxed);
+    format!("{:x}-{}-", created, instance)
+}
+
+// Whether a <route_config_name>/<host> alias could resolve to any of the modified virtual hosts,
+// which are named <route_config_name>/<virtual_host_name>.
+fn may_alias(alias: &str, modified: &HashSet<String>) -> bool {
+    let route_config_name = match alias.rsplit_once('/') {
+        Some((route_config_name, _)) => route_config_name,
+        None => return false,
+    };
+    let prefix = format!("{}/", route_config_name);
+    modified.iter().any(|name| name.starts_with(&prefix))
+}
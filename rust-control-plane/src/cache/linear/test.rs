@@ -0,0 +1,280 @@
+use crate::cache::linear::LinearCache;
+use crate::cache::{Cache, WatchError};
+use crate::service::stream_handle::{DeltaStreamHandle, StreamHandle};
+use crate::snapshot::type_url::{CLUSTER, ENDPOINT, VIRTUAL_HOST};
+use crate::snapshot::Resource;
+use data_plane_api::envoy::config::endpoint::v3::ClusterLoadAssignment;
+use data_plane_api::envoy::config::route::v3::VirtualHost;
+use data_plane_api::envoy::service::discovery::v3::{DeltaDiscoveryRequest, DiscoveryRequest};
+use std::collections::HashMap;
+use tokio::sync::mpsc;
+
+fn endpoint(name: &str) -> Resource {
+    Resource::Endpoint(ClusterLoadAssignment {
+        cluster_name: name.to_string(),
+        ..ClusterLoadAssignment::default()
+    })
+}
+
+// Without a version prefix, so that versions are just the cache's counter.
+fn cache(type_url: &str) -> LinearCache {
+    LinearCache::new(type_url).with_version_prefix("")
+}
+
+fn request(version: &str, names: &[&str]) -> DiscoveryRequest {
+    DiscoveryRequest {
+        type_url: ENDPOINT.to_string(),
+        version_info: version.to_string(),
+        resource_names: names.iter().map(|name| name.to_string()).collect(),
+        ..DiscoveryRequest::default()
+    }
+}
+
+#[tokio::test]
+async fn test_linear_cache_responds_immediately_to_new_client() {
+    let cache = cache(ENDPOINT);
+    cache.update_resource("a", endpoint("a")).await;
+    let (tx, mut rx) = mpsc::channel(1);
+    let watch_id = cache
+        .create_watch(&request("", &[]), tx, &StreamHandle::new())
+        .await
+        .unwrap();
+    assert!(watch_id.is_none());
+    let (_, rep) = rx.try_recv().unwrap();
+    assert_eq!(rep.version_info, "1");
+    assert_eq!(rep.resources.len(), 1);
+}
+
+#[tokio::test]
+async fn test_linear_cache_update_only_wakes_interested_watches() {
+    let cache = cache(ENDPOINT);
+    cache.update_resource("a", endpoint("a")).await;
+    cache.update_resource("b", endpoint("b")).await;
+    let mut handle = StreamHandle::new();
+    handle.add_known_resource_names(ENDPOINT, &["a".to_string(), "b".to_string()]);
+
+    let (tx_a, mut rx_a) = mpsc::channel(1);
+    let (tx_b, mut rx_b) = mpsc::channel(1);
+    let (tx_all, mut rx_all) = mpsc::channel(1);
+    assert!(cache
+        .create_watch(&request("2", &["a"]), tx_a, &handle)
+        .await
+        .unwrap()
+        .is_some());
+    assert!(cache
+        .create_watch(&request("2", &["b"]), tx_b, &handle)
+        .await
+        .unwrap()
+        .is_some());
+    assert!(cache
+        .create_watch(&request("2", &[]), tx_all, &handle)
+        .await
+        .unwrap()
+        .is_some());
+
+    cache.update_resource("a", endpoint("a-modified")).await;
+    let (_, rep) = rx_a.try_recv().unwrap();
+    assert_eq!(rep.version_info, "3");
+    assert_eq!(rep.resources.len(), 1);
+    assert!(rx_b.try_recv().is_err());
+    let (_, rep) = rx_all.try_recv().unwrap();
+    assert_eq!(rep.resources.len(), 2);
+}
+
+#[tokio::test]
+async fn test_linear_cache_unchanged_update_does_not_bump_version() {
+    let cache = cache(ENDPOINT);
+    cache.update_resource("a", endpoint("a")).await;
+    cache.update_resource("a", endpoint("a")).await;
+    assert_eq!(cache.version().await, "1");
+    cache
+        .set_resources(HashMap::from([("a".to_string(), endpoint("a"))]))
+        .await;
+    assert_eq!(cache.version().await, "1");
+    cache.set_resources(HashMap::new()).await;
+    assert_eq!(cache.version().await, "2");
+}
+
+#[tokio::test]
+async fn test_linear_cache_delete_wakes_watch() {
+    let cache = cache(ENDPOINT);
+    cache.update_resource("a", endpoint("a")).await;
+    cache.update_resource("b", endpoint("b")).await;
+    let mut handle = StreamHandle::new();
+    handle.add_known_resource_names(ENDPOINT, &["a".to_string(), "b".to_string()]);
+    let (tx, mut rx) = mpsc::channel(1);
+    assert!(cache
+        .create_watch(&request("2", &["a", "b"]), tx, &handle)
+        .await
+        .unwrap()
+        .is_some());
+    cache.delete_resource("b").await;
+    let (_, rep) = rx.try_recv().unwrap();
+    assert_eq!(rep.version_info, "3");
+    assert_eq!(rep.resources.len(), 1);
+}
+
+#[tokio::test]
+async fn test_linear_cache_delta_watch_on_named_resource() {
+    let cache = cache(ENDPOINT);
+    cache.update_resource("a", endpoint("a")).await;
+    let req = DeltaDiscoveryRequest {
+        type_url: ENDPOINT.to_string(),
+        resource_names_subscribe: vec!["a".to_string()],
+        ..DeltaDiscoveryRequest::default()
+    };
+    let mut stream = DeltaStreamHandle::new(&req);
+    stream.apply_subscriptions(&req);
+
+    let (tx, mut rx) = mpsc::channel(1);
+    assert!(cache
+        .create_delta_watch(&req, tx.clone(), &stream)
+        .await
+        .unwrap()
+        .is_none());
+    let (rep, versions, watch_id) = rx.try_recv().unwrap();
+    assert_eq!(rep.resources.len(), 1);
+    assert_eq!(watch_id, None);
+    stream.set_resource_versions(versions);
+
+    let watch_id = cache.create_delta_watch(&req, tx, &stream).await.unwrap();
+    assert!(watch_id.is_some());
+    cache.update_resource("b", endpoint("b")).await;
+    assert!(rx.try_recv().is_err());
+    cache.update_resource("a", endpoint("a-modified")).await;
+    let (rep, _, fired) = rx.try_recv().unwrap();
+    assert_eq!(fired, watch_id);
+    assert_eq!(rep.resources.len(), 1);
+    assert_eq!(rep.resources[0].name, "a");
+}
+
+#[tokio::test]
+async fn test_linear_cache_rejects_other_type_urls() {
+    let cache = cache(ENDPOINT);
+    let (tx, mut rx) = mpsc::channel(1);
+    let req = DiscoveryRequest {
+        type_url: CLUSTER.to_string(),
+        ..DiscoveryRequest::default()
+    };
+    let err = cache
+        .create_watch(&req, tx.clone(), &StreamHandle::new())
+        .await
+        .unwrap_err();
+    assert_eq!(err, WatchError::UnsupportedType);
+    let req = DeltaDiscoveryRequest {
+        type_url: CLUSTER.to_string(),
+        ..DeltaDiscoveryRequest::default()
+    };
+    let (delta_tx, _) = mpsc::channel(1);
+    let err = cache
+        .create_delta_watch(&req, delta_tx, &DeltaStreamHandle::new(&req))
+        .await
+        .unwrap_err();
+    assert_eq!(err, WatchError::UnsupportedType);
+    assert!(rx.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn test_linear_cache_versions_differ_between_caches() {
+    let cache = LinearCache::new(ENDPOINT);
+    cache.update_resource("a", endpoint("a")).await;
+    let version = cache.version().await;
+    assert!(version.ends_with('1'));
+    let (tx, mut rx) = mpsc::channel(1);
+    assert!(cache
+        .create_watch(&request(&version, &[]), tx.clone(), &StreamHandle::new())
+        .await
+        .unwrap()
+        .is_some());
+
+    // A client holding the same version from a previous cache, e.g. before a restart, is sent
+    // the resources.
+    let restarted = LinearCache::new(ENDPOINT);
+    restarted.update_resource("b", endpoint("b")).await;
+    assert_ne!(restarted.version().await, version);
+    assert!(restarted
+        .create_watch(&request(&version, &[]), tx, &StreamHandle::new())
+        .await
+        .unwrap()
+        .is_none());
+    let (_, rep) = rx.try_recv().unwrap();
+    assert_eq!(rep.resources.len(), 1);
+
+    let prefixed = LinearCache::new(ENDPOINT).with_version_prefix("v1-");
+    assert_eq!(prefixed.version().await, "v1-0");
+}
+
+#[tokio::test]
+async fn test_linear_cache_cancelling_fired_watch_is_harmless() {
+    let cache = cache(ENDPOINT);
+    let handle = StreamHandle::new();
+    let (tx, mut rx) = mpsc::channel(1);
+    let fired = cache
+        .create_watch(&request("0", &[]), tx, &handle)
+        .await
+        .unwrap()
+        .unwrap();
+    cache.update_resource("a", endpoint("a")).await;
+    rx.try_recv().unwrap();
+
+    let (tx, mut rx) = mpsc::channel(1);
+    let watch_id = cache
+        .create_watch(&request("1", &[]), tx, &handle)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_ne!(watch_id, fired);
+    cache.cancel_watch(&fired).await;
+    cache.update_resource("b", endpoint("b")).await;
+    assert_eq!(rx.try_recv().unwrap().1.version_info, "2");
+}
+
+#[tokio::test]
+async fn test_linear_cache_alias_only_wakes_for_its_route_config() {
+    let cache = cache(VIRTUAL_HOST);
+    let virtual_host = |name: &str| {
+        Resource::VirtualHost(VirtualHost {
+            name: name.to_string(),
+            domains: vec!["example.com".to_string()],
+            ..VirtualHost::default()
+        })
+    };
+    let req = DeltaDiscoveryRequest {
+        type_url: VIRTUAL_HOST.to_string(),
+        resource_names_subscribe: vec!["routes/example.com".to_string()],
+        ..DeltaDiscoveryRequest::default()
+    };
+    let mut stream = DeltaStreamHandle::new(&req);
+    stream.apply_subscriptions(&req);
+    let (tx, mut rx) = mpsc::channel(1);
+    // The alias doesn't resolve yet, which the client is told straight away.
+    assert!(cache
+        .create_delta_watch(&req, tx.clone(), &stream)
+        .await
+        .unwrap()
+        .is_none());
+    let (rep, versions, _) = rx.try_recv().unwrap();
+    assert_eq!(rep.resources[0].name, "routes/example.com");
+    assert!(rep.resources[0].resource.is_none());
+    stream.set_resource_versions(versions);
+    assert!(cache
+        .create_delta_watch(&req, tx, &stream)
+        .await
+        .unwrap()
+        .is_some());
+
+    cache
+        .update_resource("other/example", virtual_host("other/example"))
+        .await;
+    assert!(rx.try_recv().is_err());
+    cache
+        .update_resource("routes/example", virtual_host("routes/example"))
+        .await;
+    let (rep, _, _) = rx.try_recv().unwrap();
+    assert_eq!(rep.resources.len(), 1);
+    assert_eq!(rep.resources[0].name, "routes/example");
+    assert_eq!(
+        rep.resources[0].aliases,
+        vec!["routes/example.com".to_string()]
+    );
+}
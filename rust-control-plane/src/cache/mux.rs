@@ -0,0 +1,108 @@
+#[cfg(test)]
+mod test;
+
+use crate::cache::{Cache, DeltaWatchResponder, FetchError, WatchError, WatchId, WatchResponder};
+use crate::service::stream_handle::{DeltaStreamHandle, StreamHandle};
+use async_trait::async_trait;
+use data_plane_api::envoy::config::core::v3::Node;
+use data_plane_api::envoy::service::discovery::v3::{
+    DeltaDiscoveryRequest, DiscoveryRequest, DiscoveryResponse,
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tracing::warn;
+
+// Picks the key of the cache that should serve a request, given the requesting node and the
+// request's type URL.
+pub type Classifier = Box<dyn Fn(&Option<Node>, &str) -> String + Send + Sync>;
+
+// A cache which forwards every request to one of several child caches, chosen by a classifier.
+// This allows, for example, serving fast-changing endpoints from a LinearCache and everything
+// else from a SnapshotCache behind a single ADS stream.
+// NB: Watch IDs are unique across every cache, so the mux hands out its children's IDs as they
+// are, and forwards cancellations to every child, which ignore IDs they don't know. The mux
+// keeps no state per watch, which would otherwise outlive watches that fire without being
+// cancelled.
+pub struct MuxCache {
+    classify: Classifier,
+    caches: HashMap<String, Arc<dyn Cache>>,
+}
+
+impl MuxCache {
+    pub fn new<F>(classify: F) -> Self
+    where
+        F: Fn(&Option<Node>, &str) -> String + Send + Sync + 'static,
+    {
+        Self {
+            classify: Box::new(classify),
+            caches: HashMap::new(),
+        }
+    }
+
+    // Registers a child cache to serve requests classified with the given key.
+    pub fn with_cache(mut self, key: &str, cache: Arc<dyn Cache>) -> Self {
+        self.caches.insert(key.to_string(), cache);
+        self
+    }
+
+    fn route(&self, node: &Option<Node>, type_url: &str) -> Option<&Arc<dyn Cache>> {
+        let key = (self.classify)(node, type_url);
+        match self.caches.get(&key) {
+            Some(cache) => Some(cache),
+            None => {
+                warn!("no cache for key={} type_url={}", key, type_url);
+                None
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Cache for MuxCache {
+    async fn create_watch(
+        &self,
+        req: &DiscoveryRequest,
+        tx: WatchResponder,
+        stream: &StreamHandle,
+    ) -> Result<Option<WatchId>, WatchError> {
+        let cache = self
+            .route(&req.node, &req.type_url)
+            .ok_or(WatchError::UnsupportedType)?;
+        cache.create_watch(req, tx, stream).await
+    }
+
+    async fn create_delta_watch(
+        &self,
+        req: &DeltaDiscoveryRequest,
+        tx: DeltaWatchResponder,
+        stream: &DeltaStreamHandle,
+    ) -> Result<Option<WatchId>, WatchError> {
+        let cache = self
+            .route(&req.node, &req.type_url)
+            .ok_or(WatchError::UnsupportedType)?;
+        cache.create_delta_watch(req, tx, stream).await
+    }
+
+    async fn cancel_watch(&self, watch_id: &WatchId) {
+        for cache in self.caches.values() {
+            cache.cancel_watch(watch_id).await;
+        }
+    }
+
+    async fn cancel_delta_watch(&self, watch_id: &WatchId) {
+        for cache in self.caches.values() {
+            cache.cancel_delta_watch(watch_id).await;
+        }
+    }
+
+    async fn fetch<'a>(
+        &'a self,
+        req: &'a DiscoveryRequest,
+        type_url: &'static str,
+    ) -> Result<DiscoveryResponse, FetchError> {
+        let cache = self
+            .route(&req.node, type_url)
+            .ok_or(FetchError::NotFound)?;
+        cache.fetch(req, type_url).await
+    }
+}
@@ -0,0 +1,123 @@
+use crate::cache::linear::LinearCache;
+use crate::cache::mux::MuxCache;
+use crate::cache::{Cache, WatchError};
+use crate::service::stream_handle::StreamHandle;
+use crate::snapshot::type_url::{CLUSTER, ENDPOINT, LISTENER};
+use crate::snapshot::Resource;
+use data_plane_api::envoy::config::cluster::v3::Cluster;
+use data_plane_api::envoy::config::endpoint::v3::ClusterLoadAssignment;
+use data_plane_api::envoy::service::discovery::v3::DiscoveryRequest;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+async fn mux() -> (MuxCache, Arc<LinearCache>, Arc<LinearCache>) {
+    let endpoints = Arc::new(LinearCache::new(ENDPOINT));
+    endpoints
+        .update_resource(
+            "a",
+            Resource::Endpoint(ClusterLoadAssignment {
+                cluster_name: "a".to_string(),
+                ..ClusterLoadAssignment::default()
+            }),
+        )
+        .await;
+    let clusters = Arc::new(LinearCache::new(CLUSTER));
+    clusters
+        .update_resource(
+            "a",
+            Resource::Cluster(Cluster {
+                name: "a".to_string(),
+                ..Cluster::default()
+            }),
+        )
+        .await;
+    let mux = MuxCache::new(|_, type_url| {
+        if type_url == ENDPOINT {
+            "eds".to_string()
+        } else {
+            "cds".to_string()
+        }
+    })
+    .with_cache("eds", endpoints.clone())
+    .with_cache("cds", clusters.clone());
+    (mux, endpoints, clusters)
+}
+
+fn request(type_url: &str, version: &str) -> DiscoveryRequest {
+    DiscoveryRequest {
+        type_url: type_url.to_string(),
+        version_info: version.to_string(),
+        ..DiscoveryRequest::default()
+    }
+}
+
+#[tokio::test]
+async fn test_mux_cache_routes_by_type_url() {
+    let (mux, _, _) = mux().await;
+    let (tx, mut rx) = mpsc::channel(2);
+    let handle = StreamHandle::new();
+    assert!(mux
+        .create_watch(&request(ENDPOINT, ""), tx.clone(), &handle)
+        .await
+        .unwrap()
+        .is_none());
+    assert!(mux
+        .create_watch(&request(CLUSTER, ""), tx, &handle)
+        .await
+        .unwrap()
+        .is_none());
+    let (_, rep) = rx.try_recv().unwrap();
+    assert_eq!(rep.type_url, ENDPOINT);
+    assert_eq!(rep.resources[0].type_url, ENDPOINT);
+    let (_, rep) = rx.try_recv().unwrap();
+    assert_eq!(rep.type_url, CLUSTER);
+    assert_eq!(rep.resources[0].type_url, CLUSTER);
+}
+
+#[tokio::test]
+async fn test_mux_cache_cancels_watch_on_child() {
+    let (mux, endpoints, clusters) = mux().await;
+    let (tx, mut rx) = mpsc::channel(1);
+    let handle = StreamHandle::new();
+    let version = endpoints.version().await;
+    let watch_id = mux
+        .create_watch(&request(ENDPOINT, &version), tx, &handle)
+        .await
+        .unwrap()
+        .unwrap();
+    clusters.delete_resource("a").await;
+    mux.cancel_watch(&watch_id).await;
+    endpoints.delete_resource("a").await;
+    assert!(rx.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn test_mux_cache_hands_out_child_watch_ids() {
+    let (mux, endpoints, _) = mux().await;
+    let (tx, mut rx) = mpsc::channel(1);
+    let handle = StreamHandle::new();
+    let version = endpoints.version().await;
+    let watch_id = mux
+        .create_watch(&request(ENDPOINT, &version), tx, &handle)
+        .await
+        .unwrap()
+        .unwrap();
+    // The child's ID works on the child directly, so the mux needn't track it.
+    endpoints.cancel_watch(&watch_id).await;
+    endpoints.delete_resource("a").await;
+    assert!(rx.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn test_mux_cache_unknown_key() {
+    let mux = MuxCache::new(|_, _| "unknown".to_string())
+        .with_cache("eds", Arc::new(LinearCache::new(ENDPOINT)));
+    let (tx, mut rx) = mpsc::channel(1);
+    let err = mux
+        .create_watch(&request(LISTENER, ""), tx, &StreamHandle::new())
+        .await
+        .unwrap_err();
+    assert_eq!(err, WatchError::UnsupportedType);
+    assert!(rx.try_recv().is_err());
+    assert!(mux.fetch(&request(LISTENER, ""), LISTENER).await.is_err());
+}
@@ -0,0 +1,81 @@
+#[cfg(test)]
+mod test;
+
+use data_plane_api::envoy::config::core::v3::Node;
+use data_plane_api::google::protobuf::value::Kind;
+use std::fmt::Debug;
+
+// Computes the key which a node's snapshot and status are stored under. Nodes which hash to the
+// same key share a snapshot, so e.g. identical sidecars can be served from one snapshot.
+pub trait NodeHash: Debug + Send + Sync + 'static {
+    fn hash(&self, node: &Option<Node>) -> String;
+}
+
+// Keys on the node's ID. This is the default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IdHash;
+
+impl NodeHash for IdHash {
+    fn hash(&self, node: &Option<Node>) -> String {
+        node.as_ref().map_or(String::new(), |node| node.id.clone())
+    }
+}
+
+// Keys on the node's cluster.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ClusterHash;
+
+impl NodeHash for ClusterHash {
+    fn hash(&self, node: &Option<Node>) -> String {
+        node.as_ref()
+            .map_or(String::new(), |node| node.cluster.clone())
+    }
+}
+
+// Keys on the value of a top-level field in the node's metadata. Nodes missing the field, or
+// with a non-scalar value for it, are keyed on the empty string.
+#[derive(Debug, Clone)]
+pub struct MetadataHash {
+    key: String,
+}
+
+impl MetadataHash {
+    pub fn new(key: &str) -> Self {
+        Self {
+            key: key.to_string(),
+        }
+    }
+}
+
+impl NodeHash for MetadataHash {
+    fn hash(&self, node: &Option<Node>) -> String {
+        let kind = node
+            .as_ref()
+            .and_then(|node| node.metadata.as_ref())
+            .and_then(|metadata| metadata.fields.get(&self.key))
+            .and_then(|value| value.kind.as_ref());
+        match kind {
+            Some(Kind::StringValue(value)) => value.clone(),
+            Some(Kind::NumberValue(value)) => value.to_string(),
+            Some(Kind::BoolValue(value)) => value.to_string(),
+            _ => String::new(),
+        }
+    }
+}
+
+// Keys on the node's locality, formatted as region/zone/sub_zone.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LocalityHash;
+
+impl NodeHash for LocalityHash {
+    fn hash(&self, node: &Option<Node>) -> String {
+        node.as_ref()
+            .and_then(|node| node.locality.as_ref())
+            .map_or(String::new(), |locality| {
+                format!(
+                    "{}/{}/{}",
+                    locality.region, locality.zone, locality.sub_zone
+                )
+            })
+    }
+}
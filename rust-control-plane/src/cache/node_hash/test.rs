@@ -0,0 +1,45 @@
+use crate::cache::node_hash::{ClusterHash, IdHash, LocalityHash, MetadataHash, NodeHash};
+use data_plane_api::envoy::config::core::v3::{Locality, Node};
+use data_plane_api::google::protobuf::value::Kind;
+use data_plane_api::google::protobuf::{Struct, Value};
+
+fn node() -> Option<Node> {
+    Some(Node {
+        id: "pod-1234".to_string(),
+        cluster: "frontend".to_string(),
+        metadata: Some(Struct {
+            fields: [(
+                "app".to_string(),
+                Value {
+                    kind: Some(Kind::StringValue("web".to_string())),
+                },
+            )]
+            .into_iter()
+            .collect(),
+        }),
+        locality: Some(Locality {
+            region: "us-east-1".to_string(),
+            zone: "us-east-1a".to_string(),
+            sub_zone: String::new(),
+        }),
+        ..Node::default()
+    })
+}
+
+#[test]
+fn test_built_in_hashes_key_on_node_fields() {
+    let node = node();
+    assert_eq!(IdHash.hash(&node), "pod-1234");
+    assert_eq!(ClusterHash.hash(&node), "frontend");
+    assert_eq!(MetadataHash::new("app").hash(&node), "web");
+    assert_eq!(MetadataHash::new("missing").hash(&node), "");
+    assert_eq!(LocalityHash.hash(&node), "us-east-1/us-east-1a/");
+}
+
+#[test]
+fn test_built_in_hashes_handle_missing_node() {
+    assert_eq!(IdHash.hash(&None), "");
+    assert_eq!(ClusterHash.hash(&None), "");
+    assert_eq!(MetadataHash::new("app").hash(&None), "");
+    assert_eq!(LocalityHash.hash(&None), "");
+}
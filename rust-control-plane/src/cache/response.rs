@@ -0,0 +1,268 @@
+#[cfg(test)]
+mod test;
+
+use crate::cache::{
+    DeltaWatchResponder, DeltaWatchResponse, WatchId, WatchResponder, WatchResponse,
+};
+use crate::service::stream_handle::DeltaStreamHandle;
+use crate::snapshot::type_url::VIRTUAL_HOST;
+use crate::snapshot::{Resource as SnapshotResource, Resources, VersionedResource};
+use data_plane_api::envoy::service::discovery::v3::{
+    DeltaDiscoveryRequest, DeltaDiscoveryResponse, DiscoveryRequest, DiscoveryResponse, Resource,
+};
+use std::collections::{BTreeSet, HashMap};
+use tracing::info;
+
+pub fn build_response(
+    req: &DiscoveryRequest,
+    resources: Option<&Resources>,
+    version: &str,
+) -> DiscoveryResponse {
+    let mut filtered_resources = Vec::new();
+    if let Some(resources) = resources {
+        if req.resource_names.is_empty() {
+            filtered_resources = resources.items.values().map(|item| item.to_any()).collect();
+        } else {
+            for name in &req.resource_names {
+                if let Some(item) = resources.items.get(name) {
+                    filtered_resources.push(item.to_any())
+                }
+            }
+        }
+    }
+    DiscoveryResponse {
+        type_url: req.type_url.clone(),
+        nonce: String::new(),
+        version_info: version.to_string(),
+        resources: filtered_resources,
+        control_plane: None,
+        canary: false,
+    }
+}
+
+// Responses computed while holding a cache's lock, to be sent once the lock is released. Sending
+// may block on a slow stream, which would otherwise stall every other stream using the cache.
+#[derive(Default)]
+pub struct PendingResponses {
+    sotw: Vec<(WatchResponder, WatchResponse)>,
+    delta: Vec<(DeltaWatchResponder, DeltaWatchResponse)>,
+}
+
+impl PendingResponses {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn respond(
+        &mut self,
+        req: &DiscoveryRequest,
+        tx: WatchResponder,
+        resources: Option<&Resources>,
+        version: &str,
+    ) {
+        let rep = build_response(req, resources, version);
+        self.sotw.push((tx, (req.clone(), rep)));
+    }
+
+    // Responds on tx if the stream is missing resources, or has stale versions of them.
+    // Returns whether a response was queued. watch_id is that of the watch responding, if set.
+    pub fn try_respond_delta(
+        &mut self,
+        req: &DeltaDiscoveryRequest,
+        watch_id: Option<WatchId>,
+        tx: DeltaWatchResponder,
+        stream: &DeltaStreamHandle,
+        resources: &Resources,
+    ) -> bool {
+        let delta = DeltaResponse::new(&req.type_url, stream, resources);
+        if !delta.filtered.is_empty()
+            || !delta.to_remove.is_empty()
+            || (stream.is_wildcard() && stream.is_first())
+        {
+            info!("delta responded type_url={}", &req.type_url);
+            let mut rep = delta.to_discovery(&req.type_url);
+            // Lets the stream tell which version of the resources a client ACKs or NACKs.
+            rep.system_version_info = resources.version.clone();
+            self.delta
+                .push((tx, (rep, delta.next_version_map, watch_id)));
+            true
+        } else {
+            info!("delta unchanged type_url={}", &req.type_url);
+            false
+        }
+    }
+
+    // Sends every response. A stream may have closed since its watch was set, in which case its
+    // response is dropped. The watch has already been removed, so there's nothing to clean up.
+    pub async fn send(self) {
+        for (tx, rep) in self.sotw {
+            if tx.send(rep).await.is_err() {
+                info!("dropped response to closed stream");
+            }
+        }
+        for (tx, rep) in self.delta {
+            if tx.send(rep).await.is_err() {
+                info!("dropped delta response to closed stream");
+            }
+        }
+    }
+}
+
+pub struct DeltaResponse {
+    pub next_version_map: HashMap<String, String>,
+    pub filtered: Vec<DeltaResource>,
+    pub to_remove: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct DeltaResource {
+    name: String,
+    aliases: Vec<String>,
+    // None if the aliases don't resolve to any resource.
+    resource: Option<VersionedResource>,
+}
+
+impl DeltaResponse {
+    pub fn new(type_url: &str, stream: &DeltaStreamHandle, resources: &Resources) -> Self {
+        let mut next_version_map: HashMap<String, String> = HashMap::new();
+        let mut filtered: Vec<DeltaResource> = Vec::new();
+        let mut to_remove: Vec<String> = Vec::new();
+        // The index in filtered of each resource, so that aliases resolving to a resource which
+        // is already being sent are listed on it rather than sending it again.
+        let mut indexes: HashMap<String, usize> = HashMap::new();
+
+        // Named subscriptions apply whether or not the stream is also subscribed to the
+        // wildcard, which adds every resource in the snapshot, and every resource previously
+        // sent in case it's since been removed.
+        let mut names: BTreeSet<&String> = stream.subscribed_resource_names().iter().collect();
+        if stream.is_wildcard() {
+            names.extend(resources.items.keys());
+            names.extend(stream.resource_versions().keys());
+        }
+        for name in names {
+            let prev_version = stream.resource_versions().get(name);
+            let (resolved_name, alias, resource) = match resources.items.get(name) {
+                Some(resource) => (name, None, Some(resource)),
+                None if type_url == VIRTUAL_HOST && name.contains('/') => {
+                    match resolve_alias(resources, name) {
+                        Some((resolved_name, resource)) => {
+                            (resolved_name, Some(name), Some(resource))
+                        }
+                        // VHDS clients wait on aliases until they're told the alias doesn't
+                        // resolve, by a resource named after it without a value.
+                        None => (name, Some(name), None),
+                    }
+                }
+                None => {
+                    // Only removals of resources the client knows about need to be sent.
+                    if prev_version.is_some() {
+                        to_remove.push(name.clone());
+                    }
+                    continue;
+                }
+            };
+            // Aliases which don't resolve are tracked with an empty version, so that the client
+            // is only told once.
+            let version = resource.map_or("", |resource| resource.version.as_str());
+            next_version_map.insert(name.clone(), version.to_string());
+            if prev_version.map(String::as_str) == Some(version) {
+                continue;
+            }
+            match indexes.get(resolved_name) {
+                Some(&index) => filtered[index].aliases.extend(alias.cloned()),
+                None => {
+                    indexes.insert(resolved_name.clone(), filtered.len());
+                    filtered.push(DeltaResource {
+                        name: resolved_name.clone(),
+                        aliases: alias.cloned().into_iter().collect(),
+                        resource: resource.cloned(),
+                    });
+                }
+            }
+        }
+        Self {
+            next_version_map,
+            filtered,
+            to_remove,
+        }
+    }
+
+    pub fn to_discovery(&self, type_url: &str) -> DeltaDiscoveryResponse {
+        let resources: Vec<Resource> = self
+            .filtered
+            .iter()
+            .map(|r| Resource {
+                name: r.name.clone(),
+                aliases: r.aliases.clone(),
+                resource: r.resource.as_ref().map(VersionedResource::to_any),
+                version: r
+                    .resource
+                    .as_ref()
+                    .map_or_else(String::new, |resource| resource.version.clone()),
+                ..Resource::default()
+            })
+            .collect();
+        DeltaDiscoveryResponse {
+            resources,
+            removed_resources: self.to_remove.clone(),
+            type_url: type_url.to_string(),
+            ..DeltaDiscoveryResponse::default()
+        }
+    }
+}
+
+// VHDS clients look up virtual hosts on demand by subscribing to <route_config_name>/<host>.
+// Resolves such an alias to the virtual host of that route configuration whose domains best
+// match the host, preferring exact domains, then the longest suffix and prefix wildcards, then
+// "*", as Envoy does. Returns None if the name isn't an alias of a virtual host.
+fn resolve_alias<'a>(
+    resources: &'a Resources,
+    alias: &str,
+) -> Option<(&'a String, &'a VersionedResource)> {
+    let (route_config_name, host) = alias.rsplit_once('/')?;
+    let prefix = format!("{}/", route_config_name);
+    let hosts = match host.rsplit_once(':') {
+        // Also try the host without its port, as it's often left out of domains.
+        Some((without_port, port)) if port.parse::<u16>().is_ok() => vec![host, without_port],
+        _ => vec![host],
+    };
+    for host in hosts {
+        let best = resources
+            .items
+            .iter()
+            .filter(|(name, _)| name.starts_with(&prefix))
+            .filter_map(|(name, item)| match &item.resource {
+                SnapshotResource::VirtualHost(virtual_host) => virtual_host
+                    .domains
+                    .iter()
+                    .filter_map(|domain| domain_match(domain, host))
+                    .max()
+                    .map(|rank| (rank, name, item)),
+                _ => None,
+            })
+            // Ties are broken by name, so that resolution is deterministic.
+            .max_by(|(a, a_name, _), (b, b_name, _)| a.cmp(b).then(b_name.cmp(a_name)));
+        if let Some((_, name, item)) = best {
+            return Some((name, item));
+        }
+    }
+    None
+}
+
+// Ranks how specifically a domain matches a host, or None if it doesn't match.
+fn domain_match(domain: &str, host: &str) -> Option<(u8, usize)> {
+    let host = host.to_lowercase();
+    let domain = domain.to_lowercase();
+    if domain == host {
+        Some((3, domain.len()))
+    } else if domain == "*" {
+        Some((0, 0))
+    } else if let Some(suffix) = domain.strip_prefix('*') {
+        // The wildcard must match at least one character.
+        (host.len() > suffix.len() && host.ends_with(suffix)).then_some((2, domain.len()))
+    } else if let Some(prefix) = domain.strip_suffix('*') {
+        (host.len() > prefix.len() && host.starts_with(prefix)).then_some((1, domain.len()))
+    } else {
+        None
+    }
+}
@@ -0,0 +1,276 @@
+use crate::cache::response::DeltaResponse;
+use crate::service::stream_handle::DeltaStreamHandle;
+use crate::snapshot::type_url::{CLUSTER, VIRTUAL_HOST};
+use crate::snapshot::{Resource, Resources};
+use data_plane_api::envoy::config::cluster::v3::Cluster;
+use data_plane_api::envoy::config::route::v3::VirtualHost;
+use data_plane_api::envoy::service::discovery::v3::DeltaDiscoveryRequest;
+
+fn request(subscribe: &[&str], unsubscribe: &[&str]) -> DeltaDiscoveryRequest {
+    DeltaDiscoveryRequest {
+        resource_names_subscribe: subscribe.iter().map(|name| name.to_string()).collect(),
+        resource_names_unsubscribe: unsubscribe.iter().map(|name| name.to_string()).collect(),
+        ..DeltaDiscoveryRequest::default()
+    }
+}
+
+fn resources(names: &[&str]) -> Resources {
+    let mut resources = Resources::new("1".to_string());
+    for name in names {
+        let cluster = Cluster {
+            name: name.to_string(),
+            ..Cluster::default()
+        };
+        resources.insert(name.to_string(), Resource::Cluster(cluster));
+    }
+    resources
+}
+
+struct Case {
+    name: &'static str,
+    // The subscribe and unsubscribe lists of each request, in order. Every request but the
+    // last is responded to with resources "a" and "b".
+    requests: Vec<(Vec<&'static str>, Vec<&'static str>)>,
+    // The resources to respond to the last request with.
+    resources: Vec<&'static str>,
+    sent: Vec<&'static str>,
+    removed: Vec<&'static str>,
+}
+
+#[test]
+fn test_delta_response_subscriptions() {
+    let cases = vec![
+        Case {
+            name: "legacy wildcard",
+            requests: vec![(vec![], vec![])],
+            resources: vec!["a", "b"],
+            sent: vec!["a", "b"],
+            removed: vec![],
+        },
+        Case {
+            name: "explicit wildcard",
+            requests: vec![(vec!["*"], vec![])],
+            resources: vec!["a", "b"],
+            sent: vec!["a", "b"],
+            removed: vec![],
+        },
+        Case {
+            name: "named resources",
+            requests: vec![(vec!["a", "c"], vec![])],
+            resources: vec!["a", "b"],
+            sent: vec!["a"],
+            removed: vec![],
+        },
+        Case {
+            name: "wildcard resource removed",
+            requests: vec![(vec!["*"], vec![]), (vec![], vec![])],
+            resources: vec!["a"],
+            sent: vec![],
+            removed: vec!["b"],
+        },
+        Case {
+            name: "named resource removed",
+            requests: vec![(vec!["a", "b"], vec![]), (vec![], vec![])],
+            resources: vec!["a"],
+            sent: vec![],
+            removed: vec!["b"],
+        },
+        Case {
+            name: "unsubscribed resource not removed",
+            requests: vec![(vec!["a", "b"], vec![]), (vec![], vec!["b"])],
+            resources: vec!["a"],
+            sent: vec![],
+            removed: vec![],
+        },
+        Case {
+            name: "legacy wildcard ended by naming resources",
+            requests: vec![(vec![], vec![]), (vec!["a"], vec![])],
+            resources: vec!["a"],
+            sent: vec![],
+            removed: vec![],
+        },
+        Case {
+            name: "named resources still delivered after * is unsubscribed",
+            requests: vec![(vec!["*", "a"], vec![]), (vec![], vec!["*"])],
+            resources: vec!["a", "b"],
+            sent: vec![],
+            removed: vec![],
+        },
+        Case {
+            name: "named resource resent after being unsubscribed from wildcard",
+            requests: vec![(vec!["*", "a"], vec![]), (vec![], vec!["a"])],
+            resources: vec!["a", "b"],
+            sent: vec!["a"],
+            removed: vec![],
+        },
+        Case {
+            name: "named resource unsubscribed from wildcard then removed",
+            requests: vec![(vec!["*", "a"], vec![]), (vec![], vec!["a"])],
+            resources: vec!["b"],
+            sent: vec![],
+            removed: vec!["a"],
+        },
+        Case {
+            name: "wildcard resubscribed",
+            requests: vec![(vec!["a"], vec![]), (vec!["*"], vec![])],
+            resources: vec!["a", "b"],
+            sent: vec!["b"],
+            removed: vec![],
+        },
+    ];
+    let initial = resources(&["a", "b"]);
+    for case in cases {
+        let reqs: Vec<_> = case
+            .requests
+            .iter()
+            .map(|(subscribe, unsubscribe)| request(subscribe, unsubscribe))
+            .collect();
+        let (last, reqs) = reqs.split_last().unwrap();
+        let mut stream = DeltaStreamHandle::new(reqs.first().unwrap_or(last));
+        for req in reqs {
+            stream.apply_subscriptions(req);
+            let delta = DeltaResponse::new(CLUSTER, &stream, &initial);
+            stream.set_resource_versions(delta.next_version_map);
+        }
+        stream.apply_subscriptions(last);
+        let rep =
+            DeltaResponse::new(CLUSTER, &stream, &resources(&case.resources)).to_discovery(CLUSTER);
+        let sent: Vec<&str> = rep.resources.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(sent, case.sent, "{}", case.name);
+        assert_eq!(rep.removed_resources, case.removed, "{}", case.name);
+    }
+}
+
+#[test]
+fn test_delta_response_uses_stored_versions() {
+    let req = request(&["a"], &[]);
+    let mut stream = DeltaStreamHandle::new(&req);
+    stream.apply_subscriptions(&req);
+    let mut resources = resources(&["a"]);
+    // A version which isn't the resource's hash shows it isn't recomputed.
+    resources.items.get_mut("a").unwrap().version = "stored".to_string();
+    let delta = DeltaResponse::new(CLUSTER, &stream, &resources);
+    assert_eq!(delta.next_version_map["a"], "stored");
+    let rep = delta.to_discovery(CLUSTER);
+    assert_eq!(rep.resources[0].version, "stored");
+}
+
+fn virtual_hosts(hosts: &[(&str, &[&str])]) -> Resources {
+    let mut resources = Resources::new("1".to_string());
+    for (name, domains) in hosts {
+        let virtual_host = VirtualHost {
+            name: name.to_string(),
+            domains: domains.iter().map(|domain| domain.to_string()).collect(),
+            ..VirtualHost::default()
+        };
+        resources.insert(name.to_string(), Resource::VirtualHost(virtual_host));
+    }
+    resources
+}
+
+#[test]
+fn test_delta_response_resolves_vhds_aliases() {
+    let resources = virtual_hosts(&[
+        ("routes/exact", &["www.example.com"]),
+        ("routes/suffix", &["*.example.com"]),
+        ("routes/prefix", &["www.example.*"]),
+        ("routes/default", &["*"]),
+        ("other/exact", &["api.example.com"]),
+    ]);
+    let cases = [
+        ("routes/www.example.com", Some("routes/exact")),
+        // Domains are matched with the port first, as Envoy does.
+        ("routes/www.example.com:8080", Some("routes/prefix")),
+        ("other/api.example.com:8080", Some("other/exact")),
+        ("routes/api.example.com", Some("routes/suffix")),
+        ("routes/www.example.org", Some("routes/prefix")),
+        ("routes/localhost", Some("routes/default")),
+        ("other/api.example.com", Some("other/exact")),
+        ("other/www.example.com", None),
+        ("missing/www.example.com", None),
+    ];
+    for (alias, resolved) in cases {
+        let req = request(&[alias], &[]);
+        let mut stream = DeltaStreamHandle::new(&req);
+        stream.apply_subscriptions(&req);
+        let rep = DeltaResponse::new(VIRTUAL_HOST, &stream, &resources).to_discovery(VIRTUAL_HOST);
+        assert_eq!(rep.resources.len(), 1, "{}", alias);
+        assert_eq!(rep.resources[0].aliases, vec![alias.to_string()]);
+        match resolved {
+            Some(name) => {
+                assert_eq!(rep.resources[0].name, name, "{}", alias);
+                assert!(rep.resources[0].resource.is_some(), "{}", alias);
+            }
+            // Aliases which don't resolve are answered as not found.
+            None => {
+                assert_eq!(rep.resources[0].name, alias);
+                assert!(rep.resources[0].resource.is_none(), "{}", alias);
+                assert!(rep.resources[0].version.is_empty(), "{}", alias);
+            }
+        }
+    }
+
+    // Other types don't have aliases.
+    let req = request(&["routes/www.example.com"], &[]);
+    let mut stream = DeltaStreamHandle::new(&req);
+    stream.apply_subscriptions(&req);
+    let delta = DeltaResponse::new(CLUSTER, &stream, &resources);
+    assert!(delta.filtered.is_empty());
+}
+
+#[test]
+fn test_delta_response_merges_vhds_aliases_of_a_virtual_host() {
+    let req = request(
+        &[
+            "routes/www.example.com",
+            "routes/www.example.com:80",
+            "routes/exact",
+        ],
+        &[],
+    );
+    let mut stream = DeltaStreamHandle::new(&req);
+    stream.apply_subscriptions(&req);
+    let resources = virtual_hosts(&[("routes/exact", &["www.example.com"])]);
+    let rep = DeltaResponse::new(VIRTUAL_HOST, &stream, &resources).to_discovery(VIRTUAL_HOST);
+    assert_eq!(rep.resources.len(), 1);
+    assert_eq!(rep.resources[0].name, "routes/exact");
+    assert_eq!(
+        rep.resources[0].aliases,
+        vec![
+            "routes/www.example.com".to_string(),
+            "routes/www.example.com:80".to_string()
+        ]
+    );
+}
+
+#[test]
+fn test_delta_response_tracks_vhds_aliases() {
+    let req = request(&["routes/www.example.com"], &[]);
+    let mut stream = DeltaStreamHandle::new(&req);
+    stream.apply_subscriptions(&req);
+    let resources = virtual_hosts(&[("routes/default", &["*"])]);
+    let delta = DeltaResponse::new(VIRTUAL_HOST, &stream, &resources);
+    stream.set_resource_versions(delta.next_version_map);
+
+    // Unchanged, so there's nothing to send.
+    let delta = DeltaResponse::new(VIRTUAL_HOST, &stream, &resources);
+    assert!(delta.filtered.is_empty() && delta.to_remove.is_empty());
+
+    // A more specific virtual host now matches.
+    let resources = virtual_hosts(&[("routes/default", &["*"]), ("routes/www", &["www.*"])]);
+    let delta = DeltaResponse::new(VIRTUAL_HOST, &stream, &resources);
+    let rep = delta.to_discovery(VIRTUAL_HOST);
+    assert_eq!(rep.resources[0].name, "routes/www");
+    stream.set_resource_versions(delta.next_version_map);
+
+    // Nothing matches any more, which the client is told once.
+    let delta = DeltaResponse::new(VIRTUAL_HOST, &stream, &virtual_hosts(&[]));
+    let rep = delta.to_discovery(VIRTUAL_HOST);
+    assert!(rep.removed_resources.is_empty());
+    assert_eq!(rep.resources.len(), 1);
+    assert_eq!(rep.resources[0].name, "routes/www.example.com");
+    assert!(rep.resources[0].resource.is_none());
+    stream.set_resource_versions(delta.next_version_map);
+    let delta = DeltaResponse::new(VIRTUAL_HOST, &stream, &virtual_hosts(&[]));
+    assert!(delta.filtered.is_empty() && delta.to_remove.is_empty());
+}
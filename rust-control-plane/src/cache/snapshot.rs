@@ -1,21 +1,29 @@
-use crate::cache::{Cache, DeltaWatchResponder, FetchError, WatchId, WatchResponder};
+#[cfg(test)]
+mod test;
+
+use crate::cache::node_hash::{IdHash, NodeHash};
+use crate::cache::response::{build_response, PendingResponses};
+use crate::cache::{Cache, DeltaWatchResponder, FetchError, WatchError, WatchId, WatchResponder};
 use crate::service::stream_handle::{DeltaStreamHandle, StreamHandle};
-use crate::snapshot::{self, Resources, Snapshot};
+use crate::snapshot::{ConsistencyError, Resource, Resources, Snapshot};
 use async_trait::async_trait;
 use data_plane_api::envoy::config::core::v3::Node;
 use data_plane_api::envoy::service::discovery::v3::{
-    DeltaDiscoveryRequest, DeltaDiscoveryResponse, DiscoveryRequest, DiscoveryResponse, Resource,
+    DeltaDiscoveryRequest, DiscoveryRequest, DiscoveryResponse,
 };
-use slab::Slab;
 use std::collections::{HashMap, HashSet};
-use std::time::Instant;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
 use tracing::info;
 
 #[derive(Debug)]
 pub struct SnapshotCache {
     inner: Mutex<Inner>,
     ads: bool,
+    node_hash: Box<dyn NodeHash>,
+    check_consistency: bool,
 }
 
 #[derive(Debug)]
@@ -27,18 +35,25 @@ struct Inner {
 #[derive(Debug)]
 struct NodeStatus {
     last_request_time: Instant,
-    watches: Slab<Watch>,
-    delta_watches: Slab<DeltaWatch>,
+    watches: HashMap<usize, Watch>,
+    delta_watches: HashMap<usize, DeltaWatch>,
 }
 
 impl NodeStatus {
     fn new() -> Self {
         Self {
             last_request_time: Instant::now(),
-            watches: Slab::new(),
-            delta_watches: Slab::new(),
+            watches: HashMap::new(),
+            delta_watches: HashMap::new(),
         }
     }
+
+    // A node is idle if it has no open watches, and hasn't made a request within idle_timeout.
+    fn is_idle(&self, now: Instant, idle_timeout: Duration) -> bool {
+        self.watches.is_empty()
+            && self.delta_watches.is_empty()
+            && now.duration_since(self.last_request_time) >= idle_timeout
+    }
 }
 
 #[derive(Debug)]
@@ -59,51 +74,245 @@ impl SnapshotCache {
         Self {
             inner: Mutex::new(Inner::new()),
             ads,
+            node_hash: Box::new(IdHash),
+            check_consistency: false,
         }
     }
 
+    // Sets how nodes are mapped to the key their snapshot is stored under. Defaults to the
+    // node's ID.
+    pub fn with_node_hash<H: NodeHash>(mut self, node_hash: H) -> Self {
+        self.node_hash = Box::new(node_hash);
+        self
+    }
+
+    // Makes upsert_resources and remove_resources reject changes which leave a snapshot
+    // referencing resources it doesn't contain. Whole snapshots are checked by try_set_snapshot.
+    pub fn with_consistency_check(mut self) -> Self {
+        self.check_consistency = true;
+        self
+    }
+
     // Updates snapshot associated with a given node so that future requests receive it.
     // Triggers existing watches for the given node.
+    // NB: The node is identified by its key, as computed by the cache's NodeHash.
     pub async fn set_snapshot(&self, node: &str, mut snapshot: Snapshot) {
+        snapshot.update_versions();
+        let mut responses = PendingResponses::new();
         let mut inner = self.inner.lock().await;
+        inner.snapshots.insert(node.to_string(), snapshot);
+        inner.respond_watches(node, None, &mut responses);
+        drop(inner);
+        responses.send().await;
+    }
 
-        if let Some(status) = inner.status.get_mut(node) {
-            let mut to_delete = Vec::new();
-            for (watch_id, watch) in &mut status.watches {
-                let version = snapshot.version(&watch.req.type_url);
-                if version != watch.req.version_info {
-                    to_delete.push(watch_id)
-                }
+    // Like set_snapshot, but fails, leaving the previous snapshot in place, if the snapshot
+    // references resources it doesn't contain.
+    pub async fn try_set_snapshot(
+        &self,
+        node: &str,
+        snapshot: Snapshot,
+    ) -> Result<(), ConsistencyError> {
+        snapshot.consistent()?;
+        self.set_snapshot(node, snapshot).await;
+        Ok(())
+    }
+
+    // Inserts or replaces resources of a given type in the snapshot associated with a node,
+    // creating the snapshot if there isn't one yet. The type's version is recomputed from its
+    // contents, and only the node's watches for that type are triggered. Returns the new version.
+    pub async fn upsert_resources(
+        &self,
+        node: &str,
+        type_url: &str,
+        items: HashMap<String, Resource>,
+    ) -> Result<String, ConsistencyError> {
+        self.mutate_resources(node, type_url, true, |resources| {
+            for (name, resource) in items {
+                resources.insert(name, resource);
             }
+            true
+        })
+        .await
+        .map(Option::unwrap_or_default)
+    }
 
-            for watch_id in to_delete {
-                let watch = status.watches.remove(watch_id);
-                let resources = snapshot.resources(&watch.req.type_url);
-                let version = snapshot.version(&watch.req.type_url);
-                info!(
-                    "watch triggered version={} type_url={}",
-                    version, &watch.req.type_url
-                );
-                respond(&watch.req, watch.tx, resources, version).await;
+    // Removes resources of a given type from the snapshot associated with a node. The type's
+    // version is recomputed from its contents, and only the node's watches for that type are
+    // triggered. Returns the new version.
+    pub async fn remove_resources(
+        &self,
+        node: &str,
+        type_url: &str,
+        names: &[String],
+    ) -> Result<String, ConsistencyError> {
+        self.mutate_resources(node, type_url, true, |resources| {
+            for name in names {
+                resources.items.remove(name);
             }
+            true
+        })
+        .await
+        .map(Option::unwrap_or_default)
+    }
+
+    // Modifies the resources of a given type in the snapshot associated with a node in place,
+    // while holding the cache's lock, so that a snapshot set concurrently can't be overwritten
+    // with stale resources. mutate returns whether it changed anything. Does nothing, returning
+    // None, if the node has no resources of that type or nothing changed. Otherwise, like
+    // upsert_resources, only the node's watches for that type are triggered, and the new version
+    // is returned.
+    pub async fn update_resources<F>(
+        &self,
+        node: &str,
+        type_url: &str,
+        mutate: F,
+    ) -> Result<Option<String>, ConsistencyError>
+    where
+        F: FnOnce(&mut Resources) -> bool,
+    {
+        self.mutate_resources(node, type_url, false, mutate).await
+    }
+
+    // Creates the node's snapshot and resources of the type if they don't exist, if create is
+    // set, and otherwise leaves them missing.
+    async fn mutate_resources<F>(
+        &self,
+        node: &str,
+        type_url: &str,
+        create: bool,
+        mutate: F,
+    ) -> Result<Option<String>, ConsistencyError>
+    where
+        F: FnOnce(&mut Resources) -> bool,
+    {
+        let mut inner = self.inner.lock().await;
+        let exists = inner
+            .snapshots
+            .get(node)
+            .is_some_and(|snapshot| snapshot.resources(type_url).is_some());
+        if !create && !exists {
+            return Ok(None);
+        }
+        let existed = inner.snapshots.contains_key(node);
+        let snapshot = inner.snapshots.entry(node.to_string()).or_default();
+        // Keep the previous resources around to roll back to, if they turn out inconsistent.
+        let previous = if self.check_consistency {
+            snapshot.resources(type_url).cloned()
+        } else {
+            None
+        };
 
-            let mut to_delete = Vec::new();
-            for (watch_id, watch) in &mut status.delta_watches {
-                info!("delta watch triggered type_url={}", &watch.req.type_url);
-                let responded =
-                    try_respond_delta(&watch.req, watch.tx.clone(), &watch.stream, &mut snapshot)
-                        .await;
-                if responded {
-                    to_delete.push(watch_id)
+        let resources = snapshot
+            .resources
+            .entry(type_url.to_string())
+            .or_insert_with(|| Resources::new(String::new()));
+        if !mutate(resources) {
+            // Only reachable without create, so the resources existed and weren't changed.
+            return Ok(None);
+        }
+        // Derived from the contents rather than a counter, so that it can't reissue a version a
+        // client holds for other contents, such as one set by the user.
+        resources.version = resources.content_version();
+        let version = resources.version.clone();
+
+        if self.check_consistency {
+            if let Err(err) = snapshot.consistent() {
+                match previous {
+                    Some(previous) => snapshot.insert(type_url.to_string(), previous),
+                    None => {
+                        snapshot.resources.remove(type_url);
+                    }
+                }
+                if !existed {
+                    inner.snapshots.remove(node);
                 }
+                return Err(err);
             }
+        }
+
+        let mut responses = PendingResponses::new();
+        inner.respond_watches(node, Some(type_url), &mut responses);
+        drop(inner);
+        responses.send().await;
+        Ok(Some(version))
+    }
 
-            for watch_id in to_delete {
-                status.delta_watches.remove(watch_id);
+    // Returns the key a node's snapshot is stored under, as computed by the cache's NodeHash.
+    pub fn node_key(&self, node: &Option<Node>) -> String {
+        self.node_hash.hash(node)
+    }
+
+    // Returns a copy of the snapshot associated with a given node, if any.
+    pub async fn snapshot(&self, node: &str) -> Option<Snapshot> {
+        let inner = self.inner.lock().await;
+        inner.snapshots.get(node).cloned()
+    }
+
+    // Returns the version of each type URL in a node's snapshot, without copying its resources.
+    pub async fn snapshot_versions(&self, node: &str) -> Option<HashMap<String, String>> {
+        let inner = self.inner.lock().await;
+        let snapshot = inner.snapshots.get(node)?;
+        Some(
+            snapshot
+                .resources
+                .iter()
+                .map(|(type_url, resources)| (type_url.clone(), resources.version.clone()))
+                .collect(),
+        )
+    }
+
+    // Returns the key of every node with a snapshot.
+    pub async fn snapshot_nodes(&self) -> Vec<String> {
+        let inner = self.inner.lock().await;
+        inner.snapshots.keys().cloned().collect()
+    }
+
+    // Removes the snapshot associated with a given node. The node's status is also removed,
+    // unless it still has open watches.
+    pub async fn clear_snapshot(&self, node: &str) {
+        let mut inner = self.inner.lock().await;
+        inner.snapshots.remove(node);
+        if let Some(status) = inner.status.get(node) {
+            if status.watches.is_empty() && status.delta_watches.is_empty() {
+                inner.status.remove(node);
             }
         }
+    }
 
-        inner.snapshots.insert(node.to_string(), snapshot.clone());
+    // Removes the status of every idle node, returning how many were removed.
+    pub async fn evict_idle_nodes(&self, idle_timeout: Duration) -> usize {
+        let mut inner = self.inner.lock().await;
+        let now = Instant::now();
+        let before = inner.status.len();
+        inner
+            .status
+            .retain(|_, status| !status.is_idle(now, idle_timeout));
+        before - inner.status.len()
+    }
+
+    // Spawns a task which evicts idle nodes every interval. The task exits once the cache is
+    // dropped.
+    pub fn spawn_reaper(
+        self: &Arc<Self>,
+        interval: Duration,
+        idle_timeout: Duration,
+    ) -> JoinHandle<()> {
+        let cache = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let cache = match cache.upgrade() {
+                    Some(cache) => cache,
+                    None => return,
+                };
+                let evicted = cache.evict_idle_nodes(idle_timeout).await;
+                if evicted > 0 {
+                    info!("evicted idle nodes count={}", evicted);
+                }
+            }
+        })
     }
 
     pub async fn node_status(&self) -> HashMap<String, Instant> {
@@ -124,54 +333,22 @@ impl Cache for SnapshotCache {
         req: &DiscoveryRequest,
         tx: WatchResponder,
         stream: &StreamHandle,
-    ) -> Option<WatchId> {
-        let mut inner = self.inner.lock().await;
-        let node_id = hash_id(&req.node);
-        inner.update_node_status(&node_id);
-        if let Some(snapshot) = inner.snapshots.get(&node_id) {
-            let resources = snapshot.resources(&req.type_url);
-            let version = snapshot.version(&req.type_url);
-            let type_known_resource_names = stream.known_resource_names(&req.type_url);
-            // Check if a different set of resources has been requested.
-            if inner.is_requesting_new_resources(req, resources, type_known_resource_names) {
-                if self.ads && check_ads_consistency(req, resources) {
-                    info!("not responding: ads consistency");
-                    return Some(inner.set_watch(&node_id, req, tx));
-                }
-                info!("responding: resource diff");
-                // TODO: Don't hold lock across await boundaries (performance).
-                respond(req, tx, resources, version).await;
-                return None;
-            }
-            if req.version_info == version {
-                // Client is already at the latest version, so we have nothing to respond with.
-                // Set a watch because we may receive a new version in the future.
-                info!("set watch: latest version");
-                Some(inner.set_watch(&node_id, req, tx))
-            } else {
-                // The version has changed, so we should respond.
-                if self.ads && check_ads_consistency(req, resources) {
-                    info!("not responding: ads consistency");
-                    return Some(inner.set_watch(&node_id, req, tx));
-                }
-                info!("responding: new version");
-                // TODO: Don't hold lock across await boundaries (performance).
-                respond(req, tx, resources, version).await;
-                None
-            }
-        } else {
-            // No snapshot exists for this node, so we have nothing to respond with.
-            // Set a watch because we may receive a snapshot for this node in the future.
-            info!("set watch: no snapshot");
-            Some(inner.set_watch(&node_id, req, tx))
-        }
+    ) -> Result<Option<WatchId>, WatchError> {
+        let node_id = self.node_hash.hash(&req.node);
+        let mut responses = PendingResponses::new();
+        let watch_id = {
+            let mut inner = self.inner.lock().await;
+            inner.create_watch(self.ads, &node_id, req, tx, stream, &mut responses)
+        };
+        responses.send().await;
+        Ok(watch_id)
     }
 
     // Deletes a watch previously created with create_watch.
     async fn cancel_watch(&self, watch_id: &WatchId) {
         let mut inner = self.inner.lock().await;
         if let Some(status) = inner.status.get_mut(&watch_id.node_id) {
-            status.watches.try_remove(watch_id.index);
+            status.watches.remove(&watch_id.index);
         }
     }
 
@@ -179,7 +356,7 @@ impl Cache for SnapshotCache {
     async fn cancel_delta_watch(&self, watch_id: &WatchId) {
         let mut inner = self.inner.lock().await;
         if let Some(status) = inner.status.get_mut(&watch_id.node_id) {
-            status.delta_watches.try_remove(watch_id.index);
+            status.delta_watches.remove(&watch_id.index);
         }
     }
 
@@ -189,7 +366,7 @@ impl Cache for SnapshotCache {
         type_url: &'static str,
     ) -> Result<DiscoveryResponse, FetchError> {
         let inner = self.inner.lock().await;
-        let node_id = hash_id(&req.node);
+        let node_id = self.node_hash.hash(&req.node);
         let snapshot = inner.snapshots.get(&node_id).ok_or(FetchError::NotFound)?;
         let version = snapshot.version(&req.type_url);
         if req.version_info == version {
@@ -204,43 +381,29 @@ impl Cache for SnapshotCache {
         req: &DeltaDiscoveryRequest,
         tx: DeltaWatchResponder,
         stream: &DeltaStreamHandle,
-    ) -> Option<WatchId> {
-        let mut inner = self.inner.lock().await;
-        let node_id = hash_id(&req.node);
-        inner.update_node_status(&node_id);
-        if let Some(snapshot) = inner.snapshots.get_mut(&node_id) {
-            if try_respond_delta(req, tx.clone(), stream, snapshot).await {
-                return None;
-            }
-        }
-
-        info!("set delta watch");
-        Some(inner.set_delta_watch(&node_id, req, tx, stream))
+    ) -> Result<Option<WatchId>, WatchError> {
+        let node_id = self.node_hash.hash(&req.node);
+        let mut responses = PendingResponses::new();
+        let watch_id = {
+            let mut inner = self.inner.lock().await;
+            inner.create_delta_watch(&node_id, req, tx, stream, &mut responses)
+        };
+        responses.send().await;
+        Ok(watch_id)
     }
 }
 
-async fn try_respond_delta(
+fn try_respond_delta(
     req: &DeltaDiscoveryRequest,
+    watch_id: Option<WatchId>,
     tx: DeltaWatchResponder,
     stream: &DeltaStreamHandle,
-    snapshot: &mut Snapshot,
+    snapshot: &Snapshot,
+    responses: &mut PendingResponses,
 ) -> bool {
-    let delta = DeltaResponse::new(req, stream, snapshot);
-    if !delta.filtered.is_empty()
-        || !delta.to_remove.is_empty()
-        || (stream.is_wildcard() && stream.is_first())
-    {
-        info!("delta responded type_url={}", &req.type_url);
-        tx.send((
-            delta.to_discovery(&req.type_url),
-            delta.next_version_map.clone(),
-        ))
-        .await
-        .unwrap();
-        true
-    } else {
-        info!("delta unchanged type_url={}", &req.type_url);
-        false
+    match snapshot.resources(&req.type_url) {
+        Some(resources) => responses.try_respond_delta(req, watch_id, tx, stream, resources),
+        None => false,
     }
 }
 
@@ -252,17 +415,151 @@ impl Inner {
         }
     }
 
+    // Either queues a response on tx, or sets a watch, returning a watch ID.
+    fn create_watch(
+        &mut self,
+        ads: bool,
+        node_id: &str,
+        req: &DiscoveryRequest,
+        tx: WatchResponder,
+        stream: &StreamHandle,
+        responses: &mut PendingResponses,
+    ) -> Option<WatchId> {
+        self.update_node_status(node_id);
+        if let Some(snapshot) = self.snapshots.get(node_id) {
+            let resources = snapshot.resources(&req.type_url);
+            let version = snapshot.version(&req.type_url);
+            let type_known_resource_names = stream.known_resource_names(&req.type_url);
+            // Check if a different set of resources has been requested.
+            if self.is_requesting_new_resources(req, resources, type_known_resource_names) {
+                if ads && check_ads_consistency(req, resources) {
+                    info!("not responding: ads consistency");
+                    return Some(self.set_watch(node_id, req, tx));
+                }
+                info!("responding: resource diff");
+                responses.respond(req, tx, resources, version);
+                return None;
+            }
+            if req.version_info == version {
+                // Client is already at the latest version, so we have nothing to respond with.
+                // Set a watch because we may receive a new version in the future.
+                info!("set watch: latest version");
+                Some(self.set_watch(node_id, req, tx))
+            } else {
+                // The version has changed, so we should respond.
+                if ads && check_ads_consistency(req, resources) {
+                    info!("not responding: ads consistency");
+                    return Some(self.set_watch(node_id, req, tx));
+                }
+                info!("responding: new version");
+                responses.respond(req, tx, resources, version);
+                None
+            }
+        } else {
+            // No snapshot exists for this node, so we have nothing to respond with.
+            // Set a watch because we may receive a snapshot for this node in the future.
+            info!("set watch: no snapshot");
+            Some(self.set_watch(node_id, req, tx))
+        }
+    }
+
+    // Either queues a response on tx, or sets a delta watch, returning a watch ID.
+    fn create_delta_watch(
+        &mut self,
+        node_id: &str,
+        req: &DeltaDiscoveryRequest,
+        tx: DeltaWatchResponder,
+        stream: &DeltaStreamHandle,
+        responses: &mut PendingResponses,
+    ) -> Option<WatchId> {
+        self.update_node_status(node_id);
+        if let Some(snapshot) = self.snapshots.get(node_id) {
+            if try_respond_delta(req, None, tx.clone(), stream, snapshot, responses) {
+                return None;
+            }
+        }
+
+        info!("set delta watch");
+        Some(self.set_delta_watch(node_id, req, tx, stream))
+    }
+
+    // Queues responses to the node's watches which are out of date with its snapshot. If a type
+    // URL is given, only watches for that type are considered.
+    fn respond_watches(
+        &mut self,
+        node: &str,
+        type_url: Option<&str>,
+        responses: &mut PendingResponses,
+    ) {
+        let (status, snapshot) = match (self.status.get_mut(node), self.snapshots.get(node)) {
+            (Some(status), Some(snapshot)) => (status, snapshot),
+            _ => return,
+        };
+        let is_selected = |watch_type_url: &str| match type_url {
+            Some(type_url) => type_url == watch_type_url,
+            None => true,
+        };
+        // Drop the watches of streams which have closed without cancelling them.
+        status.watches.retain(|_, watch| !watch.tx.is_closed());
+        status
+            .delta_watches
+            .retain(|_, watch| !watch.tx.is_closed());
+
+        let mut to_delete = Vec::new();
+        for (watch_id, watch) in &mut status.watches {
+            let version = snapshot.version(&watch.req.type_url);
+            if is_selected(&watch.req.type_url) && version != watch.req.version_info {
+                to_delete.push(*watch_id)
+            }
+        }
+
+        for watch_id in to_delete {
+            let watch = status.watches.remove(&watch_id).unwrap();
+            let resources = snapshot.resources(&watch.req.type_url);
+            let version = snapshot.version(&watch.req.type_url);
+            info!(
+                "watch triggered version={} type_url={}",
+                version, &watch.req.type_url
+            );
+            responses.respond(&watch.req, watch.tx, resources, version);
+        }
+
+        let mut to_delete = Vec::new();
+        for (watch_id, watch) in &mut status.delta_watches {
+            if !is_selected(&watch.req.type_url) {
+                continue;
+            }
+            info!("delta watch triggered type_url={}", &watch.req.type_url);
+            let responded = try_respond_delta(
+                &watch.req,
+                Some(WatchId {
+                    node_id: node.to_string(),
+                    index: *watch_id,
+                }),
+                watch.tx.clone(),
+                &watch.stream,
+                snapshot,
+                responses,
+            );
+            if responded {
+                to_delete.push(*watch_id)
+            }
+        }
+
+        for watch_id in to_delete {
+            status.delta_watches.remove(&watch_id);
+        }
+    }
+
     fn set_watch(&mut self, node_id: &str, req: &DiscoveryRequest, tx: WatchResponder) -> WatchId {
         let watch = Watch {
             req: req.clone(),
             tx,
         };
         let status = self.status.get_mut(node_id).unwrap();
-        let index = status.watches.insert(watch);
-        WatchId {
-            node_id: node_id.to_string(),
-            index,
-        }
+        let watch_id = WatchId::new(node_id);
+        status.watches.insert(watch_id.index, watch);
+        watch_id
     }
 
     fn set_delta_watch(
@@ -278,11 +575,9 @@ impl Inner {
             stream: stream.clone(),
         };
         let status = self.status.get_mut(node_id).unwrap();
-        let index = status.delta_watches.insert(watch);
-        WatchId {
-            node_id: node_id.to_string(),
-            index,
-        }
+        let watch_id = WatchId::new(node_id);
+        status.delta_watches.insert(watch_id.index, watch);
+        watch_id
     }
 
     fn update_node_status(&mut self, node_id: &str) {
@@ -317,51 +612,6 @@ impl Inner {
     }
 }
 
-fn hash_id(node: &Option<Node>) -> String {
-    node.as_ref().map_or(String::new(), |node| node.id.clone())
-}
-
-fn build_response(
-    req: &DiscoveryRequest,
-    resources: Option<&Resources>,
-    version: &str,
-) -> DiscoveryResponse {
-    let mut filtered_resources = Vec::new();
-    if let Some(resources) = resources {
-        if req.resource_names.is_empty() {
-            filtered_resources = resources
-                .items
-                .values()
-                .map(|resource| resource.into_any())
-                .collect();
-        } else {
-            for name in &req.resource_names {
-                if let Some(resource) = resources.items.get(name) {
-                    filtered_resources.push(resource.into_any())
-                }
-            }
-        }
-    }
-    DiscoveryResponse {
-        type_url: req.type_url.clone(),
-        nonce: String::new(),
-        version_info: version.to_string(),
-        resources: filtered_resources,
-        control_plane: None,
-        canary: false,
-    }
-}
-
-async fn respond(
-    req: &DiscoveryRequest,
-    tx: WatchResponder,
-    resources: Option<&Resources>,
-    version: &str,
-) {
-    let rep = build_response(req, resources, version);
-    tx.send((req.clone(), rep)).await.unwrap();
-}
-
 fn check_ads_consistency(req: &DiscoveryRequest, resources: Option<&Resources>) -> bool {
     if !req.resource_names.is_empty() {
         if let Some(resources) = resources {
@@ -375,113 +625,3 @@ fn check_ads_consistency(req: &DiscoveryRequest, resources: Option<&Resources>)
     }
     true
 }
-
-struct DeltaResponse {
-    next_version_map: HashMap<String, String>,
-    filtered: Vec<DeltaResource>,
-    to_remove: Vec<String>,
-}
-
-#[derive(Debug)]
-struct DeltaResource {
-    name: String,
-    resource: snapshot::Resource,
-}
-
-impl DeltaResponse {
-    fn new(
-        req: &DeltaDiscoveryRequest,
-        stream: &DeltaStreamHandle,
-        snapshot: &mut Snapshot,
-    ) -> Self {
-        let mut next_version_map: HashMap<String, String> = HashMap::new();
-        let mut filtered: Vec<DeltaResource> = Vec::new();
-        let mut to_remove: Vec<String> = Vec::new();
-
-        snapshot.build_version_map();
-        let version_map = snapshot
-            .version_map
-            .as_ref()
-            .unwrap()
-            .get(&req.type_url)
-            .unwrap();
-        let resources = snapshot.resources(&req.type_url).unwrap();
-        if stream.is_wildcard() {
-            for (name, resource) in &resources.items {
-                let version = version_map.get(name).unwrap();
-                next_version_map.insert(name.clone(), version.to_string());
-                if let Some(prev_version) = stream.resource_versions().get(name) {
-                    if prev_version != version {
-                        filtered.push(DeltaResource {
-                            name: name.clone(),
-                            resource: resource.clone(),
-                        });
-                    }
-                } else {
-                    filtered.push(DeltaResource {
-                        name: name.clone(),
-                        resource: resource.clone(),
-                    });
-                }
-            }
-            for name in stream.resource_versions().keys() {
-                if resources.items.get(name).is_none() {
-                    to_remove.push(name.clone());
-                }
-            }
-        } else {
-            for name in stream.subscribed_resource_names() {
-                if let Some(prev_version) = stream.resource_versions().get(name) {
-                    if let Some(resource) = resources.items.get(name) {
-                        let version = version_map.get(name).unwrap();
-                        if prev_version != version {
-                            filtered.push(DeltaResource {
-                                name: name.clone(),
-                                resource: resource.clone(),
-                            });
-                        }
-                        next_version_map.insert(name.clone(), version.to_string());
-                    } else {
-                        // Remove because this is a previously subscribed resource, but is no
-                        // longer in the snapshot.
-                        to_remove.push(name.clone());
-                    }
-                } else if let Some(resource) = resources.items.get(name) {
-                    // TODO: This is duplicate code from above.
-                    let version = version_map.get(name).unwrap();
-                    if !version.is_empty() {
-                        filtered.push(DeltaResource {
-                            name: name.clone(),
-                            resource: resource.clone(),
-                        });
-                    }
-                    next_version_map.insert(name.clone(), version.to_string());
-                }
-            }
-        }
-        Self {
-            next_version_map,
-            filtered,
-            to_remove,
-        }
-    }
-
-    fn to_discovery(&self, type_url: &str) -> DeltaDiscoveryResponse {
-        let resources: Vec<Resource> = self
-            .filtered
-            .iter()
-            .map(|r| Resource {
-                name: r.name.clone(),
-                resource: Some(r.resource.into_any()),
-                version: snapshot::hash_resource(r.resource.clone()),
-                ..Resource::default()
-            })
-            .collect();
-        DeltaDiscoveryResponse {
-            resources,
-            removed_resources: self.to_remove.clone(),
-            type_url: type_url.to_string(),
-            ..DeltaDiscoveryResponse::default()
-        }
-    }
-}
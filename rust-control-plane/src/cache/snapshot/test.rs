@@ -0,0 +1,510 @@
+use crate::cache::node_hash::ClusterHash;
+use crate::cache::snapshot::SnapshotCache;
+use crate::cache::Cache;
+use crate::service::stream_handle::{DeltaStreamHandle, StreamHandle};
+use crate::snapshot::type_url::{CLUSTER, ENDPOINT};
+use crate::snapshot::{Resource, Resources, Snapshot};
+use data_plane_api::envoy::config::cluster::v3::cluster::{ClusterDiscoveryType, DiscoveryType};
+use data_plane_api::envoy::config::cluster::v3::Cluster;
+use data_plane_api::envoy::config::core::v3::Node;
+use data_plane_api::envoy::service::discovery::v3::{
+    DeltaDiscoveryRequest, DiscoveryRequest, DiscoveryResponse,
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+fn snapshot(version: &str, names: &[&str]) -> Snapshot {
+    let mut resources = Resources::new(version.to_string());
+    for name in names {
+        resources.insert(
+            name.to_string(),
+            Resource::Cluster(Cluster {
+                name: name.to_string(),
+                ..Cluster::default()
+            }),
+        );
+    }
+    let mut snapshot = Snapshot::new();
+    snapshot.insert(CLUSTER.to_string(), resources);
+    snapshot
+}
+
+fn content_addressed_snapshot(clusters: &[(&str, &str)]) -> Snapshot {
+    let mut resources = Resources::content_addressed();
+    for (name, alt_stat_name) in clusters {
+        resources.insert(
+            name.to_string(),
+            Resource::Cluster(Cluster {
+                name: name.to_string(),
+                alt_stat_name: alt_stat_name.to_string(),
+                ..Cluster::default()
+            }),
+        );
+    }
+    let mut snapshot = Snapshot::new();
+    snapshot.insert(CLUSTER.to_string(), resources);
+    snapshot
+}
+
+fn request(id: &str, cluster: &str, version: &str) -> DiscoveryRequest {
+    DiscoveryRequest {
+        node: Some(Node {
+            id: id.to_string(),
+            cluster: cluster.to_string(),
+            ..Node::default()
+        }),
+        type_url: CLUSTER.to_string(),
+        version_info: version.to_string(),
+        ..DiscoveryRequest::default()
+    }
+}
+
+#[tokio::test]
+async fn test_snapshot_cache_node_hash_shares_snapshots() {
+    let cache = SnapshotCache::new(false).with_node_hash(ClusterHash);
+    cache.set_snapshot("frontend", snapshot("1", &["a"])).await;
+    let handle = StreamHandle::new();
+    for id in ["pod-1", "pod-2"] {
+        let (tx, mut rx) = mpsc::channel(1);
+        let watch_id = cache
+            .create_watch(&request(id, "frontend", ""), tx, &handle)
+            .await
+            .unwrap();
+        assert!(watch_id.is_none());
+        let (_, rep) = rx.try_recv().unwrap();
+        assert_eq!(rep.version_info, "1");
+    }
+    let (tx, mut rx) = mpsc::channel(1);
+    let watch_id = cache
+        .create_watch(&request("pod-3", "backend", ""), tx, &handle)
+        .await
+        .unwrap();
+    assert_eq!(watch_id.unwrap().node_id, "backend");
+    assert!(rx.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn test_snapshot_cache_rejects_inconsistent_snapshot() {
+    let cache = SnapshotCache::new(false);
+    let mut snapshot = snapshot("1", &["a"]);
+    assert!(cache
+        .try_set_snapshot("node", snapshot.clone())
+        .await
+        .is_ok());
+    if let Some(resources) = snapshot.resources.get_mut(CLUSTER) {
+        resources.insert(
+            "b".to_string(),
+            Resource::Cluster(Cluster {
+                name: "b".to_string(),
+                cluster_discovery_type: Some(ClusterDiscoveryType::Type(DiscoveryType::Eds as i32)),
+                ..Cluster::default()
+            }),
+        );
+    }
+    let err = cache.try_set_snapshot("node", snapshot).await.unwrap_err();
+    assert_eq!(err.dangling[ENDPOINT], vec!["b".to_string()]);
+
+    let (tx, mut rx) = mpsc::channel(1);
+    cache
+        .create_watch(&request("node", "", ""), tx, &StreamHandle::new())
+        .await
+        .unwrap();
+    let (_, rep) = rx.try_recv().unwrap();
+    assert_eq!(rep.version_info, "1");
+}
+
+#[tokio::test]
+async fn test_snapshot_cache_evicts_idle_nodes() {
+    let cache = SnapshotCache::new(false);
+    cache.set_snapshot("responded", snapshot("1", &["a"])).await;
+    let handle = StreamHandle::new();
+    let (tx, _rx) = mpsc::channel(1);
+    cache
+        .create_watch(&request("responded", "", ""), tx, &handle)
+        .await
+        .unwrap();
+    let (tx, _rx) = mpsc::channel(1);
+    let watch_id = cache
+        .create_watch(&request("watching", "", ""), tx, &handle)
+        .await
+        .unwrap();
+    assert!(watch_id.is_some());
+
+    assert_eq!(cache.evict_idle_nodes(Duration::from_secs(60)).await, 0);
+    assert_eq!(cache.evict_idle_nodes(Duration::ZERO).await, 1);
+    let status = cache.node_status().await;
+    assert_eq!(status.len(), 1);
+    assert!(status.contains_key("watching"));
+
+    cache.cancel_watch(&watch_id.unwrap()).await;
+    assert_eq!(cache.evict_idle_nodes(Duration::ZERO).await, 1);
+    assert!(cache.node_status().await.is_empty());
+}
+
+#[tokio::test]
+async fn test_snapshot_cache_clear_snapshot() {
+    let cache = SnapshotCache::new(false);
+    cache.set_snapshot("node", snapshot("1", &["a"])).await;
+    let handle = StreamHandle::new();
+    let (tx, mut rx) = mpsc::channel(1);
+    cache
+        .create_watch(&request("node", "", ""), tx, &handle)
+        .await
+        .unwrap();
+    assert!(rx.try_recv().is_ok());
+    cache.clear_snapshot("node").await;
+    assert!(cache.node_status().await.is_empty());
+
+    let (tx, mut rx) = mpsc::channel(1);
+    let watch_id = cache
+        .create_watch(&request("node", "", ""), tx, &handle)
+        .await
+        .unwrap();
+    assert!(watch_id.is_some());
+    assert!(rx.try_recv().is_err());
+    cache.clear_snapshot("node").await;
+    assert_eq!(cache.node_status().await.len(), 1);
+}
+
+#[tokio::test]
+async fn test_snapshot_cache_upsert_and_remove_resources() {
+    let cache = SnapshotCache::new(false);
+    cache.set_snapshot("node", snapshot("1", &["a"])).await;
+    let mut handle = StreamHandle::new();
+    handle.add_known_resource_names(CLUSTER, &["a".to_string()]);
+    let (tx, mut rx) = mpsc::channel(1);
+    let watch_id = cache
+        .create_watch(&request("node", "", "1"), tx.clone(), &handle)
+        .await
+        .unwrap();
+    assert!(watch_id.is_some());
+    let endpoints_req = DiscoveryRequest {
+        type_url: ENDPOINT.to_string(),
+        ..request("node", "", "")
+    };
+    let (endpoints_tx, mut endpoints_rx) = mpsc::channel(1);
+    let watch_id = cache
+        .create_watch(&endpoints_req, endpoints_tx, &handle)
+        .await
+        .unwrap();
+    assert!(watch_id.is_some());
+
+    let version = cache
+        .upsert_resources(
+            "node",
+            CLUSTER,
+            HashMap::from([(
+                "b".to_string(),
+                Resource::Cluster(Cluster {
+                    name: "b".to_string(),
+                    ..Cluster::default()
+                }),
+            )]),
+        )
+        .await
+        .unwrap();
+    let (_, rep) = rx.try_recv().unwrap();
+    assert_eq!(rep.version_info, version);
+    assert_eq!(rep.resources.len(), 2);
+    assert!(endpoints_rx.try_recv().is_err());
+
+    let watch_id = cache
+        .create_watch(&request("node", "", &version), tx, &handle)
+        .await
+        .unwrap();
+    assert!(watch_id.is_some());
+    let next_version = cache
+        .remove_resources("node", CLUSTER, &["a".to_string()])
+        .await
+        .unwrap();
+    assert_ne!(version, next_version);
+    let (_, rep) = rx.try_recv().unwrap();
+    assert_eq!(rep.version_info, next_version);
+    assert_eq!(rep.resources.len(), 1);
+    assert!(endpoints_rx.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn test_snapshot_cache_update_resources_in_place() {
+    let cache = SnapshotCache::new(false);
+    // Nothing to update, so no snapshot or resources are created.
+    let version = cache.update_resources("node", CLUSTER, |_| true).await;
+    assert_eq!(version.unwrap(), None);
+    assert!(cache.snapshot("node").await.is_none());
+
+    cache.set_snapshot("node", snapshot("1", &["a"])).await;
+    let version = cache.update_resources("node", ENDPOINT, |_| true).await;
+    assert_eq!(version.unwrap(), None);
+    assert!(cache
+        .snapshot("node")
+        .await
+        .unwrap()
+        .resources(ENDPOINT)
+        .is_none());
+
+    let (tx, mut rx) = mpsc::channel(1);
+    let watch_id = cache
+        .create_watch(&request("node", "", "1"), tx, &StreamHandle::new())
+        .await
+        .unwrap();
+    assert!(watch_id.is_some());
+    // Unchanged resources keep their version, and don't trigger watches.
+    let version = cache
+        .update_resources("node", CLUSTER, |resources| {
+            assert_eq!(resources.version, "1");
+            false
+        })
+        .await;
+    assert_eq!(version.unwrap(), None);
+    assert!(rx.try_recv().is_err());
+
+    let version = cache
+        .update_resources("node", CLUSTER, |resources| {
+            resources.items.remove("a");
+            true
+        })
+        .await
+        .unwrap()
+        .unwrap();
+    let (_, rep) = rx.try_recv().unwrap();
+    assert_eq!(rep.version_info, version);
+    assert!(rep.resources.is_empty());
+}
+
+#[tokio::test]
+async fn test_snapshot_cache_generated_versions_dont_reuse_user_versions() {
+    let cache = SnapshotCache::new(false);
+    let handle = StreamHandle::new();
+    let b = HashMap::from([(
+        "b".to_string(),
+        Resource::Cluster(Cluster {
+            name: "b".to_string(),
+            ..Cluster::default()
+        }),
+    )]);
+    // A client holds version "2" with just "a", and a later snapshot restarts the user's
+    // versions.
+    cache.set_snapshot("node", snapshot("2", &["a"])).await;
+    cache.set_snapshot("node", snapshot("1", &["a"])).await;
+    let version = cache.upsert_resources("node", CLUSTER, b).await.unwrap();
+    assert_ne!(version, "1");
+    assert_ne!(version, "2");
+
+    let (tx, mut rx) = mpsc::channel(1);
+    let watch_id = cache
+        .create_watch(&request("node", "", "2"), tx, &handle)
+        .await
+        .unwrap();
+    assert!(watch_id.is_none());
+    assert_eq!(rx.try_recv().unwrap().1.resources.len(), 2);
+}
+
+#[tokio::test]
+async fn test_snapshot_cache_upsert_rejects_inconsistent_resources() {
+    let cache = SnapshotCache::new(false).with_consistency_check();
+    cache.set_snapshot("node", snapshot("1", &["a"])).await;
+    let eds_cluster = Resource::Cluster(Cluster {
+        name: "b".to_string(),
+        cluster_discovery_type: Some(ClusterDiscoveryType::Type(DiscoveryType::Eds as i32)),
+        ..Cluster::default()
+    });
+    let result = cache
+        .upsert_resources(
+            "node",
+            CLUSTER,
+            HashMap::from([("b".to_string(), eds_cluster)]),
+        )
+        .await;
+    assert!(result.is_err());
+
+    let (tx, mut rx) = mpsc::channel(1);
+    cache
+        .create_watch(&request("node", "", ""), tx, &StreamHandle::new())
+        .await
+        .unwrap();
+    let (_, rep) = rx.try_recv().unwrap();
+    assert_eq!(rep.version_info, "1");
+    assert_eq!(rep.resources.len(), 1);
+}
+
+#[tokio::test]
+async fn test_snapshot_cache_slow_stream_does_not_block_others() {
+    let cache = Arc::new(SnapshotCache::new(false));
+    let handle = StreamHandle::new();
+    // Fill the channel, as if the stream stopped reading responses.
+    let (slow_tx, mut slow_rx) = mpsc::channel(1);
+    slow_tx
+        .try_send((DiscoveryRequest::default(), DiscoveryResponse::default()))
+        .unwrap();
+    assert!(cache
+        .create_watch(&request("slow", "", ""), slow_tx, &handle)
+        .await
+        .unwrap()
+        .is_some());
+    let blocked = tokio::spawn({
+        let cache = cache.clone();
+        async move { cache.set_snapshot("slow", snapshot("1", &["a"])).await }
+    });
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert!(!blocked.is_finished());
+
+    let (tx, mut rx) = mpsc::channel(1);
+    tokio::time::timeout(Duration::from_secs(1), async {
+        cache.set_snapshot("fast", snapshot("1", &["a"])).await;
+        cache
+            .create_watch(&request("fast", "", ""), tx, &handle)
+            .await
+            .unwrap()
+    })
+    .await
+    .expect("cache blocked by slow stream");
+    assert_eq!(rx.try_recv().unwrap().1.version_info, "1");
+
+    slow_rx.recv().await.unwrap();
+    blocked.await.unwrap();
+    assert_eq!(slow_rx.recv().await.unwrap().1.version_info, "1");
+}
+
+#[tokio::test]
+async fn test_snapshot_cache_drops_watches_of_closed_streams() {
+    let cache = SnapshotCache::new(false);
+    let handle = StreamHandle::new();
+    let (tx, rx) = mpsc::channel(1);
+    assert!(cache
+        .create_watch(&request("foobar", "", ""), tx.clone(), &handle)
+        .await
+        .unwrap()
+        .is_some());
+    assert!(cache
+        .create_watch(
+            &DiscoveryRequest {
+                type_url: ENDPOINT.to_string(),
+                ..request("foobar", "", "")
+            },
+            tx,
+            &handle
+        )
+        .await
+        .unwrap()
+        .is_some());
+    drop(rx);
+    cache.set_snapshot("foobar", snapshot("1", &["a"])).await;
+    // Neither the responded nor the untriggered watch is left behind.
+    assert_eq!(cache.evict_idle_nodes(Duration::ZERO).await, 1);
+}
+
+#[tokio::test]
+async fn test_snapshot_cache_cancelling_fired_watch_is_harmless() {
+    let cache = SnapshotCache::new(false);
+    let handle = StreamHandle::new();
+    let (tx, mut rx) = mpsc::channel(1);
+    let fired = cache
+        .create_watch(&request("foobar", "", ""), tx, &handle)
+        .await
+        .unwrap()
+        .unwrap();
+    cache.set_snapshot("foobar", snapshot("1", &["a"])).await;
+    rx.try_recv().unwrap();
+
+    // The fired watch has been removed, so a new watch may be created in its place before the
+    // old stream gets around to cancelling it.
+    let (tx, mut rx) = mpsc::channel(1);
+    let watch_id = cache
+        .create_watch(&request("foobar", "", "1"), tx, &handle)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_ne!(watch_id, fired);
+    cache.cancel_watch(&fired).await;
+    cache.set_snapshot("foobar", snapshot("2", &["a"])).await;
+    assert_eq!(rx.try_recv().unwrap().1.version_info, "2");
+}
+
+#[tokio::test]
+async fn test_snapshot_cache_cancelling_fired_delta_watch_is_harmless() {
+    let cache = SnapshotCache::new(false);
+    let req = DeltaDiscoveryRequest {
+        node: request("foobar", "", "").node,
+        type_url: CLUSTER.to_string(),
+        resource_names_subscribe: vec!["a".to_string()],
+        ..DeltaDiscoveryRequest::default()
+    };
+    let mut stream = DeltaStreamHandle::new(&req);
+    stream.apply_subscriptions(&req);
+    let (tx, mut rx) = mpsc::channel(1);
+    let fired = cache
+        .create_delta_watch(&req, tx.clone(), &stream)
+        .await
+        .unwrap()
+        .unwrap();
+    cache.set_snapshot("foobar", snapshot("1", &["a"])).await;
+    let (_, versions, watch_id) = rx.try_recv().unwrap();
+    assert_eq!(watch_id, Some(fired.clone()));
+    stream.set_resource_versions(versions);
+
+    let watch_id = cache
+        .create_delta_watch(&req, tx, &stream)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_ne!(watch_id, fired);
+    cache.cancel_delta_watch(&fired).await;
+    let mut updated = snapshot("2", &["a"]);
+    let mut resources = Resources::new("2".to_string());
+    resources.insert(
+        "a".to_string(),
+        Resource::Cluster(Cluster {
+            name: "a".to_string(),
+            alt_stat_name: "modified".to_string(),
+            ..Cluster::default()
+        }),
+    );
+    updated.insert(CLUSTER.to_string(), resources);
+    cache.set_snapshot("foobar", updated).await;
+    let (rep, _, _) = rx.try_recv().unwrap();
+    assert_eq!(rep.resources[0].name, "a");
+}
+
+#[tokio::test]
+async fn test_snapshot_cache_identical_content_addressed_snapshot_does_not_wake_watches() {
+    let cache = SnapshotCache::new(false);
+    let snapshot = content_addressed_snapshot(&[("a", ""), ("b", "")]);
+    let version = snapshot.version(CLUSTER).to_string();
+    cache.set_snapshot("node", snapshot).await;
+    let (tx, mut rx) = mpsc::channel(1);
+    assert!(cache
+        .create_watch(&request("node", "", &version), tx, &StreamHandle::new())
+        .await
+        .unwrap()
+        .is_some());
+
+    // Built separately, and in a different order, but with the same content.
+    let identical = content_addressed_snapshot(&[("b", ""), ("a", "")]);
+    assert_eq!(identical.version(CLUSTER), version);
+    cache.set_snapshot("node", identical).await;
+    assert!(rx.try_recv().is_err());
+    let upserted = cache
+        .upsert_resources(
+            "node",
+            CLUSTER,
+            HashMap::from([(
+                "a".to_string(),
+                Resource::Cluster(Cluster {
+                    name: "a".to_string(),
+                    ..Cluster::default()
+                }),
+            )]),
+        )
+        .await
+        .unwrap();
+    assert_eq!(upserted, version);
+    assert!(rx.try_recv().is_err());
+
+    let modified = content_addressed_snapshot(&[("a", "modified"), ("b", "")]);
+    let modified_version = modified.version(CLUSTER).to_string();
+    assert_ne!(modified_version, version);
+    cache.set_snapshot("node", modified).await;
+    assert_eq!(rx.try_recv().unwrap().1.version_info, modified_version);
+}
@@ -1,7 +1,14 @@
+pub mod ack_tracker;
+pub mod callbacks;
+pub mod client_status;
 pub mod common;
 mod delta_stream;
 mod delta_watches;
 mod discovery;
+pub mod health;
+pub mod load_stats;
 mod stream;
 pub mod stream_handle;
+#[cfg(test)]
+mod test_util;
 mod watches;
@@ -0,0 +1,336 @@
+#[cfg(test)]
+mod test;
+
+use crate::cache::node_hash::{IdHash, NodeHash};
+use crate::snapshot::Resource;
+use data_plane_api::envoy::config::core::v3::Node;
+use data_plane_api::envoy::service::discovery::v3::DeltaDiscoveryResponse;
+use data_plane_api::google::protobuf::Any;
+use data_plane_api::google::rpc::Status;
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::time::Duration;
+use tokio::sync::{Mutex, Notify};
+
+// What a node has done with the responses sent to it for a single type URL.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AckStatus {
+    // The version of the last response sent to the node.
+    pub last_sent_version: Option<String>,
+    // The version of the last response the node accepted.
+    pub last_acked_version: Option<String>,
+    // The version of the last response the node rejected, and why it rejected it.
+    pub last_nacked_version: Option<String>,
+    pub nack_detail: Option<Status>,
+}
+
+// A resource as last sent to a node.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SentResource {
+    // Empty if the resource was sent over SotW, and isn't of a built-in type.
+    pub name: String,
+    pub version: String,
+    pub resource: Option<Any>,
+}
+
+// Records the responses sent on SotW and delta streams, and whether nodes ACKed or NACKed them,
+// keyed by node ID and type URL. Nodes which share a snapshot are still tracked separately, as
+// each may ACK or NACK it. A node is forgotten once its last stream closes.
+#[derive(Debug)]
+pub struct AckTracker {
+    nodes: Mutex<HashMap<String, NodeEntry>>,
+    // Woken whenever a node ACKs or NACKs a response.
+    changed: Notify,
+}
+
+#[derive(Debug, Default)]
+struct NodeEntry {
+    // The node as last sent in a request.
+    node: Option<Node>,
+    // The number of open streams which have identified themselves as this node.
+    streams: usize,
+    statuses: HashMap<String, AckStatus>,
+    // The versions recently sent for each type URL, in the order they were last sent.
+    sent_versions: HashMap<String, VecDeque<String>>,
+    sent_resources: HashMap<String, SentResources>,
+}
+
+// The resources last sent to a node for a type URL. The encodings are shared with the cache
+// they were sent from, so keeping them is cheap.
+#[derive(Debug)]
+enum SentResources {
+    // A SotW response, which replaces every resource of its type. Its resources are only named
+    // when they're reported, as naming them means decoding them.
+    Sotw {
+        version: String,
+        resources: Vec<Any>,
+    },
+    // The resources added by delta responses, and not since removed, keyed by name.
+    Delta(HashMap<String, SentResource>),
+}
+
+// How many sent versions are remembered per type URL, to tell whether a version was superseded.
+const SENT_HISTORY: usize = 16;
+
+// Why a node didn't ACK the version being waited for.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AckError {
+    Nacked(Status),
+    // A later version was sent before the node responded to this one, and the node NACKed it.
+    Superseded,
+    Timeout,
+}
+
+impl fmt::Display for AckError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AckError::Nacked(status) => write!(f, "nacked: {}", status.message),
+            AckError::Superseded => write!(f, "superseded by a nacked version"),
+            AckError::Timeout => write!(f, "timed out waiting for ack"),
+        }
+    }
+}
+
+impl Error for AckError {}
+
+impl AckTracker {
+    pub fn new() -> Self {
+        Self {
+            nodes: Mutex::new(HashMap::new()),
+            changed: Notify::new(),
+        }
+    }
+
+    pub async fn status(&self, node_id: &str, type_url: &str) -> Option<AckStatus> {
+        let nodes = self.nodes.lock().await;
+        nodes.get(node_id)?.statuses.get(type_url).cloned()
+    }
+
+    // Returns the status of every type URL sent to a node, keyed by type URL.
+    pub async fn node_status(&self, node_id: &str) -> HashMap<String, AckStatus> {
+        let nodes = self.nodes.lock().await;
+        nodes
+            .get(node_id)
+            .map(|entry| entry.statuses.clone())
+            .unwrap_or_default()
+    }
+
+    // Returns the node with an ID, as last sent in a request.
+    pub async fn node(&self, node_id: &str) -> Option<Node> {
+        let nodes = self.nodes.lock().await;
+        nodes.get(node_id)?.node.clone()
+    }
+
+    pub async fn nodes(&self) -> Vec<String> {
+        let nodes = self.nodes.lock().await;
+        nodes.keys().cloned().collect()
+    }
+
+    // Returns the resources of a type URL as last sent to a node, sorted by name.
+    pub async fn sent_resources(&self, node_id: &str, type_url: &str) -> Vec<SentResource> {
+        let nodes = self.nodes.lock().await;
+        let mut resources = match nodes
+            .get(node_id)
+            .and_then(|entry| entry.sent_resources.get(type_url))
+        {
+            Some(SentResources::Sotw { version, resources }) => resources
+                .iter()
+                .map(|any| SentResource {
+                    name: Resource::from_any(any)
+                        .map(|resource| resource.name().to_string())
+                        .unwrap_or_default(),
+                    version: version.clone(),
+                    resource: Some(any.clone()),
+                })
+                .collect(),
+            Some(SentResources::Delta(resources)) => resources.values().cloned().collect(),
+            None => Vec::new(),
+        };
+        resources.sort_by(|a, b| a.name.cmp(&b.name));
+        resources
+    }
+
+    // Waits until the node ACKs the given version of a type URL, or a version sent after it,
+    // returning immediately if it already has. Fails if the node NACKs the version or the one
+    // which superseded it, or doesn't respond within the timeout.
+    pub async fn wait_for_ack(
+        &self,
+        node_id: &str,
+        type_url: &str,
+        version: &str,
+        timeout: Duration,
+    ) -> Result<(), AckError> {
+        let wait = async {
+            loop {
+                // Subscribe before checking, so that a change in between isn't missed.
+                let changed = self.changed.notified();
+                if let Some(result) = self.outcome(node_id, type_url, version).await {
+                    return result;
+                }
+                changed.await;
+            }
+        };
+        tokio::time::timeout(timeout, wait)
+            .await
+            .unwrap_or(Err(AckError::Timeout))
+    }
+
+    // Whether the node has responded to a version, or to one sent after it, which is all a
+    // node responds to once it's been sent something newer.
+    async fn outcome(
+        &self,
+        node_id: &str,
+        type_url: &str,
+        version: &str,
+    ) -> Option<Result<(), AckError>> {
+        let nodes = self.nodes.lock().await;
+        let entry = nodes.get(node_id)?;
+        let status = entry.statuses.get(type_url)?;
+        if status.last_acked_version.as_deref() == Some(version) {
+            return Some(Ok(()));
+        }
+        if status.last_nacked_version.as_deref() == Some(version) {
+            return Some(Err(AckError::Nacked(
+                status.nack_detail.clone().unwrap_or_default(),
+            )));
+        }
+        let sent = entry.sent_versions.get(type_url)?;
+        let later = sent
+            .iter()
+            .skip(sent.iter().position(|v| v == version)? + 1);
+        for later in later {
+            if status.last_acked_version.as_ref() == Some(later) {
+                return Some(Ok(()));
+            }
+            if status.last_nacked_version.as_ref() == Some(later) {
+                return Some(Err(AckError::Superseded));
+            }
+        }
+        None
+    }
+
+    // Called when a stream learns which node it serves.
+    pub(crate) async fn stream_opened(&self, node: &Option<Node>) {
+        let mut nodes = self.nodes.lock().await;
+        nodes.entry(IdHash.hash(node)).or_default().streams += 1;
+    }
+
+    // Called when a stream which learnt its node closes. The node's statuses are dropped once
+    // none of its streams are open.
+    pub(crate) async fn stream_closed(&self, node: &Option<Node>) {
+        let key = IdHash.hash(node);
+        let mut nodes = self.nodes.lock().await;
+        if let Some(entry) = nodes.get_mut(&key) {
+            entry.streams = entry.streams.saturating_sub(1);
+            if entry.streams == 0 {
+                nodes.remove(&key);
+            }
+        }
+    }
+
+    // Records a SotW response.
+    pub(crate) async fn sent(
+        &self,
+        node: &Option<Node>,
+        type_url: &str,
+        version: &str,
+        resources: &[Any],
+    ) {
+        self.update(node, |entry| {
+            entry.sent(type_url, version);
+            let resources = SentResources::Sotw {
+                version: version.to_string(),
+                resources: resources.to_vec(),
+            };
+            entry.sent_resources.insert(type_url.to_string(), resources);
+        })
+        .await;
+    }
+
+    pub(crate) async fn sent_delta(&self, node: &Option<Node>, rep: &DeltaDiscoveryResponse) {
+        self.update(node, |entry| {
+            entry.sent(&rep.type_url, &rep.system_version_info);
+            let resources = entry
+                .sent_resources
+                .entry(rep.type_url.clone())
+                .or_insert_with(|| SentResources::Delta(HashMap::new()));
+            if let SentResources::Sotw { .. } = resources {
+                // Another stream of the node uses SotW.
+                *resources = SentResources::Delta(HashMap::new());
+            }
+            if let SentResources::Delta(resources) = resources {
+                for name in &rep.removed_resources {
+                    resources.remove(name);
+                }
+                for resource in &rep.resources {
+                    let sent = SentResource {
+                        name: resource.name.clone(),
+                        version: resource.version.clone(),
+                        resource: resource.resource.clone(),
+                    };
+                    resources.insert(resource.name.clone(), sent);
+                }
+            }
+        })
+        .await;
+    }
+
+    pub(crate) async fn acked(&self, node: &Option<Node>, type_url: &str, version: &str) {
+        self.update(node, |entry| {
+            entry.status(type_url).last_acked_version = Some(version.to_string());
+        })
+        .await;
+        self.changed.notify_waiters();
+    }
+
+    pub(crate) async fn nacked(
+        &self,
+        node: &Option<Node>,
+        type_url: &str,
+        version: &str,
+        detail: Status,
+    ) {
+        self.update(node, |entry| {
+            let status = entry.status(type_url);
+            status.last_nacked_version = Some(version.to_string());
+            status.nack_detail = Some(detail);
+        })
+        .await;
+        self.changed.notify_waiters();
+    }
+
+    async fn update<F>(&self, node: &Option<Node>, f: F)
+    where
+        F: FnOnce(&mut NodeEntry),
+    {
+        let mut nodes = self.nodes.lock().await;
+        let entry = nodes.entry(IdHash.hash(node)).or_default();
+        if node.is_some() && entry.node != *node {
+            entry.node = node.clone();
+        }
+        f(entry);
+    }
+}
+
+impl NodeEntry {
+    fn status(&mut self, type_url: &str) -> &mut AckStatus {
+        self.statuses.entry(type_url.to_string()).or_default()
+    }
+
+    fn sent(&mut self, type_url: &str, version: &str) {
+        self.status(type_url).last_sent_version = Some(version.to_string());
+        let sent = self.sent_versions.entry(type_url.to_string()).or_default();
+        sent.retain(|sent| sent != version);
+        if sent.len() == SENT_HISTORY {
+            sent.pop_front();
+        }
+        sent.push_back(version.to_string());
+    }
+}
+
+impl Default for AckTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
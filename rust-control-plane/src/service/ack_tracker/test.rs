@@ -0,0 +1,153 @@
+use crate::service::ack_tracker::{AckError, AckTracker};
+use crate::snapshot::type_url::CLUSTER;
+use data_plane_api::envoy::config::core::v3::Node;
+use data_plane_api::google::rpc::Status;
+use std::sync::Arc;
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+fn node(id: &str) -> Option<Node> {
+    Some(Node {
+        id: id.to_string(),
+        ..Node::default()
+    })
+}
+
+#[tokio::test]
+async fn test_wait_for_ack_resolves_on_ack() {
+    let tracker = Arc::new(AckTracker::new());
+    tracker.sent(&node("foobar"), CLUSTER, "1", &[]).await;
+    let wait = tokio::spawn({
+        let tracker = tracker.clone();
+        async move { tracker.wait_for_ack("foobar", CLUSTER, "1", TIMEOUT).await }
+    });
+    tokio::task::yield_now().await;
+    tracker.acked(&node("foobar"), CLUSTER, "1").await;
+    assert_eq!(wait.await.unwrap(), Ok(()));
+    // Already ACKed versions resolve immediately.
+    assert_eq!(
+        tracker.wait_for_ack("foobar", CLUSTER, "1", TIMEOUT).await,
+        Ok(())
+    );
+}
+
+#[tokio::test]
+async fn test_wait_for_ack_fails_on_nack() {
+    let tracker = Arc::new(AckTracker::new());
+    let wait = tokio::spawn({
+        let tracker = tracker.clone();
+        async move { tracker.wait_for_ack("foobar", CLUSTER, "2", TIMEOUT).await }
+    });
+    tokio::task::yield_now().await;
+    // A NACK of another version doesn't end the wait.
+    let detail = Status {
+        message: "bad cluster".to_string(),
+        ..Status::default()
+    };
+    tracker
+        .nacked(&node("foobar"), CLUSTER, "1", detail.clone())
+        .await;
+    tokio::task::yield_now().await;
+    assert!(!wait.is_finished());
+    tracker
+        .nacked(&node("foobar"), CLUSTER, "2", detail.clone())
+        .await;
+    assert_eq!(wait.await.unwrap(), Err(AckError::Nacked(detail)));
+}
+
+#[tokio::test]
+async fn test_wait_for_ack_times_out() {
+    let tracker = AckTracker::new();
+    tracker.acked(&node("foobar"), CLUSTER, "1").await;
+    let result = tracker
+        .wait_for_ack("foobar", CLUSTER, "2", Duration::from_millis(10))
+        .await;
+    assert_eq!(result, Err(AckError::Timeout));
+}
+
+#[tokio::test]
+async fn test_sent_records_node() {
+    let tracker = AckTracker::new();
+    tracker.sent(&node("foobar"), CLUSTER, "1", &[]).await;
+    assert_eq!(tracker.node("foobar").await, node("foobar"));
+    assert_eq!(
+        tracker
+            .status("foobar", CLUSTER)
+            .await
+            .unwrap()
+            .last_sent_version,
+        Some("1".to_string())
+    );
+    assert_eq!(tracker.node("missing").await, None);
+}
+
+#[tokio::test]
+async fn test_node_forgotten_when_last_stream_closes() {
+    let tracker = AckTracker::new();
+    tracker.stream_opened(&node("foobar")).await;
+    tracker.stream_opened(&node("foobar")).await;
+    tracker.sent(&node("foobar"), CLUSTER, "1", &[]).await;
+
+    tracker.stream_closed(&node("foobar")).await;
+    assert!(tracker.status("foobar", CLUSTER).await.is_some());
+    tracker.stream_closed(&node("foobar")).await;
+    assert_eq!(tracker.status("foobar", CLUSTER).await, None);
+    assert_eq!(tracker.node("foobar").await, None);
+    assert!(tracker.nodes().await.is_empty());
+}
+
+#[tokio::test]
+async fn test_nodes_sharing_a_cluster_tracked_separately() {
+    let tracker = AckTracker::new();
+    let sidecar = |id: &str| {
+        Some(Node {
+            id: id.to_string(),
+            cluster: "sidecars".to_string(),
+            ..Node::default()
+        })
+    };
+    tracker.sent(&sidecar("a"), CLUSTER, "1", &[]).await;
+    tracker.sent(&sidecar("b"), CLUSTER, "1", &[]).await;
+    tracker.acked(&sidecar("b"), CLUSTER, "1").await;
+    let mut nodes = tracker.nodes().await;
+    nodes.sort();
+    assert_eq!(nodes, vec!["a".to_string(), "b".to_string()]);
+    let status = tracker.status("a", CLUSTER).await.unwrap();
+    assert_eq!(status.last_acked_version, None);
+    let result = tracker
+        .wait_for_ack("a", CLUSTER, "1", Duration::from_millis(10))
+        .await;
+    assert_eq!(result, Err(AckError::Timeout));
+}
+
+#[tokio::test]
+async fn test_wait_for_ack_resolves_on_later_versions() {
+    let tracker = AckTracker::new();
+    for version in ["1", "2", "3"] {
+        tracker.sent(&node("foobar"), CLUSTER, version, &[]).await;
+    }
+    // The node only responds to the last version it was sent.
+    tracker.acked(&node("foobar"), CLUSTER, "2").await;
+    assert_eq!(
+        tracker.wait_for_ack("foobar", CLUSTER, "1", TIMEOUT).await,
+        Ok(())
+    );
+    tracker
+        .nacked(&node("foobar"), CLUSTER, "3", Status::default())
+        .await;
+    assert_eq!(
+        tracker.wait_for_ack("foobar", CLUSTER, "2", TIMEOUT).await,
+        Ok(())
+    );
+
+    tracker.sent(&node("foobar"), CLUSTER, "4", &[]).await;
+    tracker.sent(&node("foobar"), CLUSTER, "5", &[]).await;
+    tracker
+        .nacked(&node("foobar"), CLUSTER, "5", Status::default())
+        .await;
+    assert_eq!(
+        tracker.wait_for_ack("foobar", CLUSTER, "4", TIMEOUT).await,
+        Err(AckError::Superseded)
+    );
+}
@@ -0,0 +1,91 @@
+use crate::service::common::CloseReason;
+use async_trait::async_trait;
+use data_plane_api::envoy::config::core::v3::Node;
+use data_plane_api::envoy::service::discovery::v3::{
+    DeltaDiscoveryRequest, DeltaDiscoveryResponse, DiscoveryRequest, DiscoveryResponse,
+};
+use std::fmt::Debug;
+use tonic::Status;
+
+// Hooks into the lifecycle of the streams and requests served by a Service, e.g. for auditing,
+// authorization, or metrics. Every method does nothing by default.
+// Returning an error from a fallible callback closes the stream with that status, or in the
+// case of on_fetch_request, fails the request.
+#[async_trait]
+pub trait Callbacks: Debug + Send + Sync + 'static {
+    // Called when a SotW stream is opened, before any requests are read. The type URL is
+    // ANY_TYPE for ADS streams.
+    async fn on_stream_open(&self, _stream_id: usize, _type_url: &str) -> Result<(), Status> {
+        Ok(())
+    }
+
+    // Called when a SotW stream which was successfully opened is closed, with the last node
+    // seen on it, and why it closed.
+    async fn on_stream_closed(
+        &self,
+        _stream_id: usize,
+        _node: Option<&Node>,
+        _reason: &CloseReason,
+    ) {
+    }
+
+    // Called for every request received on a SotW stream, before it's handled.
+    async fn on_stream_request(
+        &self,
+        _stream_id: usize,
+        _req: &DiscoveryRequest,
+    ) -> Result<(), Status> {
+        Ok(())
+    }
+
+    // Called before a response is sent on a SotW stream, along with the request it answers.
+    async fn on_stream_response(
+        &self,
+        _stream_id: usize,
+        _req: &DiscoveryRequest,
+        _rep: &DiscoveryResponse,
+    ) {
+    }
+
+    // Called when a delta stream is opened, before any requests are read. The type URL is
+    // ANY_TYPE for ADS streams.
+    async fn on_delta_stream_open(&self, _stream_id: usize, _type_url: &str) -> Result<(), Status> {
+        Ok(())
+    }
+
+    // Called when a delta stream which was successfully opened is closed, with the last node
+    // seen on it, and why it closed.
+    async fn on_delta_stream_closed(
+        &self,
+        _stream_id: usize,
+        _node: Option<&Node>,
+        _reason: &CloseReason,
+    ) {
+    }
+
+    // Called for every request received on a delta stream, before it's handled.
+    async fn on_stream_delta_request(
+        &self,
+        _stream_id: usize,
+        _req: &DeltaDiscoveryRequest,
+    ) -> Result<(), Status> {
+        Ok(())
+    }
+
+    // Called before a response is sent on a delta stream.
+    async fn on_stream_delta_response(&self, _stream_id: usize, _rep: &DeltaDiscoveryResponse) {}
+
+    // Called for every unary fetch request, before it's handled.
+    async fn on_fetch_request(&self, _req: &DiscoveryRequest) -> Result<(), Status> {
+        Ok(())
+    }
+
+    // Called before a successful fetch response is returned.
+    async fn on_fetch_response(&self, _req: &DiscoveryRequest, _rep: &DiscoveryResponse) {}
+}
+
+// Callbacks which do nothing, for services which don't need any hooks.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopCallbacks;
+
+impl Callbacks for NoopCallbacks {}
@@ -0,0 +1,315 @@
+#[cfg(test)]
+mod test;
+
+use crate::cache::snapshot::SnapshotCache;
+use crate::service::ack_tracker::{AckStatus, AckTracker, SentResource};
+use crate::service::common::StreamResponse;
+use data_plane_api::envoy::admin::v3::{ClientResourceStatus, UpdateFailureState};
+use data_plane_api::envoy::config::core::v3::Node;
+use data_plane_api::envoy::r#type::matcher::v3::{
+    double_matcher, list_matcher, string_matcher, struct_matcher, value_matcher, DoubleMatcher,
+    NodeMatcher, StringMatcher, StructMatcher, ValueMatcher,
+};
+use data_plane_api::envoy::service::status::v3::client_config::GenericXdsConfig;
+use data_plane_api::envoy::service::status::v3::client_status_discovery_service_server::ClientStatusDiscoveryService;
+use data_plane_api::envoy::service::status::v3::{
+    ClientConfig, ClientStatusRequest, ClientStatusResponse, ConfigStatus,
+};
+use data_plane_api::google::protobuf::{value, Struct, Value};
+use futures::StreamExt;
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status, Streaming};
+use tracing::{info, info_span, Instrument};
+
+// Serves CSDS, reporting the config each node was sent, and whether it ACKed or NACKed it.
+// Only nodes which have been sent a response are reported. The SnapshotCache the nodes are
+// served from tells whether they've been sent their latest snapshot.
+#[derive(Debug, Clone)]
+pub struct ClientStatusService {
+    cache: Arc<SnapshotCache>,
+    // Must be shared with the services the nodes connect to.
+    ack_tracker: Arc<AckTracker>,
+}
+
+impl ClientStatusService {
+    pub fn new(cache: Arc<SnapshotCache>, ack_tracker: Arc<AckTracker>) -> Self {
+        Self { cache, ack_tracker }
+    }
+
+    // Reports every node matching any of the request's node matchers, or every node if there
+    // are none.
+    pub async fn client_status(&self, req: &ClientStatusRequest) -> ClientStatusResponse {
+        let mut node_ids = self.ack_tracker.nodes().await;
+        node_ids.sort();
+        let mut config = Vec::new();
+        for node_id in node_ids {
+            let node = match self.ack_tracker.node(&node_id).await {
+                Some(node) => node,
+                None => continue,
+            };
+            let matched = req.node_matchers.is_empty()
+                || req
+                    .node_matchers
+                    .iter()
+                    .any(|matcher| node_matches(matcher, &node));
+            if matched {
+                config.push(
+                    self.client_config(&node_id, node, req.exclude_resource_contents)
+                        .await,
+                );
+            }
+        }
+        ClientStatusResponse { config }
+    }
+
+    // Reports every type URL the node was sent, or which is in its snapshot.
+    async fn client_config(
+        &self,
+        node_id: &str,
+        node: Node,
+        exclude_resource_contents: bool,
+    ) -> ClientConfig {
+        let statuses = self.ack_tracker.node_status(node_id).await;
+        let key = self.cache.node_key(&Some(node.clone()));
+        let versions = self.cache.snapshot_versions(&key).await.unwrap_or_default();
+        let type_urls: BTreeSet<&String> = statuses.keys().chain(versions.keys()).collect();
+        let mut generic_xds_configs = Vec::new();
+        for type_url in type_urls {
+            let status = statuses.get(type_url).cloned().unwrap_or_default();
+            let sent = self.ack_tracker.sent_resources(node_id, type_url).await;
+            generic_xds_configs.extend(generic_xds_configs_for(
+                type_url,
+                &status,
+                versions.get(type_url).map(String::as_str),
+                sent,
+                exclude_resource_contents,
+            ));
+        }
+        ClientConfig {
+            node: Some(node),
+            generic_xds_configs,
+            ..ClientConfig::default()
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl ClientStatusDiscoveryService for ClientStatusService {
+    type StreamClientStatusStream = StreamResponse<ClientStatusResponse>;
+
+    async fn stream_client_status(
+        &self,
+        req: Request<Streaming<ClientStatusRequest>>,
+    ) -> Result<Response<Self::StreamClientStatusStream>, Status> {
+        let (tx, rx) = mpsc::channel(1);
+        let mut requests = req.into_inner();
+        let service = self.clone();
+        tokio::spawn(
+            async move {
+                while let Some(result) = requests.next().await {
+                    let req = match result {
+                        Ok(req) => req,
+                        Err(status) => {
+                            info!("client status stream receive failed: {}", status);
+                            return;
+                        }
+                    };
+                    let rep = service.client_status(&req).await;
+                    if tx.send(Ok(rep)).await.is_err() {
+                        return;
+                    }
+                }
+                info!("client status stream closed");
+            }
+            .instrument(info_span!("handle_client_status_stream")),
+        );
+        Ok(Response::new(
+            Box::pin(ReceiverStream::new(rx)) as StreamResponse<ClientStatusResponse>
+        ))
+    }
+
+    async fn fetch_client_status(
+        &self,
+        req: Request<ClientStatusRequest>,
+    ) -> Result<Response<ClientStatusResponse>, Status> {
+        Ok(Response::new(self.client_status(req.get_ref()).await))
+    }
+}
+
+// Describes each resource of a type as last sent to the node. The statuses say whether the node
+// is running it, and whether that's the node's latest snapshot. Types without sent resources,
+// such as those not sent yet, are described by a single config without a name, whose
+// version_info is the version the node last ACKed.
+fn generic_xds_configs_for(
+    type_url: &str,
+    status: &AckStatus,
+    snapshot_version: Option<&str>,
+    sent: Vec<SentResource>,
+    exclude_resource_contents: bool,
+) -> Vec<GenericXdsConfig> {
+    let (config_status, client_status) = statuses(status, snapshot_version);
+    let error_state = match (config_status, &status.last_nacked_version) {
+        (ConfigStatus::Error, Some(version)) => Some(UpdateFailureState {
+            details: status
+                .nack_detail
+                .as_ref()
+                .map(|detail| detail.message.clone())
+                .unwrap_or_default(),
+            version_info: version.clone(),
+            ..UpdateFailureState::default()
+        }),
+        _ => None,
+    };
+    let config = GenericXdsConfig {
+        type_url: type_url.to_string(),
+        version_info: status.last_acked_version.clone().unwrap_or_default(),
+        config_status: config_status as i32,
+        client_status: client_status as i32,
+        error_state,
+        ..GenericXdsConfig::default()
+    };
+    if sent.is_empty() {
+        return vec![config];
+    }
+    sent.into_iter()
+        .map(|resource| GenericXdsConfig {
+            name: resource.name,
+            version_info: resource.version,
+            xds_config: if exclude_resource_contents {
+                None
+            } else {
+                resource.resource
+            },
+            ..config.clone()
+        })
+        .collect()
+}
+
+// The status of the snapshot's config for a type, and what the node did with the last version
+// it was sent.
+fn statuses(
+    status: &AckStatus,
+    snapshot_version: Option<&str>,
+) -> (ConfigStatus, ClientResourceStatus) {
+    let sent = match &status.last_sent_version {
+        Some(sent) => sent,
+        None => return (ConfigStatus::NotSent, ClientResourceStatus::Unknown),
+    };
+    let client_status = if status.last_acked_version.as_ref() == Some(sent) {
+        ClientResourceStatus::Acked
+    } else if status.last_nacked_version.as_ref() == Some(sent) {
+        ClientResourceStatus::Nacked
+    } else {
+        ClientResourceStatus::Unknown
+    };
+    // The snapshot changed since the node was last sent it.
+    if matches!(snapshot_version, Some(version) if version != sent) {
+        return (ConfigStatus::NotSent, client_status);
+    }
+    let config_status = match client_status {
+        ClientResourceStatus::Acked => ConfigStatus::Synced,
+        ClientResourceStatus::Nacked => ConfigStatus::Error,
+        _ => ConfigStatus::Stale,
+    };
+    (config_status, client_status)
+}
+
+// Matches a node by its ID and metadata. Every given metadata matcher must match.
+pub fn node_matches(matcher: &NodeMatcher, node: &Node) -> bool {
+    let id_matches = match &matcher.node_id {
+        Some(node_id) => string_matches(node_id, &node.id),
+        None => true,
+    };
+    id_matches
+        && matcher
+            .node_metadatas
+            .iter()
+            .all(|metadata| struct_matches(metadata, node.metadata.as_ref()))
+}
+
+// Regex and custom matchers aren't supported, and never match.
+fn string_matches(matcher: &StringMatcher, value: &str) -> bool {
+    let fold = |s: &str| {
+        if matcher.ignore_case {
+            s.to_lowercase()
+        } else {
+            s.to_string()
+        }
+    };
+    let value = fold(value);
+    match &matcher.match_pattern {
+        Some(string_matcher::MatchPattern::Exact(pattern)) => value == fold(pattern),
+        Some(string_matcher::MatchPattern::Prefix(pattern)) => value.starts_with(&fold(pattern)),
+        Some(string_matcher::MatchPattern::Suffix(pattern)) => value.ends_with(&fold(pattern)),
+        Some(string_matcher::MatchPattern::Contains(pattern)) => value.contains(&fold(pattern)),
+        _ => false,
+    }
+}
+
+// Matches the value at the matcher's path, which is absent if any of the path is missing.
+fn struct_matches(matcher: &StructMatcher, metadata: Option<&Struct>) -> bool {
+    let absent = Value::default();
+    let mut current = metadata;
+    let mut value = None;
+    for segment in &matcher.path {
+        let key = match &segment.segment {
+            Some(struct_matcher::path_segment::Segment::Key(key)) => key,
+            None => return false,
+        };
+        value = current.and_then(|fields| fields.fields.get(key));
+        current = match value.and_then(|value| value.kind.as_ref()) {
+            Some(value::Kind::StructValue(fields)) => Some(fields),
+            _ => None,
+        };
+    }
+    match &matcher.value {
+        Some(value_matcher) => value_matches(value_matcher, value.unwrap_or(&absent)),
+        None => false,
+    }
+}
+
+fn value_matches(matcher: &ValueMatcher, value: &Value) -> bool {
+    let pattern = match &matcher.match_pattern {
+        Some(pattern) => pattern,
+        None => return false,
+    };
+    match (pattern, &value.kind) {
+        (value_matcher::MatchPattern::NullMatch(_), Some(value::Kind::NullValue(_))) => true,
+        (value_matcher::MatchPattern::DoubleMatch(matcher), Some(value::Kind::NumberValue(n))) => {
+            double_matches(matcher, *n)
+        }
+        (value_matcher::MatchPattern::StringMatch(matcher), Some(value::Kind::StringValue(s))) => {
+            string_matches(matcher, s)
+        }
+        (value_matcher::MatchPattern::BoolMatch(b), Some(value::Kind::BoolValue(v))) => b == v,
+        (value_matcher::MatchPattern::PresentMatch(present), kind) => *present == kind.is_some(),
+        (value_matcher::MatchPattern::ListMatch(matcher), Some(value::Kind::ListValue(list))) => {
+            match &matcher.match_pattern {
+                Some(list_matcher::MatchPattern::OneOf(matcher)) => list
+                    .values
+                    .iter()
+                    .any(|value| value_matches(matcher, value)),
+                None => false,
+            }
+        }
+        (value_matcher::MatchPattern::OrMatch(matcher), _) => matcher
+            .value_matchers
+            .iter()
+            .any(|matcher| value_matches(matcher, value)),
+        _ => false,
+    }
+}
+
+// Ranges include their start, but not their end.
+fn double_matches(matcher: &DoubleMatcher, value: f64) -> bool {
+    match &matcher.match_pattern {
+        Some(double_matcher::MatchPattern::Range(range)) => {
+            range.start <= value && value < range.end
+        }
+        Some(double_matcher::MatchPattern::Exact(exact)) => value == *exact,
+        None => false,
+    }
+}
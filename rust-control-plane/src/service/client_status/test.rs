@@ -0,0 +1,431 @@
+use crate::cache::node_hash::ClusterHash;
+use crate::cache::snapshot::SnapshotCache;
+use crate::service::ack_tracker::AckTracker;
+use crate::service::client_status::{node_matches, ClientStatusService};
+use crate::snapshot::type_url::{CLUSTER, LISTENER};
+use crate::snapshot::{Resource, Resources, Snapshot};
+use data_plane_api::envoy::admin::v3::ClientResourceStatus;
+use data_plane_api::envoy::config::cluster::v3::Cluster;
+use data_plane_api::envoy::config::core::v3::Node;
+use data_plane_api::envoy::r#type::matcher::v3::{
+    double_matcher, list_matcher, string_matcher, struct_matcher, value_matcher, DoubleMatcher,
+    ListMatcher, NodeMatcher, OrMatcher, StringMatcher, StructMatcher, ValueMatcher,
+};
+use data_plane_api::envoy::r#type::v3::DoubleRange;
+use data_plane_api::envoy::service::discovery::v3::{
+    DeltaDiscoveryResponse, Resource as DeltaResource,
+};
+use data_plane_api::envoy::service::status::v3::{ClientStatusRequest, ConfigStatus};
+use data_plane_api::google::protobuf::{value, Any, ListValue, Struct, Value};
+use data_plane_api::google::rpc::Status;
+use std::sync::Arc;
+
+fn node(id: &str, metadata: Vec<(&str, value::Kind)>) -> Node {
+    Node {
+        id: id.to_string(),
+        metadata: Some(Struct {
+            fields: metadata
+                .into_iter()
+                .map(|(key, kind)| (key.to_string(), Value { kind: Some(kind) }))
+                .collect(),
+        }),
+        ..Node::default()
+    }
+}
+
+fn string(pattern: string_matcher::MatchPattern) -> StringMatcher {
+    StringMatcher {
+        match_pattern: Some(pattern),
+        ignore_case: false,
+    }
+}
+
+fn node_id(pattern: string_matcher::MatchPattern) -> NodeMatcher {
+    NodeMatcher {
+        node_id: Some(string(pattern)),
+        node_metadatas: vec![],
+    }
+}
+
+fn metadata(path: &[&str], pattern: value_matcher::MatchPattern) -> NodeMatcher {
+    NodeMatcher {
+        node_id: None,
+        node_metadatas: vec![StructMatcher {
+            path: path
+                .iter()
+                .map(|key| struct_matcher::PathSegment {
+                    segment: Some(struct_matcher::path_segment::Segment::Key(key.to_string())),
+                })
+                .collect(),
+            value: Some(ValueMatcher {
+                match_pattern: Some(pattern),
+            }),
+        }],
+    }
+}
+
+fn string_value(s: &str) -> value::Kind {
+    value::Kind::StringValue(s.to_string())
+}
+
+fn clusters(version: &str, names: &[&str]) -> Snapshot {
+    let mut resources = Resources::new(version.to_string());
+    for name in names {
+        resources.insert(
+            name.to_string(),
+            Resource::Cluster(Cluster {
+                name: name.to_string(),
+                ..Cluster::default()
+            }),
+        );
+    }
+    let mut snapshot = Snapshot::new();
+    snapshot.insert(CLUSTER.to_string(), resources);
+    snapshot
+}
+
+// The resources of a snapshot as sent in a SotW response.
+fn anys(snapshot: &Snapshot) -> Vec<Any> {
+    snapshot
+        .resources(CLUSTER)
+        .unwrap()
+        .items
+        .values()
+        .map(|item| item.to_any())
+        .collect()
+}
+
+#[test]
+fn test_node_matches() {
+    use string_matcher::MatchPattern::{Contains, Exact, Prefix, Suffix};
+    use value_matcher::MatchPattern::{
+        BoolMatch, DoubleMatch, ListMatch, OrMatch, PresentMatch, StringMatch,
+    };
+    let nested = value::Kind::StructValue(Struct {
+        fields: [(
+            "version".to_string(),
+            Value {
+                kind: Some(string_value("1.2")),
+            },
+        )]
+        .into_iter()
+        .collect(),
+    });
+    let list = value::Kind::ListValue(ListValue {
+        values: vec![Value {
+            kind: Some(string_value("canary")),
+        }],
+    });
+    let node = node(
+        "sidecar~10.0.0.1~app",
+        vec![
+            ("istio", nested),
+            ("labels", list),
+            ("weight", value::Kind::NumberValue(5.0)),
+            ("ready", value::Kind::BoolValue(true)),
+        ],
+    );
+    let string_match = |s: &str| StringMatch(string(Exact(s.to_string())));
+
+    let cases = vec![
+        ("empty", NodeMatcher::default(), true),
+        ("exact id", node_id(Exact(node.id.clone())), true),
+        ("prefix id", node_id(Prefix("sidecar~".to_string())), true),
+        ("suffix id", node_id(Suffix("~app".to_string())), true),
+        (
+            "contains id",
+            node_id(Contains("10.0.0.1".to_string())),
+            true,
+        ),
+        ("other id", node_id(Exact("router".to_string())), false),
+        (
+            "ignore case id",
+            NodeMatcher {
+                node_id: Some(StringMatcher {
+                    match_pattern: Some(Prefix("SIDECAR".to_string())),
+                    ignore_case: true,
+                }),
+                node_metadatas: vec![],
+            },
+            true,
+        ),
+        (
+            "nested metadata",
+            metadata(&["istio", "version"], string_match("1.2")),
+            true,
+        ),
+        (
+            "nested metadata mismatch",
+            metadata(&["istio", "version"], string_match("1.3")),
+            false,
+        ),
+        ("present", metadata(&["ready"], PresentMatch(true)), true),
+        (
+            "missing",
+            metadata(&["istio", "missing"], PresentMatch(true)),
+            false,
+        ),
+        (
+            "not present",
+            metadata(&["missing"], PresentMatch(false)),
+            true,
+        ),
+        ("bool", metadata(&["ready"], BoolMatch(true)), true),
+        (
+            "double range excludes end",
+            metadata(
+                &["weight"],
+                DoubleMatch(DoubleMatcher {
+                    match_pattern: Some(double_matcher::MatchPattern::Range(DoubleRange {
+                        start: 1.0,
+                        end: 5.0,
+                    })),
+                }),
+            ),
+            false,
+        ),
+        (
+            "list one of",
+            metadata(
+                &["labels"],
+                ListMatch(Box::new(ListMatcher {
+                    match_pattern: Some(list_matcher::MatchPattern::OneOf(Box::new(
+                        ValueMatcher {
+                            match_pattern: Some(string_match("canary")),
+                        },
+                    ))),
+                })),
+            ),
+            true,
+        ),
+        (
+            "or",
+            metadata(
+                &["istio", "version"],
+                OrMatch(OrMatcher {
+                    value_matchers: vec![
+                        ValueMatcher {
+                            match_pattern: Some(string_match("1.1")),
+                        },
+                        ValueMatcher {
+                            match_pattern: Some(string_match("1.2")),
+                        },
+                    ],
+                }),
+            ),
+            true,
+        ),
+        (
+            "id and metadata",
+            NodeMatcher {
+                node_id: Some(string(Exact("router".to_string()))),
+                ..metadata(&["ready"], BoolMatch(true))
+            },
+            false,
+        ),
+    ];
+    for (name, matcher, expected) in cases {
+        assert_eq!(node_matches(&matcher, &node), expected, "{}", name);
+    }
+}
+
+#[tokio::test]
+async fn test_client_status_reports_acked_resources() {
+    let cache = Arc::new(SnapshotCache::new(false));
+    let tracker = Arc::new(AckTracker::new());
+    let service = ClientStatusService::new(cache.clone(), tracker.clone());
+    let snapshot = clusters("1", &["x", "y"]);
+    cache.set_snapshot("a", snapshot.clone()).await;
+    let a = Some(node("a", vec![]));
+    tracker.sent(&a, CLUSTER, "1", &anys(&snapshot)).await;
+    tracker.acked(&a, CLUSTER, "1").await;
+
+    let rep = service.client_status(&ClientStatusRequest::default()).await;
+    assert_eq!(rep.config.len(), 1);
+    let config = &rep.config[0];
+    assert_eq!(config.node.as_ref().unwrap().id, "a");
+    let names: Vec<&str> = config
+        .generic_xds_configs
+        .iter()
+        .map(|config| config.name.as_str())
+        .collect();
+    assert_eq!(names, vec!["x", "y"]);
+    for xds_config in &config.generic_xds_configs {
+        assert_eq!(xds_config.type_url, CLUSTER);
+        assert_eq!(xds_config.version_info, "1");
+        assert_eq!(xds_config.config_status, ConfigStatus::Synced as i32);
+        assert_eq!(xds_config.client_status, ClientResourceStatus::Acked as i32);
+        assert_eq!(xds_config.xds_config.as_ref().unwrap().type_url, CLUSTER);
+    }
+
+    let rep = service
+        .client_status(&ClientStatusRequest {
+            exclude_resource_contents: true,
+            ..ClientStatusRequest::default()
+        })
+        .await;
+    assert!(rep.config[0].generic_xds_configs[0].xds_config.is_none());
+}
+
+#[tokio::test]
+async fn test_client_status_reports_nacks_and_pending_versions() {
+    let cache = Arc::new(SnapshotCache::new(false));
+    let tracker = Arc::new(AckTracker::new());
+    let service = ClientStatusService::new(cache.clone(), tracker.clone());
+    let snapshot = clusters("2", &["x"]);
+    cache.set_snapshot("a", snapshot.clone()).await;
+    let a = Some(node("a", vec![]));
+    tracker.sent(&a, CLUSTER, "1", &[]).await;
+    tracker.acked(&a, CLUSTER, "1").await;
+    tracker.sent(&a, CLUSTER, "2", &anys(&snapshot)).await;
+    let detail = Status {
+        message: "bad cluster".to_string(),
+        ..Status::default()
+    };
+    tracker.nacked(&a, CLUSTER, "2", detail).await;
+    // Listeners come from elsewhere, and aren't in the snapshot.
+    tracker.sent(&a, LISTENER, "7", &[]).await;
+
+    let rep = service.client_status(&ClientStatusRequest::default()).await;
+    let configs = &rep.config[0].generic_xds_configs;
+    assert_eq!(configs.len(), 2);
+    let cluster = &configs[0];
+    assert_eq!(cluster.name, "x");
+    assert_eq!(cluster.version_info, "2");
+    assert_eq!(cluster.config_status, ConfigStatus::Error as i32);
+    assert_eq!(cluster.client_status, ClientResourceStatus::Nacked as i32);
+    let error_state = cluster.error_state.as_ref().unwrap();
+    assert_eq!(error_state.details, "bad cluster");
+    assert_eq!(error_state.version_info, "2");
+
+    let listener = &configs[1];
+    assert_eq!(listener.type_url, LISTENER);
+    assert_eq!(listener.name, "");
+    assert_eq!(listener.version_info, "");
+    assert_eq!(listener.config_status, ConfigStatus::Stale as i32);
+    assert_eq!(listener.client_status, ClientResourceStatus::Unknown as i32);
+
+    // A new snapshot hasn't been sent yet, so what was sent is still reported.
+    cache.set_snapshot("a", clusters("3", &["x", "y"])).await;
+    let rep = service.client_status(&ClientStatusRequest::default()).await;
+    let configs = &rep.config[0].generic_xds_configs;
+    assert_eq!(configs.len(), 2);
+    let cluster = &configs[0];
+    assert_eq!(cluster.version_info, "2");
+    assert_eq!(cluster.config_status, ConfigStatus::NotSent as i32);
+    assert!(cluster.error_state.is_none());
+}
+
+#[tokio::test]
+async fn test_client_status_reports_delta_resources() {
+    let cache = Arc::new(SnapshotCache::new(false));
+    let tracker = Arc::new(AckTracker::new());
+    let service = ClientStatusService::new(cache, tracker.clone());
+    let a = Some(node("a", vec![]));
+    let resources = anys(&clusters("1", &["x", "y"]));
+    let delta = |resources: Vec<DeltaResource>, removed: &[&str]| DeltaDiscoveryResponse {
+        type_url: CLUSTER.to_string(),
+        system_version_info: "1".to_string(),
+        resources,
+        removed_resources: removed.iter().map(|name| name.to_string()).collect(),
+        ..DeltaDiscoveryResponse::default()
+    };
+    let resource = |name: &str, version: &str| DeltaResource {
+        name: name.to_string(),
+        version: version.to_string(),
+        resource: Some(resources[0].clone()),
+        ..DeltaResource::default()
+    };
+    tracker
+        .sent_delta(
+            &a,
+            &delta(vec![resource("x", "x1"), resource("y", "y1")], &[]),
+        )
+        .await;
+    tracker
+        .sent_delta(&a, &delta(vec![resource("x", "x2")], &["y"]))
+        .await;
+
+    let rep = service.client_status(&ClientStatusRequest::default()).await;
+    let configs = &rep.config[0].generic_xds_configs;
+    assert_eq!(configs.len(), 1);
+    assert_eq!(configs[0].name, "x");
+    assert_eq!(configs[0].version_info, "x2");
+}
+
+#[tokio::test]
+async fn test_client_status_filters_nodes() {
+    let cache = Arc::new(SnapshotCache::new(false));
+    let tracker = Arc::new(AckTracker::new());
+    let service = ClientStatusService::new(cache, tracker.clone());
+    for id in ["gateway-1", "sidecar-1", "sidecar-2"] {
+        tracker
+            .sent(&Some(node(id, vec![])), CLUSTER, "1", &[])
+            .await;
+    }
+    let rep = service
+        .client_status(&ClientStatusRequest {
+            node_matchers: vec![
+                node_id(string_matcher::MatchPattern::Prefix("sidecar-".to_string())),
+                node_id(string_matcher::MatchPattern::Exact("missing".to_string())),
+            ],
+            ..ClientStatusRequest::default()
+        })
+        .await;
+    let ids: Vec<&str> = rep
+        .config
+        .iter()
+        .map(|config| config.node.as_ref().unwrap().id.as_str())
+        .collect();
+    assert_eq!(ids, vec!["sidecar-1", "sidecar-2"]);
+    // Types sent but not in a snapshot are still reported.
+    assert_eq!(rep.config[0].generic_xds_configs.len(), 1);
+}
+
+#[tokio::test]
+async fn test_client_status_reports_nodes_sharing_a_snapshot_separately() {
+    let cache = Arc::new(SnapshotCache::new(false).with_node_hash(ClusterHash));
+    let tracker = Arc::new(AckTracker::new());
+    let service = ClientStatusService::new(cache.clone(), tracker.clone());
+    let snapshot = clusters("1", &["x"]);
+    cache.set_snapshot("sidecars", snapshot.clone()).await;
+    for (id, zone) in [("a", "east"), ("b", "west")] {
+        let node = Some(Node {
+            cluster: "sidecars".to_string(),
+            ..node(id, vec![("zone", string_value(zone))])
+        });
+        tracker.sent(&node, CLUSTER, "1", &anys(&snapshot)).await;
+        if id == "a" {
+            tracker.acked(&node, CLUSTER, "1").await;
+        }
+    }
+
+    let rep = service.client_status(&ClientStatusRequest::default()).await;
+    assert_eq!(rep.config.len(), 2);
+    let client_statuses: Vec<i32> = rep
+        .config
+        .iter()
+        .map(|config| config.generic_xds_configs[0].client_status)
+        .collect();
+    assert_eq!(
+        client_statuses,
+        vec![
+            ClientResourceStatus::Acked as i32,
+            ClientResourceStatus::Unknown as i32
+        ]
+    );
+
+    let rep = service
+        .client_status(&ClientStatusRequest {
+            node_matchers: vec![metadata(
+                &["zone"],
+                value_matcher::MatchPattern::StringMatch(string(
+                    string_matcher::MatchPattern::Exact("west".to_string()),
+                )),
+            )],
+            ..ClientStatusRequest::default()
+        })
+        .await;
+    assert_eq!(rep.config.len(), 1);
+    assert_eq!(rep.config[0].node.as_ref().unwrap().id, "b");
+}
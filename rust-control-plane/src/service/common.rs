@@ -1,11 +1,15 @@
-use crate::cache::{Cache, FetchError};
+use crate::cache::{Cache, FetchError, WatchError};
+use crate::service::ack_tracker::AckTracker;
+use crate::service::callbacks::Callbacks;
 use crate::service::delta_stream::handle_delta_stream;
 use crate::service::stream::handle_stream;
 use crate::snapshot::type_url;
+use data_plane_api::envoy::config::core::v3::Locality;
 use data_plane_api::envoy::service::discovery::v3::{
     DeltaDiscoveryRequest, DeltaDiscoveryResponse, DiscoveryRequest, DiscoveryResponse,
 };
 use futures::Stream;
+use std::fmt;
 use std::pin::Pin;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
@@ -18,19 +22,88 @@ use tracing::{info_span, Instrument};
 #[derive(Debug)]
 pub struct Service<C: Cache> {
     cache: Arc<C>,
+    callbacks: Arc<dyn Callbacks>,
+    ack_tracker: Arc<AckTracker>,
     next_stream_id: AtomicUsize,
 }
 
 pub type StreamResponse<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send + 'static>>;
 
+// How many updates the stores of reports from nodes buffer for each subscriber. Subscribers which
+// fall further behind miss updates.
+pub(crate) const UPDATES_CAPACITY: usize = 1024;
+
+// Identifies a locality, which as a protobuf message can't be used as a key.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct LocalityKey {
+    pub region: String,
+    pub zone: String,
+    pub sub_zone: String,
+}
+
+impl From<&Locality> for LocalityKey {
+    fn from(locality: &Locality) -> Self {
+        Self {
+            region: locality.region.clone(),
+            zone: locality.zone.clone(),
+            sub_zone: locality.sub_zone.clone(),
+        }
+    }
+}
+
+impl From<WatchError> for Status {
+    fn from(err: WatchError) -> Self {
+        match err {
+            WatchError::UnsupportedType => Status::invalid_argument("type URL is not served"),
+        }
+    }
+}
+
+// Why a SotW or delta stream ended.
+#[derive(Debug)]
+pub enum CloseReason {
+    // The client closed its side of the stream.
+    ClientClosed,
+    // Receiving a request failed, e.g. because the connection was reset, or the request was
+    // malformed.
+    RecvFailed(Status),
+    // Sending a response failed because the client went away.
+    SendFailed,
+    // The server closed the stream with an error, e.g. because a callback rejected a request.
+    Rejected(Status),
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CloseReason::ClientClosed => write!(f, "client closed stream"),
+            CloseReason::RecvFailed(status) => write!(f, "receive failed: {}", status),
+            CloseReason::SendFailed => write!(f, "send failed: client went away"),
+            CloseReason::Rejected(status) => write!(f, "rejected: {}", status),
+        }
+    }
+}
+
 impl<C: Cache> Service<C> {
-    pub fn new(cache: Arc<C>) -> Self {
+    pub fn new(cache: Arc<C>, callbacks: Arc<dyn Callbacks>) -> Self {
         Self {
             cache,
+            callbacks,
+            ack_tracker: Arc::new(AckTracker::new()),
             next_stream_id: AtomicUsize::new(0),
         }
     }
 
+    // Lets the services a node connects to, and a ClientStatusService, share one tracker.
+    pub fn with_ack_tracker(mut self, ack_tracker: Arc<AckTracker>) -> Self {
+        self.ack_tracker = ack_tracker;
+        self
+    }
+
+    pub fn ack_tracker(&self) -> Arc<AckTracker> {
+        self.ack_tracker.clone()
+    }
+
     pub fn stream(
         &self,
         req: Request<Streaming<DiscoveryRequest>>,
@@ -40,16 +113,26 @@ impl<C: Cache> Service<C> {
         let (tx, rx) = mpsc::channel(1);
         let output = ReceiverStream::new(rx);
         let cache_clone = self.cache.clone();
+        let callbacks = self.callbacks.clone();
+        let ack_tracker = self.ack_tracker.clone();
         let stream_id = self.next_stream_id.fetch_add(1, Ordering::SeqCst);
 
+        let span = info_span!(
+            "handle_stream",
+            stream_id,
+            type_url = type_url::shorten(type_url),
+        );
         tokio::spawn(
-            async move { handle_stream(input, tx, type_url, cache_clone).await }.instrument(
-                info_span!(
-                    "handle_stream",
-                    stream_id,
-                    type_url = type_url::shorten(type_url),
-                ),
-            ),
+            handle_stream(
+                input,
+                tx,
+                type_url,
+                cache_clone,
+                stream_id,
+                callbacks,
+                ack_tracker,
+            )
+            .instrument(span),
         );
 
         Ok(Response::new(
@@ -66,16 +149,26 @@ impl<C: Cache> Service<C> {
         let (tx, rx) = mpsc::channel(1);
         let output = ReceiverStream::new(rx);
         let cache_clone = self.cache.clone();
+        let callbacks = self.callbacks.clone();
+        let ack_tracker = self.ack_tracker.clone();
         let stream_id = self.next_stream_id.fetch_add(1, Ordering::SeqCst);
 
+        let span = info_span!(
+            "handle_delta_stream",
+            stream_id,
+            type_url = type_url::shorten(type_url),
+        );
         tokio::spawn(
-            async move { handle_delta_stream(input, tx, type_url, cache_clone).await }.instrument(
-                info_span!(
-                    "handle_delta_stream",
-                    stream_id,
-                    type_url = type_url::shorten(type_url),
-                ),
-            ),
+            handle_delta_stream(
+                input,
+                tx,
+                type_url,
+                cache_clone,
+                stream_id,
+                callbacks,
+                ack_tracker,
+            )
+            .instrument(span),
         );
 
         Ok(Response::new(
@@ -88,8 +181,12 @@ impl<C: Cache> Service<C> {
         req: &DiscoveryRequest,
         type_url: &'static str,
     ) -> Result<Response<DiscoveryResponse>, Status> {
+        self.callbacks.on_fetch_request(req).await?;
         match self.cache.fetch(req, type_url).await {
-            Ok(resp) => Ok(Response::new(resp)),
+            Ok(resp) => {
+                self.callbacks.on_fetch_response(req, &resp).await;
+                Ok(Response::new(resp))
+            }
             Err(FetchError::NotFound) => Err(Status::not_found("Resource not found for node")),
             Err(FetchError::VersionUpToDate) => {
                 Err(Status::already_exists("Version already up to date"))
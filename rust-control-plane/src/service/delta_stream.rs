@@ -1,4 +1,10 @@
+#[cfg(test)]
+mod test;
+
 use crate::cache::{Cache, DeltaWatchResponse};
+use crate::service::ack_tracker::AckTracker;
+use crate::service::callbacks::Callbacks;
+use crate::service::common::CloseReason;
 use crate::service::delta_watches::DeltaWatches;
 use crate::service::stream_handle::DeltaStreamHandle;
 use crate::snapshot::type_url::{self, ANY_TYPE};
@@ -11,40 +17,61 @@ use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::mpsc;
 use tonic::{Status, Streaming};
-use tracing::{info_span, Instrument};
+use tracing::{info, info_span, warn, Instrument};
 
 pub async fn handle_delta_stream<C: Cache>(
-    mut requests: Streaming<DeltaDiscoveryRequest>,
+    requests: Streaming<DeltaDiscoveryRequest>,
     responses: mpsc::Sender<Result<DeltaDiscoveryResponse, Status>>,
     type_url: &'static str,
     cache: Arc<C>,
+    stream_id: usize,
+    callbacks: Arc<dyn Callbacks>,
+    ack_tracker: Arc<AckTracker>,
 ) {
-    let mut stream = DeltaStream::new(responses, type_url, cache);
-    loop {
-        tokio::select! {
-            maybe_req = requests.next() => {
-                let req = maybe_req.unwrap().unwrap();
-                let span = stream.build_client_request_span(&req);
-                stream.handle_client_request(req).instrument(span).await;
-            }
-            Some(rep) = stream.watches_rx.recv() => {
-                stream.handle_watch_response(rep)
-                    .instrument(info_span!("handle_watch_response")).await;
-            }
-        }
+    if let Err(status) = callbacks.on_delta_stream_open(stream_id, type_url).await {
+        // Nothing to clean up if the client has already gone away.
+        let _ = responses.send(Err(status)).await;
+        return;
     }
+    let mut stream = DeltaStream::new(
+        responses,
+        type_url,
+        cache,
+        stream_id,
+        callbacks,
+        ack_tracker,
+    );
+    let reason = stream.run(requests).await;
+    info!("delta stream closed: {}", reason);
+    stream.watches.cancel_all().await;
+    if stream.node.is_some() {
+        stream.ack_tracker.stream_closed(&stream.node).await;
+    }
+    stream
+        .callbacks
+        .on_delta_stream_closed(stream.id, stream.node.as_ref(), &reason)
+        .await;
+}
+
+struct LastResponse {
+    nonce: i64,
+    version: String,
 }
 
 struct DeltaStream<C: Cache> {
+    id: usize,
     responses: mpsc::Sender<Result<DeltaDiscoveryResponse, Status>>,
     type_url: &'static str,
     cache: Arc<C>,
     nonce: i64,
     node: Option<Node>,
     states: HashMap<String, DeltaStreamHandle>,
+    last_responses: HashMap<String, LastResponse>,
     watches_tx: mpsc::Sender<DeltaWatchResponse>,
     watches_rx: mpsc::Receiver<DeltaWatchResponse>,
     watches: DeltaWatches<C>,
+    callbacks: Arc<dyn Callbacks>,
+    ack_tracker: Arc<AckTracker>,
 }
 
 impl<C: Cache> DeltaStream<C> {
@@ -52,28 +79,74 @@ impl<C: Cache> DeltaStream<C> {
         responses: mpsc::Sender<Result<DeltaDiscoveryResponse, Status>>,
         type_url: &'static str,
         cache: Arc<C>,
+        id: usize,
+        callbacks: Arc<dyn Callbacks>,
+        ack_tracker: Arc<AckTracker>,
     ) -> Self {
         let (watches_tx, watches_rx) = mpsc::channel(16);
         let cache_clone = cache.clone();
         Self {
+            id,
             responses,
             type_url,
             cache,
             nonce: 0,
             node: None,
             states: HashMap::new(),
+            last_responses: HashMap::new(),
             watches_tx,
             watches_rx,
             watches: DeltaWatches::new(cache_clone),
+            callbacks,
+            ack_tracker,
         }
     }
 
-    async fn handle_client_request(&mut self, mut req: DeltaDiscoveryRequest) {
+    // Serves requests until the stream ends, returning why it ended.
+    async fn run<S>(&mut self, mut requests: S) -> CloseReason
+    where
+        S: futures::Stream<Item = Result<DeltaDiscoveryRequest, Status>> + Unpin,
+    {
+        loop {
+            let result = tokio::select! {
+                maybe_req = requests.next() => match maybe_req {
+                    Some(Ok(req)) => {
+                        let span = self.build_client_request_span(&req);
+                        self.handle_client_request(req).instrument(span).await
+                    }
+                    Some(Err(status)) => Err(CloseReason::RecvFailed(status)),
+                    None => Err(CloseReason::ClientClosed),
+                },
+                Some(rep) = self.watches_rx.recv() => {
+                    self.handle_watch_response(rep)
+                        .instrument(info_span!("handle_watch_response")).await
+                }
+            };
+            if let Err(reason) = result {
+                if let CloseReason::Rejected(status) = &reason {
+                    let _ = self.responses.send(Err(status.clone())).await;
+                }
+                return reason;
+            }
+        }
+    }
+
+    // Returns an error if the stream should be closed.
+    async fn handle_client_request(
+        &mut self,
+        mut req: DeltaDiscoveryRequest,
+    ) -> Result<(), CloseReason> {
         // Node might only be sent on the first request to save sending the same data
         // repeatedly, so let's cache it in memory for future requests on this stream.
         // NB: If client changes the node after the first request (that's a client bug), we've
         // chosen to forward that new one to avoid complexity in this algorithm.
         if req.node.is_some() {
+            if req.node != self.node {
+                if self.node.is_some() {
+                    self.ack_tracker.stream_closed(&self.node).await;
+                }
+                self.ack_tracker.stream_opened(&req.node).await;
+            }
             self.node = req.node.clone();
         } else {
             req.node = self.node.clone();
@@ -83,40 +156,130 @@ impl<C: Cache> DeltaStream<C> {
             // Type URL is required for ADS (ANY_TYPE) because we can't tell from just the
             // gRPC method which resource this request is for.
             let status = Status::invalid_argument("type URL is required for ADS");
-            self.responses.send(Err(status)).await.unwrap();
-            return;
+            return self
+                .responses
+                .send(Err(status))
+                .await
+                .map_err(|_| CloseReason::SendFailed);
         } else if req.type_url.is_empty() {
             // Type URL is otherwise optional, but let's set it for consistency.
             // NB: We don't currently validate the type_url, or check if it's for the right RPC.
             req.type_url = self.type_url.to_string();
         }
 
+        self.callbacks
+            .on_stream_delta_request(self.id, &req)
+            .await
+            .map_err(CloseReason::Rejected)?;
+        self.record_ack(&req).await;
+
+        let is_stale = self.is_stale(&req);
+        let is_new = !self.states.contains_key(&req.type_url);
         let state = self
             .states
             .entry(req.type_url.to_string())
             .or_insert_with(|| DeltaStreamHandle::new(&req));
-        self.watches.remove(&req.type_url);
-        if let Some(watch) = self.watches.remove(&req.type_url) {
-            self.cache.cancel_watch(&watch.id).await;
+        if is_new || changes_subscriptions(&req) {
+            if let Some(watch) = self.watches.remove(&req.type_url) {
+                self.cache.cancel_delta_watch(&watch.id).await;
+            }
+            state.apply_subscriptions(&req);
+        } else if is_stale {
+            // A newer response has been sent since, which the client has yet to ACK or NACK.
+            info!("ignoring stale nonce");
+            return Ok(());
+        } else if self.watches.contains(&req.type_url) {
+            // Subscriptions are unchanged, so the existing watch is still waiting on the right
+            // resources.
+            info!("subscriptions unchanged");
+            return Ok(());
         }
-        state.apply_subscriptions(&req);
         let watch_id = self
             .cache
             .create_delta_watch(&req, self.watches_tx.clone(), state)
-            .await;
+            .await
+            .map_err(|err| CloseReason::Rejected(err.into()))?;
         if let Some(id) = watch_id {
             self.watches.add(&req.type_url, id);
         }
+        Ok(())
     }
 
-    async fn handle_watch_response(&mut self, mut rep: DeltaWatchResponse) {
+    // Returns an error if the stream should be closed.
+    async fn handle_watch_response(
+        &mut self,
+        mut rep: DeltaWatchResponse,
+    ) -> Result<(), CloseReason> {
         self.nonce += 1;
         rep.0.nonce = self.nonce.to_string();
+        // The cache removes a delta watch once it responds, so there's nothing left to cancel.
+        // The response may have been queued before the stream set another watch for the type,
+        // which is still waiting, so only the watch which fired is forgotten.
+        if let Some(watch_id) = &rep.2 {
+            self.watches.remove_if(&rep.0.type_url, watch_id);
+        }
         self.states
             .get_mut(&rep.0.type_url)
             .unwrap()
             .set_resource_versions(rep.1);
-        self.responses.send(Ok(rep.0)).await.unwrap();
+        self.callbacks
+            .on_stream_delta_response(self.id, &rep.0)
+            .await;
+        self.ack_tracker.sent_delta(&self.node, &rep.0).await;
+        self.last_responses.insert(
+            rep.0.type_url.clone(),
+            LastResponse {
+                nonce: self.nonce,
+                version: rep.0.system_version_info.clone(),
+            },
+        );
+        self.responses
+            .send(Ok(rep.0))
+            .await
+            .map_err(|_| CloseReason::SendFailed)
+    }
+
+    // A request is stale if it responds to a response other than the last one sent for its type.
+    fn is_stale(&self, req: &DeltaDiscoveryRequest) -> bool {
+        if req.response_nonce.is_empty() {
+            return false;
+        }
+        !matches!(
+            self.last_responses.get(&req.type_url),
+            Some(last_response) if req.response_nonce == last_response.nonce.to_string()
+        )
+    }
+
+    // If the request responds to the last response sent for its type, records whether the
+    // client accepted or rejected that response. Requests for stale nonces are ignored.
+    async fn record_ack(&self, req: &DeltaDiscoveryRequest) {
+        let last_response = match self.last_responses.get(&req.type_url) {
+            Some(last_response) if req.response_nonce == last_response.nonce.to_string() => {
+                last_response
+            }
+            _ => return,
+        };
+        match &req.error_detail {
+            Some(detail) => {
+                warn!(
+                    "nack version={} type_url={} error={}",
+                    last_response.version, req.type_url, detail.message
+                );
+                self.ack_tracker
+                    .nacked(
+                        &req.node,
+                        &req.type_url,
+                        &last_response.version,
+                        detail.clone(),
+                    )
+                    .await;
+            }
+            None => {
+                self.ack_tracker
+                    .acked(&req.node, &req.type_url, &last_response.version)
+                    .await;
+            }
+        }
     }
 
     fn build_client_request_span(&self, req: &DeltaDiscoveryRequest) -> tracing::Span {
@@ -127,3 +290,9 @@ impl<C: Cache> DeltaStream<C> {
         )
     }
 }
+
+// Unlike SotW, delta requests only carry changes to subscriptions, so a request without any is
+// a plain ACK or NACK.
+fn changes_subscriptions(req: &DeltaDiscoveryRequest) -> bool {
+    !req.resource_names_subscribe.is_empty() || !req.resource_names_unsubscribe.is_empty()
+}
@@ -0,0 +1,438 @@
+use crate::cache::{Cache, DeltaWatchResponder, FetchError, WatchError, WatchId, WatchResponder};
+use crate::service::ack_tracker::AckTracker;
+use crate::service::callbacks::NoopCallbacks;
+use crate::service::common::CloseReason;
+use crate::service::delta_stream::DeltaStream;
+use crate::service::stream_handle::{DeltaStreamHandle, StreamHandle};
+use crate::snapshot::type_url::{ANY_TYPE, CLUSTER, ENDPOINT};
+use async_trait::async_trait;
+use data_plane_api::envoy::config::core::v3::Node;
+use data_plane_api::envoy::service::discovery::v3::{
+    DeltaDiscoveryRequest, DeltaDiscoveryResponse, DiscoveryRequest, DiscoveryResponse,
+};
+use data_plane_api::google::rpc::Status as RpcStatus;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::sync::Mutex;
+use tonic::Code;
+use tonic::Status;
+
+struct MockCache {
+    pub inner: Mutex<InnerMockCache>,
+}
+
+type CreateDeltaWatchCall = (DeltaDiscoveryRequest, DeltaWatchResponder);
+
+struct InnerMockCache {
+    pub create_delta_watch_calls: Vec<CreateDeltaWatchCall>,
+    pub create_delta_watch_rep: Option<WatchId>,
+    pub cancel_delta_watch_calls: Vec<WatchId>,
+}
+
+#[async_trait]
+impl Cache for MockCache {
+    async fn create_watch(
+        &self,
+        _req: &DiscoveryRequest,
+        _tx: WatchResponder,
+        _handle: &StreamHandle,
+    ) -> Result<Option<WatchId>, WatchError> {
+        unimplemented!()
+    }
+
+    async fn create_delta_watch(
+        &self,
+        req: &DeltaDiscoveryRequest,
+        tx: DeltaWatchResponder,
+        _state: &DeltaStreamHandle,
+    ) -> Result<Option<WatchId>, WatchError> {
+        let mut inner = self.inner.lock().await;
+        inner.create_delta_watch_calls.push((req.clone(), tx));
+        Ok(inner.create_delta_watch_rep.clone())
+    }
+
+    async fn cancel_watch(&self, _watch_id: &WatchId) {
+        unimplemented!()
+    }
+
+    async fn cancel_delta_watch(&self, watch_id: &WatchId) {
+        let mut inner = self.inner.lock().await;
+        inner.cancel_delta_watch_calls.push(watch_id.clone());
+    }
+
+    async fn fetch<'a>(
+        &'a self,
+        _req: &'a DiscoveryRequest,
+        _type_url: &'static str,
+    ) -> Result<DiscoveryResponse, FetchError> {
+        unimplemented!()
+    }
+}
+
+impl MockCache {
+    fn new() -> Self {
+        Self {
+            inner: Mutex::new(InnerMockCache::new()),
+        }
+    }
+}
+
+impl InnerMockCache {
+    fn new() -> Self {
+        Self {
+            create_delta_watch_calls: Vec::new(),
+            create_delta_watch_rep: None,
+            cancel_delta_watch_calls: Vec::new(),
+        }
+    }
+}
+
+struct TestHandle {
+    pub rx: mpsc::Receiver<Result<DeltaDiscoveryResponse, Status>>,
+    pub stream: DeltaStream<MockCache>,
+    pub cache: Arc<MockCache>,
+    type_url: &'static str,
+}
+
+impl TestHandle {
+    fn new(type_url: &'static str) -> Self {
+        let (tx, rx) = mpsc::channel(1);
+        let cache = Arc::new(MockCache::new());
+        let stream = new_stream(tx, type_url, cache.clone());
+        Self {
+            rx,
+            stream,
+            cache,
+            type_url,
+        }
+    }
+
+    fn reconnect(&mut self) {
+        let (tx, rx) = mpsc::channel(1);
+        let mut stream = new_stream(tx, self.type_url, self.cache.clone());
+        self.rx = rx;
+        std::mem::swap(&mut self.stream, &mut stream);
+        drop(stream);
+    }
+
+    async fn create_delta_watch_calls(&self) -> Vec<CreateDeltaWatchCall> {
+        self.cache
+            .inner
+            .lock()
+            .await
+            .create_delta_watch_calls
+            .clone()
+    }
+
+    async fn set_create_delta_watch_rep(&self, rep: Option<WatchId>) {
+        self.cache.inner.lock().await.create_delta_watch_rep = rep;
+    }
+
+    async fn cancel_delta_watch_calls(&self) -> Vec<WatchId> {
+        self.cache
+            .inner
+            .lock()
+            .await
+            .cancel_delta_watch_calls
+            .clone()
+    }
+}
+
+fn new_stream(
+    tx: mpsc::Sender<Result<DeltaDiscoveryResponse, Status>>,
+    type_url: &'static str,
+    cache: Arc<MockCache>,
+) -> DeltaStream<MockCache> {
+    DeltaStream::new(
+        tx,
+        type_url,
+        cache,
+        0,
+        Arc::new(NoopCallbacks),
+        Arc::new(AckTracker::new()),
+    )
+}
+
+fn request(type_url: &str) -> DeltaDiscoveryRequest {
+    DeltaDiscoveryRequest {
+        node: Some(Node {
+            id: "foobar".to_string(),
+            ..Node::default()
+        }),
+        type_url: type_url.to_string(),
+        ..DeltaDiscoveryRequest::default()
+    }
+}
+
+fn response(type_url: &str, version: &str) -> DeltaDiscoveryResponse {
+    DeltaDiscoveryResponse {
+        type_url: type_url.to_string(),
+        system_version_info: version.to_string(),
+        ..DeltaDiscoveryResponse::default()
+    }
+}
+
+fn watch_id(index: usize) -> WatchId {
+    WatchId {
+        node_id: "foobar".to_string(),
+        index,
+    }
+}
+
+#[tokio::test]
+async fn test_delta_stream_stores_node_for_future_requests() {
+    let mut h = TestHandle::new(CLUSTER);
+    let req_with_node = request(CLUSTER);
+    let req_without_node = DeltaDiscoveryRequest {
+        node: None,
+        ..request(CLUSTER)
+    };
+    h.stream
+        .handle_client_request(req_with_node.clone())
+        .await
+        .unwrap();
+    h.stream
+        .handle_client_request(req_without_node)
+        .await
+        .unwrap();
+    let calls = h.create_delta_watch_calls().await;
+    assert_eq!(calls.len(), 2);
+    for (req, _) in calls {
+        assert_eq!(req, req_with_node);
+    }
+}
+
+#[tokio::test]
+async fn test_delta_stream_forwards_type_url_if_not_present() {
+    let mut h = TestHandle::new(CLUSTER);
+    h.stream.handle_client_request(request("")).await.unwrap();
+    let calls = h.create_delta_watch_calls().await;
+    assert_eq!(calls.len(), 1);
+    assert_eq!(calls[0].0.type_url, CLUSTER);
+}
+
+#[tokio::test]
+async fn test_delta_stream_aborts_if_type_url_not_present_for_ads() {
+    let mut h = TestHandle::new(ANY_TYPE);
+    h.stream.handle_client_request(request("")).await.unwrap();
+    assert_eq!(h.create_delta_watch_calls().await.len(), 0);
+    let status = h.rx.try_recv().unwrap().unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+    assert_eq!(status.message(), "type URL is required for ADS");
+}
+
+#[tokio::test]
+async fn test_delta_stream_subscription_change_cancels_watch() {
+    let mut h = TestHandle::new(ANY_TYPE);
+    h.set_create_delta_watch_rep(Some(watch_id(0))).await;
+    h.stream
+        .handle_client_request(request(CLUSTER))
+        .await
+        .unwrap();
+    h.set_create_delta_watch_rep(Some(watch_id(1))).await;
+    h.stream
+        .handle_client_request(DeltaDiscoveryRequest {
+            resource_names_subscribe: vec!["a".to_string()],
+            ..request(CLUSTER)
+        })
+        .await
+        .unwrap();
+    assert_eq!(h.create_delta_watch_calls().await.len(), 2);
+    assert_eq!(h.cancel_delta_watch_calls().await, vec![watch_id(0)]);
+}
+
+#[tokio::test]
+async fn test_delta_stream_ack_without_subscription_change_keeps_watch() {
+    let mut h = TestHandle::new(CLUSTER);
+    h.set_create_delta_watch_rep(Some(watch_id(0))).await;
+    h.stream
+        .handle_client_request(request(CLUSTER))
+        .await
+        .unwrap();
+    h.stream
+        .handle_client_request(request(CLUSTER))
+        .await
+        .unwrap();
+    assert_eq!(h.create_delta_watch_calls().await.len(), 1);
+    assert!(h.cancel_delta_watch_calls().await.is_empty());
+}
+
+#[tokio::test]
+async fn test_delta_stream_ignores_stale_nonce() {
+    let mut h = TestHandle::new(CLUSTER);
+    h.stream
+        .handle_client_request(request(CLUSTER))
+        .await
+        .unwrap();
+    for version in ["1", "2"] {
+        h.stream
+            .handle_watch_response((response(CLUSTER, version), HashMap::new(), None))
+            .await
+            .unwrap();
+        h.rx.recv().await.unwrap().unwrap();
+    }
+    // Responds to the first response, which has been superseded by the second.
+    h.stream
+        .handle_client_request(DeltaDiscoveryRequest {
+            response_nonce: "1".to_string(),
+            ..request(CLUSTER)
+        })
+        .await
+        .unwrap();
+    assert_eq!(h.create_delta_watch_calls().await.len(), 1);
+    let status = h
+        .stream
+        .ack_tracker
+        .status("foobar", CLUSTER)
+        .await
+        .unwrap();
+    assert_eq!(status.last_sent_version, Some("2".to_string()));
+    assert_eq!(status.last_acked_version, None);
+}
+
+#[tokio::test]
+async fn test_delta_stream_records_acks_and_nacks() {
+    let mut h = TestHandle::new(CLUSTER);
+    h.stream
+        .handle_client_request(request(CLUSTER))
+        .await
+        .unwrap();
+    h.stream
+        .handle_watch_response((response(CLUSTER, "1"), HashMap::new(), None))
+        .await
+        .unwrap();
+    h.rx.recv().await.unwrap().unwrap();
+    h.stream
+        .handle_client_request(DeltaDiscoveryRequest {
+            response_nonce: "1".to_string(),
+            ..request(CLUSTER)
+        })
+        .await
+        .unwrap();
+    h.stream
+        .handle_watch_response((response(CLUSTER, "2"), HashMap::new(), None))
+        .await
+        .unwrap();
+    h.rx.recv().await.unwrap().unwrap();
+    let detail = RpcStatus {
+        message: "bad cluster".to_string(),
+        ..RpcStatus::default()
+    };
+    h.stream
+        .handle_client_request(DeltaDiscoveryRequest {
+            response_nonce: "2".to_string(),
+            error_detail: Some(detail.clone()),
+            ..request(CLUSTER)
+        })
+        .await
+        .unwrap();
+    let status = h
+        .stream
+        .ack_tracker
+        .status("foobar", CLUSTER)
+        .await
+        .unwrap();
+    assert_eq!(status.last_acked_version, Some("1".to_string()));
+    assert_eq!(status.last_nacked_version, Some("2".to_string()));
+    assert_eq!(status.nack_detail, Some(detail));
+    // Each ACK or NACK waits for further changes, since the previous watch fired.
+    assert_eq!(h.create_delta_watch_calls().await.len(), 3);
+}
+
+#[tokio::test]
+async fn test_delta_stream_does_not_cancel_fired_watch() {
+    let mut h = TestHandle::new(CLUSTER);
+    h.set_create_delta_watch_rep(Some(watch_id(0))).await;
+    h.stream
+        .handle_client_request(request(CLUSTER))
+        .await
+        .unwrap();
+    let rep = DeltaDiscoveryResponse {
+        type_url: CLUSTER.to_string(),
+        ..DeltaDiscoveryResponse::default()
+    };
+    h.stream
+        .handle_watch_response((rep, HashMap::new(), Some(watch_id(0))))
+        .await
+        .unwrap();
+    h.stream
+        .handle_client_request(request(CLUSTER))
+        .await
+        .unwrap();
+    assert!(h.cancel_delta_watch_calls().await.is_empty());
+}
+
+#[tokio::test]
+async fn test_delta_stream_cancels_watches_on_drop() {
+    let mut h = TestHandle::new(ANY_TYPE);
+    h.set_create_delta_watch_rep(Some(watch_id(0))).await;
+    h.stream
+        .handle_client_request(request(CLUSTER))
+        .await
+        .unwrap();
+    h.set_create_delta_watch_rep(Some(watch_id(1))).await;
+    h.stream
+        .handle_client_request(request(ENDPOINT))
+        .await
+        .unwrap();
+    h.reconnect();
+    // NB: I don't know how else we can wait for the task spawned by drop to complete.
+    tokio::time::sleep(tokio::time::Duration::from_millis(1)).await;
+    let mut cancel_calls = h.cancel_delta_watch_calls().await;
+    cancel_calls.sort();
+    assert_eq!(cancel_calls, vec![watch_id(0), watch_id(1)]);
+}
+
+#[tokio::test]
+async fn test_delta_stream_run_ends_on_eof() {
+    let mut h = TestHandle::new(CLUSTER);
+    h.set_create_delta_watch_rep(Some(watch_id(0))).await;
+    let reason = h
+        .stream
+        .run(tokio_stream::iter(vec![Ok(request(CLUSTER))]))
+        .await;
+    assert!(matches!(reason, CloseReason::ClientClosed));
+    h.stream.watches.cancel_all().await;
+    assert_eq!(h.cancel_delta_watch_calls().await, vec![watch_id(0)]);
+}
+
+#[tokio::test]
+async fn test_delta_stream_keeps_watch_set_after_fired_watch() {
+    let mut h = TestHandle::new(CLUSTER);
+    h.set_create_delta_watch_rep(Some(watch_id(0))).await;
+    h.stream
+        .handle_client_request(request(CLUSTER))
+        .await
+        .unwrap();
+    // The first watch fires, but before its response is handled, a subscription change replaces
+    // it with another.
+    h.set_create_delta_watch_rep(Some(watch_id(1))).await;
+    h.stream
+        .handle_client_request(DeltaDiscoveryRequest {
+            resource_names_subscribe: vec!["a".to_string()],
+            ..request(CLUSTER)
+        })
+        .await
+        .unwrap();
+    h.stream
+        .handle_watch_response((response(CLUSTER, "1"), HashMap::new(), Some(watch_id(0))))
+        .await
+        .unwrap();
+    h.rx.recv().await.unwrap().unwrap();
+
+    // The second watch is still waiting, so an ACK doesn't set another.
+    h.stream
+        .handle_client_request(DeltaDiscoveryRequest {
+            response_nonce: "1".to_string(),
+            ..request(CLUSTER)
+        })
+        .await
+        .unwrap();
+    assert_eq!(h.create_delta_watch_calls().await.len(), 2);
+    h.stream.watches.cancel_all().await;
+    assert_eq!(
+        h.cancel_delta_watch_calls().await,
+        vec![watch_id(0), watch_id(1)]
+    );
+}
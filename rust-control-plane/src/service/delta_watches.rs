@@ -25,14 +25,32 @@ impl<C: Cache> DeltaWatches<C> {
             .insert(type_url.to_string(), Watch { id: watch_id });
     }
 
+    pub fn contains(&self, type_url: &str) -> bool {
+        self.active.contains_key(type_url)
+    }
+
     pub fn remove(&mut self, type_url: &str) -> Option<Watch> {
         self.active.remove(type_url)
     }
+
+    // Forgets the type's watch if it's the one with the given ID, e.g. as it has fired. A watch
+    // set since then is kept.
+    pub fn remove_if(&mut self, type_url: &str, watch_id: &WatchId) -> Option<Watch> {
+        match self.active.get(type_url) {
+            Some(watch) if &watch.id == watch_id => self.active.remove(type_url),
+            _ => None,
+        }
+    }
+
+    // Cancels every active watch.
+    pub async fn cancel_all(&mut self) {
+        cancel_all(std::mem::take(&mut self.active), self.cache.clone()).await;
+    }
 }
 
 pub async fn cancel_all<C: Cache>(active: HashMap<String, Watch>, cache: Arc<C>) {
     for (_, watch) in active.iter() {
-        cache.cancel_watch(&watch.id).await;
+        cache.cancel_delta_watch(&watch.id).await;
     }
 }
 
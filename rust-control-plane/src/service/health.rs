@@ -0,0 +1,485 @@
+#[cfg(test)]
+mod test;
+
+use crate::cache::node_hash::{IdHash, NodeHash};
+use crate::cache::snapshot::SnapshotCache;
+use crate::service::common::{LocalityKey, StreamResponse, UPDATES_CAPACITY};
+use crate::snapshot::{type_url, ConsistencyError, Resource, VersionedResource};
+use data_plane_api::envoy::config::core::v3::{address, socket_address, HealthStatus, Node};
+use data_plane_api::envoy::config::endpoint::v3::{lb_endpoint, ClusterLoadAssignment, Endpoint};
+use data_plane_api::envoy::service::health::v3::health_check_request_or_endpoint_health_response::RequestType;
+use data_plane_api::envoy::service::health::v3::health_discovery_service_server::HealthDiscoveryService;
+use data_plane_api::envoy::service::health::v3::{
+    Capability, EndpointHealthResponse, HealthCheckRequestOrEndpointHealthResponse,
+    HealthCheckSpecifier,
+};
+use futures::StreamExt;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+use tokio::sync::{broadcast, mpsc, Mutex};
+use tokio::task::JoinHandle;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status, Streaming};
+use tracing::{info, info_span, warn, Instrument};
+
+// Decides which endpoints a node health checks on behalf of the control plane, and how.
+#[tonic::async_trait]
+pub trait HealthCheckAssigner: fmt::Debug + Send + Sync + 'static {
+    // Returns the health checks for a node, or None if it shouldn't health check anything.
+    async fn assign(
+        &self,
+        node: &Node,
+        capability: Option<&Capability>,
+    ) -> Option<HealthCheckSpecifier>;
+}
+
+// Assigns the same health checks to every node.
+#[derive(Debug, Clone)]
+pub struct StaticAssigner {
+    specifier: HealthCheckSpecifier,
+}
+
+impl StaticAssigner {
+    pub fn new(specifier: HealthCheckSpecifier) -> Self {
+        Self { specifier }
+    }
+}
+
+#[tonic::async_trait]
+impl HealthCheckAssigner for StaticAssigner {
+    async fn assign(
+        &self,
+        _node: &Node,
+        _capability: Option<&Capability>,
+    ) -> Option<HealthCheckSpecifier> {
+        Some(self.specifier.clone())
+    }
+}
+
+// Serves HDS, handing out health checks to nodes as decided by a HealthCheckAssigner, and
+// recording the health they report into an EndpointHealthTable.
+#[derive(Debug)]
+pub struct HealthService {
+    assigner: Arc<dyn HealthCheckAssigner>,
+    table: Arc<EndpointHealthTable>,
+}
+
+impl HealthService {
+    pub fn new<A: HealthCheckAssigner>(assigner: A) -> Self {
+        Self {
+            assigner: Arc::new(assigner),
+            table: Arc::new(EndpointHealthTable::new()),
+        }
+    }
+
+    pub fn with_table(mut self, table: Arc<EndpointHealthTable>) -> Self {
+        self.table = table;
+        self
+    }
+
+    pub fn table(&self) -> Arc<EndpointHealthTable> {
+        self.table.clone()
+    }
+}
+
+#[tonic::async_trait]
+impl HealthDiscoveryService for HealthService {
+    type StreamHealthCheckStream = StreamResponse<HealthCheckSpecifier>;
+
+    async fn stream_health_check(
+        &self,
+        req: Request<Streaming<HealthCheckRequestOrEndpointHealthResponse>>,
+    ) -> Result<Response<Self::StreamHealthCheckStream>, Status> {
+        let (tx, rx) = mpsc::channel(1);
+        tokio::spawn(
+            handle_health_stream(
+                req.into_inner(),
+                tx,
+                self.assigner.clone(),
+                self.table.clone(),
+            )
+            .instrument(info_span!("handle_health_stream")),
+        );
+        Ok(Response::new(
+            Box::pin(ReceiverStream::new(rx)) as StreamResponse<HealthCheckSpecifier>
+        ))
+    }
+
+    async fn fetch_health_check(
+        &self,
+        req: Request<HealthCheckRequestOrEndpointHealthResponse>,
+    ) -> Result<Response<HealthCheckSpecifier>, Status> {
+        // Reports can't be attributed to a node outside of a stream, so only requests for
+        // health checks are accepted.
+        let req = match req.into_inner().request_type {
+            Some(RequestType::HealthCheckRequest(req)) => req,
+            _ => return Err(Status::invalid_argument("health check request is required")),
+        };
+        let node = req
+            .node
+            .ok_or_else(|| Status::invalid_argument("node is required"))?;
+        match self.assigner.assign(&node, req.capability.as_ref()).await {
+            Some(specifier) => Ok(Response::new(specifier)),
+            None => Err(Status::not_found("no health checks assigned")),
+        }
+    }
+}
+
+// Sends the node its health checks whenever it asks for them, then records every report until
+// the stream ends, when the node's reports are dropped.
+async fn handle_health_stream<S>(
+    mut requests: S,
+    responses: mpsc::Sender<Result<HealthCheckSpecifier, Status>>,
+    assigner: Arc<dyn HealthCheckAssigner>,
+    table: Arc<EndpointHealthTable>,
+) where
+    S: futures::Stream<Item = Result<HealthCheckRequestOrEndpointHealthResponse, Status>> + Unpin,
+{
+    // Reports are attributed to the node which asked for health checks.
+    let mut node_id: Option<String> = None;
+    while let Some(result) = requests.next().await {
+        let req = match result {
+            Ok(req) => req,
+            Err(status) => {
+                info!("health stream receive failed: {}", status);
+                break;
+            }
+        };
+        match req.request_type {
+            Some(RequestType::HealthCheckRequest(req)) => {
+                let node = match req.node {
+                    Some(node) => node,
+                    None => {
+                        let status = Status::invalid_argument("node is required");
+                        let _ = responses.send(Err(status)).await;
+                        break;
+                    }
+                };
+                let id = IdHash.hash(&Some(node.clone()));
+                if node_id.as_ref() != Some(&id) {
+                    if let Some(previous) = &node_id {
+                        table.stream_closed(previous).await;
+                    }
+                    table.stream_opened(&id).await;
+                    node_id = Some(id.clone());
+                }
+                match assigner.assign(&node, req.capability.as_ref()).await {
+                    Some(specifier) => {
+                        if responses.send(Ok(specifier)).await.is_err() {
+                            break;
+                        }
+                    }
+                    None => info!("no health checks assigned node_id={}", id),
+                }
+            }
+            Some(RequestType::EndpointHealthResponse(report)) => match &node_id {
+                Some(node_id) => table.record(node_id, &report).await,
+                None => {
+                    let status =
+                        Status::invalid_argument("health check request must be sent first");
+                    let _ = responses.send(Err(status)).await;
+                    break;
+                }
+            },
+            None => {}
+        }
+    }
+    info!("health stream closed");
+    if let Some(node_id) = &node_id {
+        table.stream_closed(node_id).await;
+    }
+}
+
+// The health of endpoints as last reported over HDS, keyed by cluster name then endpoint
+// address. When several nodes check the same endpoint, the latest report wins, and a node's
+// reports are dropped once its last stream closes. Subscribers can use it to e.g. feed the
+// health back into load assignments.
+#[derive(Debug)]
+pub struct EndpointHealthTable {
+    inner: Mutex<TableInner>,
+    updates: broadcast::Sender<HealthUpdate>,
+}
+
+#[derive(Debug, Default)]
+struct TableInner {
+    clusters: HashMap<String, HashMap<String, EndpointStatus>>,
+    // The number of open streams of each node.
+    streams: HashMap<String, usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EndpointStatus {
+    pub health_status: HealthStatus,
+    pub locality: LocalityKey,
+    // The node which last reported the endpoint's health.
+    pub node_id: String,
+}
+
+// Sent to subscribers whenever an endpoint's health status changes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HealthUpdate {
+    pub cluster_name: String,
+    pub address: String,
+    pub health_status: HealthStatus,
+    // None if the endpoint hadn't been reported before.
+    pub previous: Option<HealthStatus>,
+}
+
+// Identifies an endpoint in an EndpointHealthTable, as "address:port" for sockets, or the path
+// for pipes.
+pub fn endpoint_address(endpoint: &Endpoint) -> Option<String> {
+    match endpoint.address.as_ref()?.address.as_ref()? {
+        address::Address::SocketAddress(socket) => match &socket.port_specifier {
+            Some(socket_address::PortSpecifier::PortValue(port)) => {
+                Some(format!("{}:{}", socket.address, port))
+            }
+            Some(socket_address::PortSpecifier::NamedPort(port)) => {
+                Some(format!("{}:{}", socket.address, port))
+            }
+            None => Some(socket.address.clone()),
+        },
+        address::Address::Pipe(pipe) => Some(pipe.path.clone()),
+        address::Address::EnvoyInternalAddress(_) => None,
+    }
+}
+
+impl Default for EndpointHealthTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EndpointHealthTable {
+    pub fn new() -> Self {
+        let (updates, _) = broadcast::channel(UPDATES_CAPACITY);
+        Self {
+            inner: Mutex::new(TableInner::default()),
+            updates,
+        }
+    }
+
+    pub async fn health_status(&self, cluster_name: &str, address: &str) -> Option<HealthStatus> {
+        let inner = self.inner.lock().await;
+        inner
+            .clusters
+            .get(cluster_name)?
+            .get(address)
+            .map(|status| status.health_status)
+    }
+
+    // Returns the status of every endpoint reported for a cluster, keyed by address.
+    pub async fn cluster(&self, cluster_name: &str) -> Option<HashMap<String, EndpointStatus>> {
+        let inner = self.inner.lock().await;
+        inner.clusters.get(cluster_name).cloned()
+    }
+
+    pub async fn clusters(&self) -> HashMap<String, HashMap<String, EndpointStatus>> {
+        self.inner.lock().await.clusters.clone()
+    }
+
+    // Receives an update whenever an endpoint's health status changes.
+    pub fn subscribe(&self) -> broadcast::Receiver<HealthUpdate> {
+        self.updates.subscribe()
+    }
+
+    // Sets the health status of an assignment's endpoints to their reported health, returning
+    // whether any changed. Endpoints are matched by cluster name and address, and those which
+    // haven't been reported are left as they are.
+    pub async fn apply(&self, assignment: &mut ClusterLoadAssignment) -> bool {
+        let inner = self.inner.lock().await;
+        apply_health(&inner.clusters, assignment)
+    }
+
+    pub(crate) async fn stream_opened(&self, node_id: &str) {
+        let mut inner = self.inner.lock().await;
+        *inner.streams.entry(node_id.to_string()).or_default() += 1;
+    }
+
+    // Drops the endpoints the node last reported once none of its streams are open, as it's no
+    // longer checking them. This isn't published, as there's no new status to report, so load
+    // assignments the health was applied to are left as they are.
+    pub(crate) async fn stream_closed(&self, node_id: &str) {
+        let mut inner = self.inner.lock().await;
+        match inner.streams.get_mut(node_id) {
+            Some(streams) if *streams > 1 => {
+                *streams -= 1;
+                return;
+            }
+            Some(_) => {
+                inner.streams.remove(node_id);
+            }
+            None => return,
+        }
+        inner.clusters.retain(|_, endpoints| {
+            endpoints.retain(|_, status| status.node_id != node_id);
+            !endpoints.is_empty()
+        });
+    }
+
+    pub(crate) async fn record(&self, node_id: &str, report: &EndpointHealthResponse) {
+        let mut inner = self.inner.lock().await;
+        let mut updates = Vec::new();
+        for cluster in &report.cluster_endpoints_health {
+            let endpoints = inner
+                .clusters
+                .entry(cluster.cluster_name.clone())
+                .or_default();
+            for locality_health in &cluster.locality_endpoints_health {
+                let locality = locality_health
+                    .locality
+                    .as_ref()
+                    .map(LocalityKey::from)
+                    .unwrap_or_default();
+                for health in &locality_health.endpoints_health {
+                    let address = match health.endpoint.as_ref().and_then(endpoint_address) {
+                        Some(address) => address,
+                        None => continue,
+                    };
+                    let health_status = HealthStatus::from_i32(health.health_status)
+                        .unwrap_or(HealthStatus::Unknown);
+                    let status = EndpointStatus {
+                        health_status,
+                        locality: locality.clone(),
+                        node_id: node_id.to_string(),
+                    };
+                    let previous = endpoints
+                        .insert(address.clone(), status)
+                        .map(|status| status.health_status);
+                    if previous != Some(health_status) {
+                        updates.push(HealthUpdate {
+                            cluster_name: cluster.cluster_name.clone(),
+                            address,
+                            health_status,
+                            previous,
+                        });
+                    }
+                }
+            }
+        }
+        drop(inner);
+        for update in updates {
+            let _ = self.updates.send(update);
+        }
+    }
+}
+
+// Sets the health status of an assignment's endpoints to those in the given table, returning
+// whether any changed.
+fn apply_health(
+    clusters: &HashMap<String, HashMap<String, EndpointStatus>>,
+    assignment: &mut ClusterLoadAssignment,
+) -> bool {
+    let endpoints = match clusters.get(&assignment.cluster_name) {
+        Some(endpoints) => endpoints,
+        None => return false,
+    };
+    let mut changed = false;
+    for locality in &mut assignment.endpoints {
+        for lb_endpoint in &mut locality.lb_endpoints {
+            let address = match &lb_endpoint.host_identifier {
+                Some(lb_endpoint::HostIdentifier::Endpoint(endpoint)) => endpoint_address(endpoint),
+                _ => None,
+            };
+            let status = match address.and_then(|address| endpoints.get(&address)) {
+                Some(status) => status,
+                None => continue,
+            };
+            if lb_endpoint.health_status != status.health_status as i32 {
+                lb_endpoint.health_status = status.health_status as i32;
+                changed = true;
+            }
+        }
+    }
+    changed
+}
+
+// Feeds the health reported over HDS back into the load assignments of a SnapshotCache, so
+// that nodes route around unhealthy endpoints they don't health check themselves.
+// NB: Setting a node's snapshot replaces the assignments the adapter upserted, so the health
+// must be applied again afterwards, by calling apply. Until then, the node is sent the
+// assignments as set.
+#[derive(Debug)]
+pub struct SnapshotHealthAdapter {
+    table: Arc<EndpointHealthTable>,
+    cache: Arc<SnapshotCache>,
+}
+
+impl SnapshotHealthAdapter {
+    pub fn new(table: Arc<EndpointHealthTable>, cache: Arc<SnapshotCache>) -> Self {
+        Self { table, cache }
+    }
+
+    // Applies the reported health to the load assignments in a node's snapshot, upserting those
+    // which changed. Returns the new endpoint version, or None if nothing changed.
+    pub async fn apply(&self, node: &str) -> Result<Option<String>, ConsistencyError> {
+        let clusters = self.table.clusters().await;
+        self.apply_with(node, &clusters).await
+    }
+
+    // The assignments are updated in place under the cache's lock, so that a snapshot set
+    // meanwhile isn't overwritten with the assignments it replaced.
+    async fn apply_with(
+        &self,
+        node: &str,
+        clusters: &HashMap<String, HashMap<String, EndpointStatus>>,
+    ) -> Result<Option<String>, ConsistencyError> {
+        self.cache
+            .update_resources(node, type_url::ENDPOINT, |resources| {
+                let mut changed = false;
+                for versioned in resources.items.values_mut() {
+                    if let Resource::Endpoint(assignment) = &versioned.resource {
+                        let mut assignment = assignment.clone();
+                        if apply_health(clusters, &mut assignment) {
+                            *versioned = VersionedResource::new(Resource::Endpoint(assignment));
+                            changed = true;
+                        }
+                    }
+                }
+                changed
+            })
+            .await
+    }
+
+    // Applies the reported health to every node's snapshot, returning how many were updated.
+    pub async fn apply_all(&self) -> usize {
+        let clusters = self.table.clusters().await;
+        let mut updated = 0;
+        for node in self.cache.snapshot_nodes().await {
+            match self.apply_with(&node, &clusters).await {
+                Ok(Some(_)) => updated += 1,
+                Ok(None) => {}
+                Err(err) => warn!("failed to apply endpoint health node={}: {}", node, err),
+            }
+        }
+        updated
+    }
+
+    // Spawns a task which applies the reported health to every node's snapshot whenever it
+    // changes, but not when a snapshot is set. The task exits once the cache is dropped.
+    pub fn spawn(self) -> JoinHandle<()> {
+        let table = self.table;
+        let cache = Arc::downgrade(&self.cache);
+        let mut updates = table.subscribe();
+        tokio::spawn(async move {
+            loop {
+                match updates.recv().await {
+                    Ok(_) | Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => return,
+                }
+                // Handle a burst of updates, such as a whole report, at once.
+                while matches!(updates.try_recv(), Ok(_) | Err(TryRecvError::Lagged(_))) {}
+                let cache = match cache.upgrade() {
+                    Some(cache) => cache,
+                    None => return,
+                };
+                let adapter = Self::new(table.clone(), cache);
+                let updated = adapter.apply_all().await;
+                if updated > 0 {
+                    info!("applied endpoint health count={}", updated);
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,359 @@
+use crate::cache::snapshot::SnapshotCache;
+use crate::service::health::{
+    endpoint_address, handle_health_stream, EndpointHealthTable, HealthCheckAssigner,
+    HealthService, HealthUpdate, SnapshotHealthAdapter, StaticAssigner,
+};
+use crate::service::test_util::run_stream;
+use crate::snapshot::{type_url, Resource, Resources, Snapshot};
+use data_plane_api::envoy::config::core::v3::{
+    address, socket_address, Address, HealthStatus, Locality, Node, SocketAddress,
+};
+use data_plane_api::envoy::config::endpoint::v3::{
+    lb_endpoint, ClusterLoadAssignment, Endpoint, LbEndpoint, LocalityLbEndpoints,
+};
+use data_plane_api::envoy::service::health::v3::health_check_request_or_endpoint_health_response::RequestType;
+use data_plane_api::envoy::service::health::v3::health_discovery_service_server::HealthDiscoveryService;
+use data_plane_api::envoy::service::health::v3::{
+    Capability, ClusterEndpointsHealth, ClusterHealthCheck, EndpointHealth, EndpointHealthResponse,
+    HealthCheckRequest, HealthCheckRequestOrEndpointHealthResponse, HealthCheckSpecifier,
+    LocalityEndpointsHealth,
+};
+use std::sync::Arc;
+use tonic::{Code, Request, Status};
+
+// Assigns health checks for a cluster named after the node.
+#[derive(Debug)]
+struct PerNodeAssigner;
+
+#[tonic::async_trait]
+impl HealthCheckAssigner for PerNodeAssigner {
+    async fn assign(
+        &self,
+        node: &Node,
+        _capability: Option<&Capability>,
+    ) -> Option<HealthCheckSpecifier> {
+        if node.id == "unassigned" {
+            return None;
+        }
+        Some(specifier(&node.id))
+    }
+}
+
+fn specifier(cluster_name: &str) -> HealthCheckSpecifier {
+    HealthCheckSpecifier {
+        cluster_health_checks: vec![ClusterHealthCheck {
+            cluster_name: cluster_name.to_string(),
+            ..ClusterHealthCheck::default()
+        }],
+        ..HealthCheckSpecifier::default()
+    }
+}
+
+fn health_check_request(node: Option<&str>) -> HealthCheckRequestOrEndpointHealthResponse {
+    HealthCheckRequestOrEndpointHealthResponse {
+        request_type: Some(RequestType::HealthCheckRequest(HealthCheckRequest {
+            node: node.map(|id| Node {
+                id: id.to_string(),
+                ..Node::default()
+            }),
+            capability: None,
+        })),
+    }
+}
+
+fn endpoint(ip: &str, port: u32) -> Endpoint {
+    Endpoint {
+        address: Some(Address {
+            address: Some(address::Address::SocketAddress(SocketAddress {
+                address: ip.to_string(),
+                port_specifier: Some(socket_address::PortSpecifier::PortValue(port)),
+                ..SocketAddress::default()
+            })),
+        }),
+        ..Endpoint::default()
+    }
+}
+
+fn report(
+    cluster_name: &str,
+    endpoints: Vec<(&str, HealthStatus)>,
+) -> HealthCheckRequestOrEndpointHealthResponse {
+    let endpoints_health = endpoints
+        .into_iter()
+        .map(|(ip, status)| EndpointHealth {
+            endpoint: Some(endpoint(ip, 80)),
+            health_status: status as i32,
+        })
+        .collect();
+    HealthCheckRequestOrEndpointHealthResponse {
+        request_type: Some(RequestType::EndpointHealthResponse(
+            EndpointHealthResponse {
+                cluster_endpoints_health: vec![ClusterEndpointsHealth {
+                    cluster_name: cluster_name.to_string(),
+                    locality_endpoints_health: vec![LocalityEndpointsHealth {
+                        locality: Some(Locality {
+                            zone: "east".to_string(),
+                            ..Locality::default()
+                        }),
+                        endpoints_health,
+                    }],
+                }],
+                ..EndpointHealthResponse::default()
+            },
+        )),
+    }
+}
+
+fn assignment(cluster_name: &str, ips: &[&str]) -> ClusterLoadAssignment {
+    ClusterLoadAssignment {
+        cluster_name: cluster_name.to_string(),
+        endpoints: vec![LocalityLbEndpoints {
+            lb_endpoints: ips
+                .iter()
+                .map(|ip| LbEndpoint {
+                    host_identifier: Some(lb_endpoint::HostIdentifier::Endpoint(endpoint(ip, 80))),
+                    ..LbEndpoint::default()
+                })
+                .collect(),
+            ..LocalityLbEndpoints::default()
+        }],
+        ..ClusterLoadAssignment::default()
+    }
+}
+
+fn health_statuses(assignment: &ClusterLoadAssignment) -> Vec<i32> {
+    assignment.endpoints[0]
+        .lb_endpoints
+        .iter()
+        .map(|lb_endpoint| lb_endpoint.health_status)
+        .collect()
+}
+
+async fn run(
+    requests: Vec<HealthCheckRequestOrEndpointHealthResponse>,
+    table: Arc<EndpointHealthTable>,
+) -> Vec<Result<HealthCheckSpecifier, Status>> {
+    run_stream(requests, |requests, tx| {
+        handle_health_stream(requests, tx, Arc::new(PerNodeAssigner), table)
+    })
+    .await
+}
+
+#[tokio::test]
+async fn test_health_stream_assigns_and_records_reports() {
+    let table = Arc::new(EndpointHealthTable::new());
+    // Another stream of the node keeps its reports once this one ends.
+    table.stream_opened("a").await;
+    let requests = vec![
+        health_check_request(Some("a")),
+        report("a", vec![("10.0.0.1", HealthStatus::Healthy)]),
+        report("a", vec![("10.0.0.1", HealthStatus::Unhealthy)]),
+    ];
+    let responses = run(requests, table.clone()).await;
+    assert_eq!(responses.len(), 1);
+    assert_eq!(responses[0].as_ref().unwrap(), &specifier("a"));
+
+    // The latest report wins.
+    assert_eq!(
+        table.health_status("a", "10.0.0.1:80").await,
+        Some(HealthStatus::Unhealthy)
+    );
+    let status = &table.cluster("a").await.unwrap()["10.0.0.1:80"];
+    assert_eq!(status.node_id, "a");
+    assert_eq!(status.locality.zone, "east");
+}
+
+#[tokio::test]
+async fn test_health_stream_rejects_reports_before_request() {
+    let table = Arc::new(EndpointHealthTable::new());
+    let responses = run(
+        vec![report("a", vec![("10.0.0.1", HealthStatus::Healthy)])],
+        table.clone(),
+    )
+    .await;
+    assert_eq!(responses.len(), 1);
+    assert_eq!(
+        responses[0].as_ref().unwrap_err().code(),
+        Code::InvalidArgument
+    );
+    assert!(table.clusters().await.is_empty());
+
+    let responses = run(vec![health_check_request(None)], table).await;
+    assert_eq!(
+        responses[0].as_ref().unwrap_err().code(),
+        Code::InvalidArgument
+    );
+}
+
+#[tokio::test]
+async fn test_health_stream_without_assignment_still_records() {
+    let table = Arc::new(EndpointHealthTable::new());
+    table.stream_opened("unassigned").await;
+    let requests = vec![
+        health_check_request(Some("unassigned")),
+        report("a", vec![("10.0.0.1", HealthStatus::Healthy)]),
+    ];
+    let responses = run(requests, table.clone()).await;
+    assert!(responses.is_empty());
+    assert_eq!(
+        table.health_status("a", "10.0.0.1:80").await,
+        Some(HealthStatus::Healthy)
+    );
+}
+
+#[tokio::test]
+async fn test_health_service_fetch() {
+    let service = HealthService::new(StaticAssigner::new(specifier("a")));
+    let rep = service
+        .fetch_health_check(Request::new(health_check_request(Some("node"))))
+        .await
+        .unwrap();
+    assert_eq!(rep.into_inner(), specifier("a"));
+
+    let err = service
+        .fetch_health_check(Request::new(report("a", vec![])))
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), Code::InvalidArgument);
+
+    let service = HealthService::new(PerNodeAssigner);
+    let err = service
+        .fetch_health_check(Request::new(health_check_request(Some("unassigned"))))
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), Code::NotFound);
+}
+
+#[tokio::test]
+async fn test_endpoint_health_table_publishes_changes() {
+    let table = Arc::new(EndpointHealthTable::new());
+    let mut updates = table.subscribe();
+    let requests = vec![
+        health_check_request(Some("a")),
+        report("a", vec![("10.0.0.1", HealthStatus::Healthy)]),
+        report("a", vec![("10.0.0.1", HealthStatus::Healthy)]),
+        report("a", vec![("10.0.0.1", HealthStatus::Unhealthy)]),
+    ];
+    run(requests, table).await;
+    assert_eq!(
+        updates.try_recv().unwrap(),
+        HealthUpdate {
+            cluster_name: "a".to_string(),
+            address: "10.0.0.1:80".to_string(),
+            health_status: HealthStatus::Healthy,
+            previous: None,
+        }
+    );
+    // Repeating a status isn't a change.
+    assert_eq!(
+        updates.try_recv().unwrap().previous,
+        Some(HealthStatus::Healthy)
+    );
+    assert!(updates.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn test_endpoint_health_table_applies_to_assignments() {
+    let table = Arc::new(EndpointHealthTable::new());
+    table.stream_opened("a").await;
+    run(
+        vec![
+            health_check_request(Some("a")),
+            report("a", vec![("10.0.0.1", HealthStatus::Unhealthy)]),
+        ],
+        table.clone(),
+    )
+    .await;
+
+    let mut cla = assignment("a", &["10.0.0.1", "10.0.0.2"]);
+    assert!(table.apply(&mut cla).await);
+    // Endpoints which haven't been reported are left alone.
+    assert_eq!(
+        health_statuses(&cla),
+        vec![HealthStatus::Unhealthy as i32, HealthStatus::Unknown as i32]
+    );
+    assert!(!table.apply(&mut cla).await);
+
+    let mut other = assignment("b", &["10.0.0.1"]);
+    assert!(!table.apply(&mut other).await);
+    assert_eq!(
+        endpoint_address(&endpoint("::1", 8080)).unwrap(),
+        "::1:8080"
+    );
+}
+
+#[tokio::test]
+async fn test_snapshot_health_adapter_updates_cache() {
+    let table = Arc::new(EndpointHealthTable::new());
+    let cache = Arc::new(SnapshotCache::new(false));
+    let mut resources = Resources::new("1".to_string());
+    resources.insert(
+        "a".to_string(),
+        Resource::Endpoint(assignment("a", &["10.0.0.1"])),
+    );
+    let mut snapshot = Snapshot::new();
+    snapshot.insert(type_url::ENDPOINT.to_string(), resources);
+    cache.set_snapshot("node", snapshot).await;
+
+    let adapter = SnapshotHealthAdapter::new(table.clone(), cache.clone());
+    assert_eq!(adapter.apply("node").await.unwrap(), None);
+    assert_eq!(adapter.apply("missing").await.unwrap(), None);
+    assert!(cache.snapshot("missing").await.is_none());
+    table.stream_opened("a").await;
+
+    run(
+        vec![
+            health_check_request(Some("a")),
+            report("a", vec![("10.0.0.1", HealthStatus::Unhealthy)]),
+        ],
+        table,
+    )
+    .await;
+    assert_eq!(adapter.apply_all().await, 1);
+    let snapshot = cache.snapshot("node").await.unwrap();
+    assert_ne!(snapshot.version(type_url::ENDPOINT), "1");
+    match snapshot.resources(type_url::ENDPOINT).unwrap().get("a") {
+        Some(Resource::Endpoint(cla)) => {
+            assert_eq!(health_statuses(cla), vec![HealthStatus::Unhealthy as i32])
+        }
+        other => panic!("unexpected resource {:?}", other),
+    }
+    // Already up to date.
+    assert_eq!(adapter.apply("node").await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn test_endpoint_health_table_drops_reports_when_last_stream_closes() {
+    let table = Arc::new(EndpointHealthTable::new());
+    let requests = vec![
+        health_check_request(Some("a")),
+        report("a", vec![("10.0.0.1", HealthStatus::Healthy)]),
+    ];
+    run(requests, table.clone()).await;
+    assert!(table.clusters().await.is_empty());
+
+    table.stream_opened("a").await;
+    table.stream_opened("b").await;
+    let requests = vec![
+        health_check_request(Some("a")),
+        report("a", vec![("10.0.0.1", HealthStatus::Healthy)]),
+        // Switching nodes closes the first node's stream, leaving the other open.
+        health_check_request(Some("b")),
+        report("a", vec![("10.0.0.2", HealthStatus::Healthy)]),
+        report("b", vec![("10.0.0.1", HealthStatus::Healthy)]),
+    ];
+    run(requests, table.clone()).await;
+    assert_eq!(table.clusters().await["a"].len(), 2);
+    assert_eq!(
+        table.cluster("b").await.unwrap()["10.0.0.1:80"].node_id,
+        "b"
+    );
+
+    // Only the endpoints the node last reported are dropped.
+    table.stream_closed("a").await;
+    let clusters = table.clusters().await;
+    assert_eq!(clusters["a"].len(), 1);
+    assert_eq!(clusters["a"]["10.0.0.2:80"].node_id, "b");
+    table.stream_closed("b").await;
+    assert!(table.clusters().await.is_empty());
+}
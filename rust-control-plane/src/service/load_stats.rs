@@ -0,0 +1,458 @@
+#[cfg(test)]
+mod test;
+
+use crate::cache::node_hash::{IdHash, NodeHash};
+use crate::service::common::{LocalityKey, StreamResponse, UPDATES_CAPACITY};
+use data_plane_api::envoy::config::core::v3::Node;
+use data_plane_api::envoy::config::endpoint::v3::{ClusterStats, UpstreamLocalityStats};
+use data_plane_api::envoy::service::load_stats::v3::load_reporting_service_server::LoadReportingService;
+use data_plane_api::envoy::service::load_stats::v3::{LoadStatsRequest, LoadStatsResponse};
+use data_plane_api::google::protobuf::Duration as DurationPb;
+use futures::StreamExt;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, Mutex};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status, Streaming};
+use tracing::{info, info_span, Instrument};
+
+const DEFAULT_REPORTING_INTERVAL: Duration = Duration::from_secs(10);
+
+// Decides which clusters a node reports load for, and how often.
+#[tonic::async_trait]
+pub trait LoadReportingPolicy: fmt::Debug + Send + Sync + 'static {
+    async fn response(&self, node: &Node) -> LoadStatsResponse;
+}
+
+// Asks every node to report load for the same clusters, at the same interval. By default, every
+// cluster every 10 seconds.
+#[derive(Debug, Clone)]
+pub struct StaticPolicy {
+    // The clusters to report load for, or None for every cluster.
+    clusters: Option<Vec<String>>,
+    interval: Duration,
+}
+
+impl Default for StaticPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StaticPolicy {
+    pub fn new() -> Self {
+        Self {
+            clusters: None,
+            interval: DEFAULT_REPORTING_INTERVAL,
+        }
+    }
+
+    // Asks nodes to report load for only the given clusters.
+    pub fn with_clusters(mut self, clusters: Vec<String>) -> Self {
+        self.clusters = Some(clusters);
+        self
+    }
+
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+}
+
+#[tonic::async_trait]
+impl LoadReportingPolicy for StaticPolicy {
+    async fn response(&self, _node: &Node) -> LoadStatsResponse {
+        LoadStatsResponse {
+            clusters: self.clusters.clone().unwrap_or_default(),
+            send_all_clusters: self.clusters.is_none(),
+            load_reporting_interval: Some(DurationPb {
+                seconds: self.interval.as_secs() as i64,
+                nanos: self.interval.subsec_nanos() as i32,
+            }),
+            report_endpoint_granularity: false,
+        }
+    }
+}
+
+// Serves LRS, telling nodes which clusters to report load for and how often as decided by a
+// LoadReportingPolicy, and recording their reports into a LoadStatsStore.
+#[derive(Debug)]
+pub struct LoadStatsService {
+    policy: Arc<dyn LoadReportingPolicy>,
+    store: Arc<LoadStatsStore>,
+    node_hash: Arc<dyn NodeHash>,
+}
+
+impl Default for LoadStatsService {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LoadStatsService {
+    // Asks nodes to report load as StaticPolicy does by default.
+    pub fn new() -> Self {
+        Self {
+            policy: Arc::new(StaticPolicy::new()),
+            store: Arc::new(LoadStatsStore::new()),
+            node_hash: Arc::new(IdHash),
+        }
+    }
+
+    pub fn with_policy<P: LoadReportingPolicy>(mut self, policy: P) -> Self {
+        self.policy = Arc::new(policy);
+        self
+    }
+
+    pub fn with_store(mut self, store: Arc<LoadStatsStore>) -> Self {
+        self.store = store;
+        self
+    }
+
+    // Sets the key nodes' load is recorded under, which by default is their ID. Nodes which hash
+    // to the same key replace each other's load.
+    pub fn with_node_hash<H: NodeHash>(mut self, node_hash: H) -> Self {
+        self.node_hash = Arc::new(node_hash);
+        self
+    }
+
+    pub fn store(&self) -> Arc<LoadStatsStore> {
+        self.store.clone()
+    }
+}
+
+#[tonic::async_trait]
+impl LoadReportingService for LoadStatsService {
+    type StreamLoadStatsStream = StreamResponse<LoadStatsResponse>;
+
+    async fn stream_load_stats(
+        &self,
+        req: Request<Streaming<LoadStatsRequest>>,
+    ) -> Result<Response<Self::StreamLoadStatsStream>, Status> {
+        let (tx, rx) = mpsc::channel(1);
+        tokio::spawn(
+            handle_load_stats_stream(
+                req.into_inner(),
+                tx,
+                self.policy.clone(),
+                self.store.clone(),
+                self.node_hash.clone(),
+            )
+            .instrument(info_span!("handle_load_stats_stream")),
+        );
+        Ok(Response::new(
+            Box::pin(ReceiverStream::new(rx)) as StreamResponse<LoadStatsResponse>
+        ))
+    }
+}
+
+// Sends the node which clusters to report once it identifies itself, then records every report
+// until the stream ends, when the node's load is dropped.
+async fn handle_load_stats_stream<S>(
+    mut requests: S,
+    responses: mpsc::Sender<Result<LoadStatsResponse, Status>>,
+    policy: Arc<dyn LoadReportingPolicy>,
+    store: Arc<LoadStatsStore>,
+    node_hash: Arc<dyn NodeHash>,
+) where
+    S: futures::Stream<Item = Result<LoadStatsRequest, Status>> + Unpin,
+{
+    // Like xDS, the node might only be sent on the first request.
+    let mut node: Option<Node> = None;
+    while let Some(result) = requests.next().await {
+        let req = match result {
+            Ok(req) => req,
+            Err(status) => {
+                info!("load stats stream receive failed: {}", status);
+                break;
+            }
+        };
+        if node.is_none() {
+            let first = match &req.node {
+                Some(first) => first,
+                None => {
+                    let status = Status::invalid_argument("node is required");
+                    let _ = responses.send(Err(status)).await;
+                    return;
+                }
+            };
+            node = req.node.clone();
+            store.stream_opened(&node_hash.hash(&node)).await;
+            if responses
+                .send(Ok(policy.response(first).await))
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+        let node_id = node_hash.hash(&node);
+        for stats in &req.cluster_stats {
+            store.record(&node_id, stats).await;
+        }
+    }
+    info!("load stats stream closed");
+    if node.is_some() {
+        store.stream_closed(&node_hash.hash(&node)).await;
+    }
+}
+
+// Holds the load reported by nodes over LRS, per cluster, node and locality. Each node's load is
+// that of its latest report, and is dropped once its last stream closes. Nodes are identified by
+// the key the LoadStatsService hashes them to. Subscribers can use it to e.g. weight endpoints by
+// the load on them.
+#[derive(Debug)]
+pub struct LoadStatsStore {
+    inner: Mutex<StoreInner>,
+    updates: broadcast::Sender<LoadUpdate>,
+}
+
+#[derive(Debug, Default)]
+struct StoreInner {
+    clusters: HashMap<ClusterKey, ClusterLoad>,
+    // The number of open streams of each node.
+    streams: HashMap<String, usize>,
+}
+
+// Identifies the cluster load is reported on. Clusters of the same name can use different EDS
+// service names, such as on different nodes, in which case their load is kept apart.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ClusterKey {
+    pub cluster_name: String,
+    // Empty if the cluster doesn't set an EDS service name.
+    pub cluster_service_name: String,
+}
+
+impl ClusterKey {
+    // A cluster without an EDS service name.
+    pub fn new(cluster_name: &str) -> Self {
+        Self {
+            cluster_name: cluster_name.to_string(),
+            cluster_service_name: String::new(),
+        }
+    }
+}
+
+impl From<&ClusterStats> for ClusterKey {
+    fn from(stats: &ClusterStats) -> Self {
+        Self {
+            cluster_name: stats.cluster_name.clone(),
+            cluster_service_name: stats.cluster_service_name.clone(),
+        }
+    }
+}
+
+// The load on a cluster, as of each node's latest report.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClusterLoad {
+    // Keyed by node ID, or whichever key the node hashes to.
+    pub nodes: HashMap<String, NodeLoad>,
+}
+
+// The load a node reported on a cluster over its latest reporting interval.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NodeLoad {
+    // The interval the report covers, as measured by the node.
+    pub interval: Duration,
+    // Requests dropped by the node's load balancer, in total and by category.
+    pub dropped_requests: u64,
+    pub dropped_requests_by_category: HashMap<String, u64>,
+    pub localities: HashMap<LocalityKey, RequestStats>,
+}
+
+// Requests to a cluster's endpoints over a reporting interval.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RequestStats {
+    pub successful_requests: u64,
+    pub error_requests: u64,
+    pub issued_requests: u64,
+    // As of the end of the interval.
+    pub requests_in_progress: u64,
+    // Named metrics reported by endpoints, such as CPU utilization.
+    pub metrics: HashMap<String, MetricStats>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MetricStats {
+    // How many requests reported the metric, and the sum of what they reported.
+    pub requests: u64,
+    pub total: f64,
+}
+
+// Sent to subscribers whenever a node reports load for a cluster, or its load is dropped, with
+// the cluster's load afterwards.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoadUpdate {
+    pub node_id: String,
+    pub cluster: ClusterKey,
+    pub load: ClusterLoad,
+}
+
+impl ClusterLoad {
+    // The load on each locality, summed across nodes.
+    pub fn locality_totals(&self) -> HashMap<LocalityKey, RequestStats> {
+        let mut totals: HashMap<LocalityKey, RequestStats> = HashMap::new();
+        for node in self.nodes.values() {
+            for (locality, stats) in &node.localities {
+                totals.entry(locality.clone()).or_default().add(stats);
+            }
+        }
+        totals
+    }
+
+    // Requests dropped by every node's load balancer.
+    pub fn dropped_requests(&self) -> u64 {
+        self.nodes.values().map(|node| node.dropped_requests).sum()
+    }
+}
+
+impl From<&ClusterStats> for NodeLoad {
+    fn from(stats: &ClusterStats) -> Self {
+        let mut load = NodeLoad {
+            interval: stats
+                .load_report_interval
+                .as_ref()
+                .map(|interval| {
+                    Duration::new(interval.seconds.max(0) as u64, interval.nanos.max(0) as u32)
+                })
+                .unwrap_or_default(),
+            dropped_requests: stats.total_dropped_requests,
+            ..NodeLoad::default()
+        };
+        for dropped in &stats.dropped_requests {
+            *load
+                .dropped_requests_by_category
+                .entry(dropped.category.clone())
+                .or_default() += dropped.dropped_count;
+        }
+        // A locality is reported once per priority it has endpoints at.
+        for locality_stats in &stats.upstream_locality_stats {
+            let locality = locality_stats
+                .locality
+                .as_ref()
+                .map(LocalityKey::from)
+                .unwrap_or_default();
+            load.localities
+                .entry(locality)
+                .or_default()
+                .add(&RequestStats::from(locality_stats));
+        }
+        load
+    }
+}
+
+impl From<&UpstreamLocalityStats> for RequestStats {
+    fn from(stats: &UpstreamLocalityStats) -> Self {
+        let mut metrics: HashMap<String, MetricStats> = HashMap::new();
+        for metric in &stats.load_metric_stats {
+            let total = metrics.entry(metric.metric_name.clone()).or_default();
+            total.requests += metric.num_requests_finished_with_metric;
+            total.total += metric.total_metric_value;
+        }
+        Self {
+            successful_requests: stats.total_successful_requests,
+            error_requests: stats.total_error_requests,
+            issued_requests: stats.total_issued_requests,
+            requests_in_progress: stats.total_requests_in_progress,
+            metrics,
+        }
+    }
+}
+
+impl RequestStats {
+    fn add(&mut self, other: &RequestStats) {
+        self.successful_requests += other.successful_requests;
+        self.error_requests += other.error_requests;
+        self.issued_requests += other.issued_requests;
+        self.requests_in_progress += other.requests_in_progress;
+        for (name, metric) in &other.metrics {
+            let total = self.metrics.entry(name.clone()).or_default();
+            total.requests += metric.requests;
+            total.total += metric.total;
+        }
+    }
+}
+
+impl Default for LoadStatsStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LoadStatsStore {
+    pub fn new() -> Self {
+        let (updates, _) = broadcast::channel(UPDATES_CAPACITY);
+        Self {
+            inner: Mutex::new(StoreInner::default()),
+            updates,
+        }
+    }
+
+    pub async fn cluster(&self, cluster: &ClusterKey) -> Option<ClusterLoad> {
+        let inner = self.inner.lock().await;
+        inner.clusters.get(cluster).cloned()
+    }
+
+    // Returns the load on every cluster reported by a connected node.
+    pub async fn clusters(&self) -> HashMap<ClusterKey, ClusterLoad> {
+        self.inner.lock().await.clusters.clone()
+    }
+
+    // Receives an update whenever a node reports load for a cluster, or its load is dropped.
+    pub fn subscribe(&self) -> broadcast::Receiver<LoadUpdate> {
+        self.updates.subscribe()
+    }
+
+    // Replaces the node's load on the cluster with that of its latest report.
+    pub(crate) async fn record(&self, node_id: &str, stats: &ClusterStats) {
+        let mut inner = self.inner.lock().await;
+        let cluster = ClusterKey::from(stats);
+        let load = inner.clusters.entry(cluster.clone()).or_default();
+        load.nodes
+            .insert(node_id.to_string(), NodeLoad::from(stats));
+        let update = LoadUpdate {
+            node_id: node_id.to_string(),
+            cluster,
+            load: load.clone(),
+        };
+        drop(inner);
+        let _ = self.updates.send(update);
+    }
+
+    pub(crate) async fn stream_opened(&self, node_id: &str) {
+        let mut inner = self.inner.lock().await;
+        *inner.streams.entry(node_id.to_string()).or_default() += 1;
+    }
+
+    // Drops the node's load once none of its streams are open, as it's no longer reporting.
+    pub(crate) async fn stream_closed(&self, node_id: &str) {
+        let mut inner = self.inner.lock().await;
+        match inner.streams.get_mut(node_id) {
+            Some(streams) if *streams > 1 => {
+                *streams -= 1;
+                return;
+            }
+            Some(_) => {
+                inner.streams.remove(node_id);
+            }
+            None => return,
+        }
+        let mut updates = Vec::new();
+        inner.clusters.retain(|cluster, load| {
+            if load.nodes.remove(node_id).is_some() {
+                updates.push(LoadUpdate {
+                    node_id: node_id.to_string(),
+                    cluster: cluster.clone(),
+                    load: load.clone(),
+                });
+            }
+            !load.nodes.is_empty()
+        });
+        drop(inner);
+        for update in updates {
+            let _ = self.updates.send(update);
+        }
+    }
+}
@@ -0,0 +1,255 @@
+use crate::cache::node_hash::{ClusterHash, IdHash};
+use crate::service::common::LocalityKey;
+use crate::service::load_stats::{
+    handle_load_stats_stream, ClusterKey, LoadReportingPolicy, LoadStatsStore, MetricStats,
+    StaticPolicy,
+};
+use crate::service::test_util::run_stream;
+use data_plane_api::envoy::config::core::v3::{Locality, Node};
+use data_plane_api::envoy::config::endpoint::v3::cluster_stats::DroppedRequests;
+use data_plane_api::envoy::config::endpoint::v3::{
+    ClusterStats, EndpointLoadMetricStats, UpstreamLocalityStats,
+};
+use data_plane_api::envoy::service::load_stats::v3::{LoadStatsRequest, LoadStatsResponse};
+use data_plane_api::google::protobuf::Duration as DurationPb;
+use std::sync::Arc;
+use std::time::Duration;
+use tonic::{Code, Status};
+
+fn request(node: Option<&str>, cluster_stats: Vec<ClusterStats>) -> LoadStatsRequest {
+    LoadStatsRequest {
+        node: node.map(|id| Node {
+            id: id.to_string(),
+            ..Node::default()
+        }),
+        cluster_stats,
+    }
+}
+
+fn cluster_stats(
+    cluster_name: &str,
+    zone: &str,
+    successful: u64,
+    in_progress: u64,
+) -> ClusterStats {
+    ClusterStats {
+        cluster_name: cluster_name.to_string(),
+        upstream_locality_stats: vec![UpstreamLocalityStats {
+            locality: Some(Locality {
+                zone: zone.to_string(),
+                ..Locality::default()
+            }),
+            total_successful_requests: successful,
+            total_issued_requests: successful,
+            total_requests_in_progress: in_progress,
+            load_metric_stats: vec![EndpointLoadMetricStats {
+                metric_name: "cpu".to_string(),
+                num_requests_finished_with_metric: successful,
+                total_metric_value: 0.5,
+            }],
+            ..UpstreamLocalityStats::default()
+        }],
+        load_report_interval: Some(DurationPb {
+            seconds: 10,
+            nanos: 0,
+        }),
+        ..ClusterStats::default()
+    }
+}
+
+fn zone(zone: &str) -> LocalityKey {
+    LocalityKey {
+        zone: zone.to_string(),
+        ..LocalityKey::default()
+    }
+}
+
+// Asks nodes to report load for a cluster named after them.
+#[derive(Debug)]
+struct PerNodePolicy;
+
+#[tonic::async_trait]
+impl LoadReportingPolicy for PerNodePolicy {
+    async fn response(&self, node: &Node) -> LoadStatsResponse {
+        LoadStatsResponse {
+            clusters: vec![node.id.clone()],
+            ..LoadStatsResponse::default()
+        }
+    }
+}
+
+async fn run(
+    requests: Vec<LoadStatsRequest>,
+    store: Arc<LoadStatsStore>,
+) -> Vec<Result<LoadStatsResponse, Status>> {
+    run_stream(requests, |requests, tx| {
+        handle_load_stats_stream(
+            requests,
+            tx,
+            Arc::new(PerNodePolicy),
+            store,
+            Arc::new(IdHash),
+        )
+    })
+    .await
+}
+
+#[tokio::test]
+async fn test_static_policy_responses() {
+    let node = Node::default();
+    let rep = StaticPolicy::new().response(&node).await;
+    assert!(rep.send_all_clusters);
+    assert_eq!(rep.load_reporting_interval.unwrap().seconds, 10);
+
+    let rep = StaticPolicy::new()
+        .with_clusters(vec!["a".to_string()])
+        .with_interval(Duration::from_millis(1500))
+        .response(&node)
+        .await;
+    assert!(!rep.send_all_clusters);
+    assert_eq!(rep.clusters, vec!["a".to_string()]);
+    let interval = rep.load_reporting_interval.unwrap();
+    assert_eq!((interval.seconds, interval.nanos), (1, 500_000_000));
+}
+
+#[tokio::test]
+async fn test_load_stats_stream_responds_once_and_records_reports() {
+    let store = Arc::new(LoadStatsStore::new());
+    let mut updates = store.subscribe();
+    let requests = vec![
+        request(Some("node-1"), vec![]),
+        request(None, vec![cluster_stats("a", "east", 10, 3)]),
+        request(None, vec![cluster_stats("a", "east", 5, 1)]),
+    ];
+    let responses = run(requests, store.clone()).await;
+    assert_eq!(responses.len(), 1);
+    assert_eq!(responses[0].as_ref().unwrap().clusters, vec!["node-1"]);
+
+    updates.try_recv().unwrap();
+    // Each report replaces the last, as it covers the interval since.
+    let load = updates.try_recv().unwrap().load;
+    let node = &load.nodes["node-1"];
+    assert_eq!(node.interval, Duration::from_secs(10));
+    let stats = &node.localities[&zone("east")];
+    assert_eq!(stats.successful_requests, 5);
+    assert_eq!(stats.issued_requests, 5);
+    assert_eq!(stats.requests_in_progress, 1);
+    assert_eq!(
+        stats.metrics["cpu"],
+        MetricStats {
+            requests: 5,
+            total: 0.5
+        }
+    );
+
+    // The node's load is dropped once its stream closes.
+    let update = updates.try_recv().unwrap();
+    assert_eq!(update.node_id, "node-1");
+    assert!(update.load.nodes.is_empty());
+    assert!(store.cluster(&ClusterKey::new("a")).await.is_none());
+}
+
+#[tokio::test]
+async fn test_load_stats_store_aggregates_nodes_and_localities() {
+    let store = LoadStatsStore::new();
+    let mut report = cluster_stats("a", "east", 10, 2);
+    report
+        .upstream_locality_stats
+        .extend(cluster_stats("a", "west", 1, 0).upstream_locality_stats);
+    report.total_dropped_requests = 4;
+    report.dropped_requests = vec![DroppedRequests {
+        category: "overload".to_string(),
+        dropped_count: 4,
+    }];
+    store.record("node-1", &report).await;
+    store
+        .record("node-2", &cluster_stats("a", "east", 20, 3))
+        .await;
+
+    let load = store.cluster(&ClusterKey::new("a")).await.unwrap();
+    assert_eq!(load.dropped_requests(), 4);
+    assert_eq!(
+        load.nodes["node-1"].dropped_requests_by_category["overload"],
+        4
+    );
+    let totals = load.locality_totals();
+    assert_eq!(totals[&zone("east")].successful_requests, 30);
+    assert_eq!(totals[&zone("east")].requests_in_progress, 5);
+    assert_eq!(totals[&zone("west")].successful_requests, 1);
+    assert_eq!(store.clusters().await.len(), 1);
+}
+
+#[tokio::test]
+async fn test_load_stats_store_keeps_load_until_last_stream_closes() {
+    let store = LoadStatsStore::new();
+    store.stream_opened("node-1").await;
+    store.stream_opened("node-1").await;
+    store
+        .record("node-1", &cluster_stats("a", "east", 10, 0))
+        .await;
+    store.stream_closed("node-1").await;
+    assert!(store.cluster(&ClusterKey::new("a")).await.is_some());
+    store.stream_closed("node-1").await;
+    assert!(store.clusters().await.is_empty());
+}
+
+#[tokio::test]
+async fn test_load_stats_stream_requires_node() {
+    let store = Arc::new(LoadStatsStore::new());
+    let responses = run(
+        vec![request(None, vec![cluster_stats("a", "east", 10, 0)])],
+        store.clone(),
+    )
+    .await;
+    assert_eq!(responses.len(), 1);
+    assert_eq!(
+        responses[0].as_ref().unwrap_err().code(),
+        Code::InvalidArgument
+    );
+    assert!(store.cluster(&ClusterKey::new("a")).await.is_none());
+}
+
+#[tokio::test]
+async fn test_load_stats_store_keeps_cluster_service_names_apart() {
+    let store = LoadStatsStore::new();
+    let mut report = cluster_stats("a", "east", 10, 0);
+    report.cluster_service_name = "service-1".to_string();
+    store.record("node-1", &report).await;
+    let mut report = cluster_stats("a", "east", 20, 0);
+    report.cluster_service_name = "service-2".to_string();
+    store.record("node-2", &report).await;
+
+    assert_eq!(store.clusters().await.len(), 2);
+    assert!(store.cluster(&ClusterKey::new("a")).await.is_none());
+    let key = ClusterKey {
+        cluster_service_name: "service-2".to_string(),
+        ..ClusterKey::new("a")
+    };
+    let load = store.cluster(&key).await.unwrap();
+    assert_eq!(load.nodes.len(), 1);
+    assert_eq!(
+        load.locality_totals()[&zone("east")].successful_requests,
+        20
+    );
+}
+
+#[tokio::test]
+async fn test_load_stats_stream_keys_nodes_by_node_hash() {
+    let store = Arc::new(LoadStatsStore::new());
+    // Keeps the node's load once the stream ends.
+    store.stream_opened("cluster-1").await;
+    let mut req = request(Some("node-1"), vec![cluster_stats("a", "east", 10, 0)]);
+    req.node.as_mut().unwrap().cluster = "cluster-1".to_string();
+    run_stream(vec![req], |requests, tx| {
+        handle_load_stats_stream(
+            requests,
+            tx,
+            Arc::new(PerNodePolicy),
+            store.clone(),
+            Arc::new(ClusterHash),
+        )
+    })
+    .await;
+    let load = store.cluster(&ClusterKey::new("a")).await.unwrap();
+    assert!(load.nodes.contains_key("cluster-1"));
+}
@@ -3,6 +3,9 @@ mod test;
 
 use super::watches::Watches;
 use crate::cache::{Cache, WatchResponse};
+use crate::service::ack_tracker::AckTracker;
+use crate::service::callbacks::Callbacks;
+use crate::service::common::CloseReason;
 use crate::service::stream_handle::StreamHandle;
 use crate::snapshot::type_url::{self, ANY_TYPE};
 use data_plane_api::envoy::config::core::v3::Node;
@@ -12,36 +15,50 @@ use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::mpsc;
 use tonic::{Status, Streaming};
-use tracing::{info_span, Instrument};
+use tracing::{info, info_span, warn, Instrument};
 
 pub async fn handle_stream<C: Cache>(
-    mut requests: Streaming<DiscoveryRequest>,
+    requests: Streaming<DiscoveryRequest>,
     responses: mpsc::Sender<Result<DiscoveryResponse, Status>>,
     type_url: &'static str,
     cache: Arc<C>,
+    stream_id: usize,
+    callbacks: Arc<dyn Callbacks>,
+    ack_tracker: Arc<AckTracker>,
 ) {
-    let mut stream = Stream::new(responses, type_url, cache);
-    loop {
-        tokio::select! {
-            maybe_req = requests.next() => {
-                let req = maybe_req.unwrap().unwrap();
-                let span = stream.build_client_request_span(&req);
-                stream.handle_client_request(req).instrument(span).await;
-            }
-            Some(rep) = stream.watches_rx.recv() => {
-                stream.handle_watch_response(rep)
-                    .instrument(info_span!("handle_watch_response")).await;
-            }
-        }
+    if let Err(status) = callbacks.on_stream_open(stream_id, type_url).await {
+        // Nothing to clean up if the client has already gone away.
+        let _ = responses.send(Err(status)).await;
+        return;
     }
+    let mut stream = Stream::new(
+        responses,
+        type_url,
+        cache,
+        stream_id,
+        callbacks,
+        ack_tracker,
+    );
+    let reason = stream.run(requests).await;
+    info!("stream closed: {}", reason);
+    stream.watches.cancel_all().await;
+    if stream.node.is_some() {
+        stream.ack_tracker.stream_closed(&stream.node).await;
+    }
+    stream
+        .callbacks
+        .on_stream_closed(stream.id, stream.node.as_ref(), &reason)
+        .await;
 }
 
 struct LastResponse {
     nonce: i64,
+    version: String,
     resource_names: Vec<String>,
 }
 
 struct Stream<C: Cache> {
+    id: usize,
     handle: StreamHandle,
     responses: mpsc::Sender<Result<DiscoveryResponse, Status>>,
     type_url: &'static str,
@@ -52,6 +69,8 @@ struct Stream<C: Cache> {
     node: Option<Node>,
     last_responses: HashMap<String, LastResponse>,
     watches: Watches<C>,
+    callbacks: Arc<dyn Callbacks>,
+    ack_tracker: Arc<AckTracker>,
 }
 
 impl<C: Cache> Stream<C> {
@@ -59,10 +78,14 @@ impl<C: Cache> Stream<C> {
         responses: mpsc::Sender<Result<DiscoveryResponse, Status>>,
         type_url: &'static str,
         cache: Arc<C>,
+        id: usize,
+        callbacks: Arc<dyn Callbacks>,
+        ack_tracker: Arc<AckTracker>,
     ) -> Self {
         let (watches_tx, watches_rx) = mpsc::channel(16);
         let cache_clone = cache.clone();
         Self {
+            id,
             handle: StreamHandle::new(),
             responses,
             type_url,
@@ -73,15 +96,56 @@ impl<C: Cache> Stream<C> {
             node: None,
             last_responses: HashMap::new(),
             watches: Watches::new(cache_clone),
+            callbacks,
+            ack_tracker,
+        }
+    }
+
+    // Serves requests until the stream ends, returning why it ended.
+    async fn run<S>(&mut self, mut requests: S) -> CloseReason
+    where
+        S: futures::Stream<Item = Result<DiscoveryRequest, Status>> + Unpin,
+    {
+        loop {
+            let result = tokio::select! {
+                maybe_req = requests.next() => match maybe_req {
+                    Some(Ok(req)) => {
+                        let span = self.build_client_request_span(&req);
+                        self.handle_client_request(req).instrument(span).await
+                    }
+                    Some(Err(status)) => Err(CloseReason::RecvFailed(status)),
+                    None => Err(CloseReason::ClientClosed),
+                },
+                Some(rep) = self.watches_rx.recv() => {
+                    self.handle_watch_response(rep)
+                        .instrument(info_span!("handle_watch_response")).await
+                }
+            };
+            if let Err(reason) = result {
+                if let CloseReason::Rejected(status) = &reason {
+                    let _ = self.responses.send(Err(status.clone())).await;
+                }
+                return reason;
+            }
         }
     }
 
-    async fn handle_client_request(&mut self, mut req: DiscoveryRequest) {
+    // Returns an error if the stream should be closed.
+    async fn handle_client_request(
+        &mut self,
+        mut req: DiscoveryRequest,
+    ) -> Result<(), CloseReason> {
         // Node might only be sent on the first request to save sending the same data
         // repeatedly, so let's cache it in memory for future requests on this stream.
         // NB: If client changes the node after the first request (that's a client bug), we've
         // chosen to forward that new one to avoid complexity in this algorithm.
         if req.node.is_some() {
+            if req.node != self.node {
+                if self.node.is_some() {
+                    self.ack_tracker.stream_closed(&self.node).await;
+                }
+                self.ack_tracker.stream_opened(&req.node).await;
+            }
             self.node = req.node.clone();
         } else {
             req.node = self.node.clone();
@@ -91,14 +155,23 @@ impl<C: Cache> Stream<C> {
             // Type URL is required for ADS (ANY_TYPE) because we can't tell from just the
             // gRPC method which resource this request is for.
             let status = Status::invalid_argument("type URL is required for ADS");
-            self.responses.send(Err(status)).await.unwrap();
-            return;
+            return self
+                .responses
+                .send(Err(status))
+                .await
+                .map_err(|_| CloseReason::SendFailed);
         } else if req.type_url.is_empty() {
             // Type URL is otherwise optional, but let's set it for consistency.
             // NB: We don't currently validate the type_url, or check if it's for the right RPC.
             req.type_url = self.type_url.to_string();
         }
 
+        self.callbacks
+            .on_stream_request(self.id, &req)
+            .await
+            .map_err(CloseReason::Rejected)?;
+        self.record_ack(&req).await;
+
         // If this is an ack of a previous response, record that the client has received
         // the resource names for that response.
         if let Some(last_response) = self.last_responses.get(&req.type_url) {
@@ -117,7 +190,8 @@ impl<C: Cache> Stream<C> {
                     .cache
                     .create_watch(&req, self.watches_tx.clone(), &self.handle)
                     .instrument(info_span!("create_watch"))
-                    .await;
+                    .await
+                    .map_err(|err| CloseReason::Rejected(err.into()))?;
             }
         } else {
             // No watch exists yet so we can just create one.
@@ -125,26 +199,77 @@ impl<C: Cache> Stream<C> {
                 .cache
                 .create_watch(&req, self.watches_tx.clone(), &self.handle)
                 .instrument(info_span!("create_watch"))
-                .await;
+                .await
+                .map_err(|err| CloseReason::Rejected(err.into()))?;
         }
         if let Some(id) = watch_id {
             self.watches.add(&req.type_url, id);
         }
+        Ok(())
     }
 
-    async fn handle_watch_response(&mut self, mut rep: WatchResponse) {
+    // Returns an error if the stream should be closed.
+    async fn handle_watch_response(&mut self, mut rep: WatchResponse) -> Result<(), CloseReason> {
         self.nonce += 1;
         rep.1.nonce = self.nonce.to_string();
+        self.callbacks
+            .on_stream_response(self.id, &rep.0, &rep.1)
+            .await;
+        self.ack_tracker
+            .sent(
+                &self.node,
+                &rep.0.type_url,
+                &rep.1.version_info,
+                &rep.1.resources,
+            )
+            .await;
         let last_response = LastResponse {
             nonce: self.nonce,
+            version: rep.1.version_info.clone(),
             resource_names: rep.0.resource_names,
         };
         self.last_responses
             .insert(rep.0.type_url.clone(), last_response);
-        self.responses.send(Ok(rep.1)).await.unwrap();
+        self.responses
+            .send(Ok(rep.1))
+            .await
+            .map_err(|_| CloseReason::SendFailed)?;
         if let Some(watch) = self.watches.get_mut(&rep.0.type_url) {
             watch.nonce = Some(self.nonce)
         }
+        Ok(())
+    }
+
+    // If the request responds to the last response sent for its type, records whether the
+    // client accepted or rejected that response. Requests for stale nonces are ignored.
+    async fn record_ack(&self, req: &DiscoveryRequest) {
+        let last_response = match self.last_responses.get(&req.type_url) {
+            Some(last_response) if req.response_nonce == last_response.nonce.to_string() => {
+                last_response
+            }
+            _ => return,
+        };
+        match &req.error_detail {
+            Some(detail) => {
+                warn!(
+                    "nack version={} type_url={} error={}",
+                    last_response.version, req.type_url, detail.message
+                );
+                self.ack_tracker
+                    .nacked(
+                        &req.node,
+                        &req.type_url,
+                        &last_response.version,
+                        detail.clone(),
+                    )
+                    .await;
+            }
+            None => {
+                self.ack_tracker
+                    .acked(&req.node, &req.type_url, &last_response.version)
+                    .await;
+            }
+        }
     }
 
     fn build_client_request_span(&self, req: &DiscoveryRequest) -> tracing::Span {
@@ -1,4 +1,7 @@
-use crate::cache::{Cache, DeltaWatchResponder, FetchError, WatchId, WatchResponder};
+use crate::cache::{Cache, DeltaWatchResponder, FetchError, WatchError, WatchId, WatchResponder};
+use crate::service::ack_tracker::AckTracker;
+use crate::service::callbacks::{Callbacks, NoopCallbacks};
+use crate::service::common::CloseReason;
 use crate::service::stream::Stream;
 use crate::service::stream_handle::{DeltaStreamHandle, StreamHandle};
 use crate::snapshot::type_url::{ANY_TYPE, CLUSTER, ENDPOINT};
@@ -7,6 +10,7 @@ use data_plane_api::envoy::config::core::v3::Node;
 use data_plane_api::envoy::service::discovery::v3::{
     DeltaDiscoveryRequest, DiscoveryRequest, DiscoveryResponse,
 };
+use data_plane_api::google::rpc::Status as RpcStatus;
 use std::sync::Arc;
 use tokio::sync::mpsc;
 use tokio::sync::Mutex;
@@ -21,7 +25,7 @@ type CreateWatchCall = (DiscoveryRequest, WatchResponder);
 
 struct InnerMockCache {
     pub create_watch_calls: Vec<CreateWatchCall>,
-    pub create_watch_rep: Option<WatchId>,
+    pub create_watch_rep: Result<Option<WatchId>, WatchError>,
     pub cancel_watch_calls: Vec<WatchId>,
 }
 
@@ -32,7 +36,7 @@ impl Cache for MockCache {
         req: &DiscoveryRequest,
         tx: WatchResponder,
         _handle: &StreamHandle,
-    ) -> Option<WatchId> {
+    ) -> Result<Option<WatchId>, WatchError> {
         let mut inner = self.inner.lock().await;
         inner.create_watch_calls.push((req.clone(), tx));
         inner.create_watch_rep.clone()
@@ -43,7 +47,7 @@ impl Cache for MockCache {
         _req: &DeltaDiscoveryRequest,
         _tx: DeltaWatchResponder,
         _state: &DeltaStreamHandle,
-    ) -> Option<WatchId> {
+    ) -> Result<Option<WatchId>, WatchError> {
         unimplemented!()
     }
 
@@ -77,7 +81,7 @@ impl InnerMockCache {
     fn new() -> Self {
         Self {
             create_watch_calls: Vec::new(),
-            create_watch_rep: None,
+            create_watch_rep: Ok(None),
             cancel_watch_calls: Vec::new(),
         }
     }
@@ -87,24 +91,41 @@ struct TestHandle {
     pub rx: mpsc::Receiver<Result<DiscoveryResponse, Status>>,
     pub stream: Stream<MockCache>,
     pub cache: Arc<MockCache>,
+    pub ack_tracker: Arc<AckTracker>,
     type_url: &'static str,
 }
 impl TestHandle {
     fn new(type_url: &'static str) -> Self {
         let (tx, rx) = mpsc::channel(1);
         let cache = Arc::new(MockCache::new());
-        let stream = Stream::new(tx, type_url, cache.clone());
+        let ack_tracker = Arc::new(AckTracker::new());
+        let stream = Stream::new(
+            tx,
+            type_url,
+            cache.clone(),
+            0,
+            Arc::new(NoopCallbacks),
+            ack_tracker.clone(),
+        );
         Self {
             rx,
             stream,
             cache,
+            ack_tracker,
             type_url,
         }
     }
 
     fn reconnect(&mut self) {
         let (tx, rx) = mpsc::channel(1);
-        let mut stream = Stream::new(tx, self.type_url, self.cache.clone());
+        let mut stream = Stream::new(
+            tx,
+            self.type_url,
+            self.cache.clone(),
+            0,
+            Arc::new(NoopCallbacks),
+            self.ack_tracker.clone(),
+        );
         self.rx = rx;
         std::mem::swap(&mut self.stream, &mut stream);
         drop(stream);
@@ -115,7 +136,7 @@ impl TestHandle {
     }
 
     async fn set_create_watch_rep(&self, rep: Option<WatchId>) {
-        self.cache.inner.lock().await.create_watch_rep = rep;
+        self.cache.inner.lock().await.create_watch_rep = Ok(rep);
     }
 
     async fn cancel_watch_calls(&self) -> Vec<WatchId> {
@@ -138,8 +159,14 @@ async fn test_stream_stores_node_for_future_requests() {
         type_url: CLUSTER.to_string(),
         ..DiscoveryRequest::default()
     };
-    h.stream.handle_client_request(req_with_node.clone()).await;
-    h.stream.handle_client_request(req_without_node).await;
+    h.stream
+        .handle_client_request(req_with_node.clone())
+        .await
+        .unwrap();
+    h.stream
+        .handle_client_request(req_without_node)
+        .await
+        .unwrap();
     let calls = h.create_watch_calls().await;
     assert_eq!(calls.len(), 2);
     for (req, _) in calls {
@@ -157,7 +184,10 @@ async fn test_stream_forwards_type_url_if_not_present() {
         }),
         ..DiscoveryRequest::default()
     };
-    h.stream.handle_client_request(req_without_type_url).await;
+    h.stream
+        .handle_client_request(req_without_type_url)
+        .await
+        .unwrap();
     let calls = h.create_watch_calls().await;
     assert_eq!(calls.len(), 1);
     assert_eq!(calls[0].0.type_url, CLUSTER);
@@ -173,7 +203,10 @@ async fn test_stream_aborts_if_type_url_not_present_for_ads() {
         }),
         ..DiscoveryRequest::default()
     };
-    h.stream.handle_client_request(req_without_type_url).await;
+    h.stream
+        .handle_client_request(req_without_type_url)
+        .await
+        .unwrap();
     let calls = h.create_watch_calls().await;
     assert_eq!(calls.len(), 0);
     let status = h.rx.try_recv().unwrap().unwrap_err();
@@ -181,6 +214,24 @@ async fn test_stream_aborts_if_type_url_not_present_for_ads() {
     assert_eq!(status.message(), "type URL is required for ADS");
 }
 
+#[tokio::test]
+async fn test_stream_rejects_type_url_not_served_by_cache() {
+    let mut h = TestHandle::new(ANY_TYPE);
+    h.cache.inner.lock().await.create_watch_rep = Err(WatchError::UnsupportedType);
+    let req = DiscoveryRequest {
+        node: Some(Node {
+            id: "foobar".to_string(),
+            ..Node::default()
+        }),
+        type_url: CLUSTER.to_string(),
+        ..DiscoveryRequest::default()
+    };
+    match h.stream.handle_client_request(req).await {
+        Err(CloseReason::Rejected(status)) => assert_eq!(status.code(), Code::InvalidArgument),
+        other => panic!("unexpected result {:?}", other),
+    }
+}
+
 #[tokio::test]
 async fn test_stream_cancels_watches_on_drop() {
     let mut h = TestHandle::new(ANY_TYPE);
@@ -205,13 +256,13 @@ async fn test_stream_cancels_watches_on_drop() {
         index: 0,
     };
     h.set_create_watch_rep(Some(watch1.clone())).await;
-    h.stream.handle_client_request(req1).await;
+    h.stream.handle_client_request(req1).await.unwrap();
     let watch2 = WatchId {
         node_id: "foobar".to_string(),
         index: 1,
     };
     h.set_create_watch_rep(Some(watch2.clone())).await;
-    h.stream.handle_client_request(req2).await;
+    h.stream.handle_client_request(req2).await.unwrap();
     h.reconnect();
     // NB: I don't know how else we can wait for the task spawned by drop to complete.
     tokio::time::sleep(tokio::time::Duration::from_millis(1)).await;
@@ -247,16 +298,157 @@ async fn test_stream_second_watch_cancels_first() {
         index: 0,
     };
     h.set_create_watch_rep(Some(watch1.clone())).await;
-    h.stream.handle_client_request(req1).await;
+    h.stream.handle_client_request(req1).await.unwrap();
     let watch2 = WatchId {
         node_id: "foobar".to_string(),
         index: 1,
     };
     h.set_create_watch_rep(Some(watch2.clone())).await;
-    h.stream.handle_client_request(req2).await;
+    h.stream.handle_client_request(req2).await.unwrap();
     let create_calls = h.create_watch_calls().await;
     assert_eq!(create_calls.len(), 2);
     let cancel_calls = h.cancel_watch_calls().await;
     assert_eq!(cancel_calls.len(), 1);
     assert_eq!(cancel_calls[0], watch1);
 }
+
+#[derive(Debug)]
+struct RejectingCallbacks;
+
+#[async_trait]
+impl Callbacks for RejectingCallbacks {
+    async fn on_stream_request(
+        &self,
+        _stream_id: usize,
+        _req: &DiscoveryRequest,
+    ) -> Result<(), Status> {
+        Err(Status::permission_denied("rejected"))
+    }
+}
+
+#[tokio::test]
+async fn test_stream_callback_error_rejects_request() {
+    let (tx, _rx) = mpsc::channel(1);
+    let cache = Arc::new(MockCache::new());
+    let mut stream = Stream::new(
+        tx,
+        CLUSTER,
+        cache.clone(),
+        0,
+        Arc::new(RejectingCallbacks),
+        Arc::new(AckTracker::new()),
+    );
+    let req = DiscoveryRequest {
+        type_url: CLUSTER.to_string(),
+        ..DiscoveryRequest::default()
+    };
+    let reason = stream.handle_client_request(req).await.unwrap_err();
+    assert!(matches!(
+        reason,
+        CloseReason::Rejected(status) if status.code() == Code::PermissionDenied
+    ));
+    assert!(cache.inner.lock().await.create_watch_calls.is_empty());
+}
+
+#[tokio::test]
+async fn test_stream_records_acks_and_nacks() {
+    let mut h = TestHandle::new(CLUSTER);
+    let node = Some(Node {
+        id: "foobar".to_string(),
+        ..Node::default()
+    });
+    let req = DiscoveryRequest {
+        node: node.clone(),
+        type_url: CLUSTER.to_string(),
+        ..DiscoveryRequest::default()
+    };
+    h.stream.handle_client_request(req.clone()).await.unwrap();
+    for version in ["1", "2"] {
+        let rep = DiscoveryResponse {
+            type_url: CLUSTER.to_string(),
+            version_info: version.to_string(),
+            ..DiscoveryResponse::default()
+        };
+        h.stream
+            .handle_watch_response((req.clone(), rep))
+            .await
+            .unwrap();
+        h.rx.recv().await.unwrap().unwrap();
+    }
+    let status = h.ack_tracker.status("foobar", CLUSTER).await.unwrap();
+    assert_eq!(status.last_sent_version.as_deref(), Some("2"));
+    assert_eq!(status.last_acked_version, None);
+
+    // Requests for stale nonces are ignored.
+    let ack = DiscoveryRequest {
+        version_info: "1".to_string(),
+        response_nonce: "1".to_string(),
+        ..req.clone()
+    };
+    h.stream.handle_client_request(ack).await.unwrap();
+    let status = h.ack_tracker.status("foobar", CLUSTER).await.unwrap();
+    assert_eq!(status.last_acked_version, None);
+
+    let nack = DiscoveryRequest {
+        version_info: "1".to_string(),
+        response_nonce: "2".to_string(),
+        error_detail: Some(RpcStatus {
+            code: Code::InvalidArgument as i32,
+            message: "bad cluster".to_string(),
+            ..RpcStatus::default()
+        }),
+        ..req.clone()
+    };
+    h.stream.handle_client_request(nack).await.unwrap();
+    let status = h.ack_tracker.status("foobar", CLUSTER).await.unwrap();
+    assert_eq!(status.last_acked_version, None);
+    assert_eq!(status.last_nacked_version.as_deref(), Some("2"));
+    assert_eq!(status.nack_detail.unwrap().message, "bad cluster");
+
+    let ack = DiscoveryRequest {
+        version_info: "2".to_string(),
+        response_nonce: "2".to_string(),
+        ..req
+    };
+    h.stream.handle_client_request(ack).await.unwrap();
+    let status = h.ack_tracker.status("foobar", CLUSTER).await.unwrap();
+    assert_eq!(status.last_acked_version.as_deref(), Some("2"));
+}
+
+#[tokio::test]
+async fn test_stream_run_ends_on_eof() {
+    let mut h = TestHandle::new(CLUSTER);
+    let req = DiscoveryRequest {
+        type_url: CLUSTER.to_string(),
+        ..DiscoveryRequest::default()
+    };
+    let reason = h.stream.run(tokio_stream::iter(vec![Ok(req)])).await;
+    assert!(matches!(reason, CloseReason::ClientClosed));
+    assert_eq!(h.create_watch_calls().await.len(), 1);
+}
+
+#[tokio::test]
+async fn test_stream_run_ends_on_transport_error() {
+    let mut h = TestHandle::new(CLUSTER);
+    let requests = tokio_stream::iter(vec![Err(Status::unavailable("connection reset"))]);
+    let reason = h.stream.run(requests).await;
+    assert!(matches!(
+        reason,
+        CloseReason::RecvFailed(status) if status.code() == Code::Unavailable
+    ));
+}
+
+#[tokio::test]
+async fn test_stream_run_ends_when_client_goes_away() {
+    let mut h = TestHandle::new(CLUSTER);
+    let req = DiscoveryRequest {
+        type_url: CLUSTER.to_string(),
+        ..DiscoveryRequest::default()
+    };
+    h.stream.handle_client_request(req.clone()).await.unwrap();
+    let (_, tx) = h.create_watch_calls().await.pop().unwrap();
+    tx.send((req, DiscoveryResponse::default())).await.unwrap();
+    h.rx.close();
+    let reason = h.stream.run(futures::stream::pending()).await;
+    assert!(matches!(reason, CloseReason::SendFailed));
+}
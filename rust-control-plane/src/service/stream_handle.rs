@@ -1,3 +1,6 @@
+#[cfg(test)]
+mod test;
+
 use data_plane_api::envoy::service::discovery::v3::DeltaDiscoveryRequest;
 use std::collections::{HashMap, HashSet};
 
@@ -40,9 +43,16 @@ impl Default for StreamHandle {
     }
 }
 
+// The subscriptions of a delta stream for a single type URL, and the versions of the resources
+// the client has been sent.
+// A client is subscribed to every resource (wildcard) if it explicitly subscribes to "*", or by
+// the legacy convention of naming no resources in its first request. The legacy wildcard ends
+// as soon as the client names a resource without also subscribing to "*". Named subscriptions
+// are independent of the wildcard, so they're still delivered after "*" is unsubscribed.
 #[derive(Clone, Debug)]
 pub struct DeltaStreamHandle {
     wildcard: bool,
+    legacy_wildcard: bool,
     subscribed_resource_names: HashSet<String>,
     resource_versions: HashMap<String, String>,
     first: bool,
@@ -51,18 +61,32 @@ pub struct DeltaStreamHandle {
 impl DeltaStreamHandle {
     pub fn new(req: &DeltaDiscoveryRequest) -> Self {
         Self {
-            wildcard: req.resource_names_subscribe.is_empty()
+            wildcard: false,
+            legacy_wildcard: req.resource_names_subscribe.is_empty()
                 && req.resource_names_unsubscribe.is_empty(),
             subscribed_resource_names: HashSet::new(),
-            resource_versions: req.initial_resource_versions.clone(),
+            resource_versions: req
+                .initial_resource_versions
+                .iter()
+                .map(|(name, version)| (name.clone(), version.clone()))
+                .collect(),
             first: true,
         }
     }
 
     pub fn apply_subscriptions(&mut self, req: &DeltaDiscoveryRequest) {
-        self.first = false;
+        if !req.resource_names_subscribe.is_empty() {
+            self.legacy_wildcard = false;
+        }
         self.subscribe(&req.resource_names_subscribe);
         self.unsubscribe(&req.resource_names_unsubscribe);
+        if !self.is_wildcard() {
+            // The client forgets resources it's no longer subscribed to, so there's no need to
+            // tell it they've been removed, and they must be sent in full if resubscribed to.
+            let subscribed = &self.subscribed_resource_names;
+            self.resource_versions
+                .retain(|name, _| subscribed.contains(name));
+        }
     }
 
     pub fn subscribe(&mut self, resources: &[String]) {
@@ -79,17 +103,19 @@ impl DeltaStreamHandle {
         for name in resources {
             if name == "*" {
                 self.wildcard = false;
+                self.legacy_wildcard = false;
                 continue;
             }
-            if self.subscribed_resource_names.contains(name) && self.wildcard {
+            if self.subscribed_resource_names.remove(name) && self.is_wildcard() {
+                // Still subscribed through the wildcard. The client will have dropped the
+                // resource, so it must be sent again, or removed if it no longer exists.
                 self.resource_versions.insert(name.clone(), String::new());
             }
-            self.subscribed_resource_names.remove(name);
         }
     }
 
     pub fn is_wildcard(&self) -> bool {
-        self.wildcard
+        self.wildcard || self.legacy_wildcard
     }
 
     pub fn is_first(&self) -> bool {
@@ -104,7 +130,10 @@ impl DeltaStreamHandle {
         &self.subscribed_resource_names
     }
 
+    // Records the versions sent to the client, which means it's no longer waiting on a first
+    // response.
     pub fn set_resource_versions(&mut self, versions: HashMap<String, String>) {
+        self.first = false;
         self.resource_versions = versions
     }
 }
@@ -0,0 +1,145 @@
+use crate::service::stream_handle::DeltaStreamHandle;
+use data_plane_api::envoy::service::discovery::v3::DeltaDiscoveryRequest;
+use std::collections::{HashMap, HashSet};
+
+fn request(subscribe: &[&str], unsubscribe: &[&str]) -> DeltaDiscoveryRequest {
+    DeltaDiscoveryRequest {
+        resource_names_subscribe: subscribe.iter().map(|name| name.to_string()).collect(),
+        resource_names_unsubscribe: unsubscribe.iter().map(|name| name.to_string()).collect(),
+        ..DeltaDiscoveryRequest::default()
+    }
+}
+
+struct Case {
+    name: &'static str,
+    // The subscribe and unsubscribe lists of each request, in order.
+    requests: Vec<(Vec<&'static str>, Vec<&'static str>)>,
+    wildcard: bool,
+    subscribed: Vec<&'static str>,
+}
+
+#[test]
+fn test_delta_stream_handle_subscriptions() {
+    let cases = vec![
+        Case {
+            name: "legacy wildcard",
+            requests: vec![(vec![], vec![])],
+            wildcard: true,
+            subscribed: vec![],
+        },
+        Case {
+            name: "explicit wildcard",
+            requests: vec![(vec!["*"], vec![])],
+            wildcard: true,
+            subscribed: vec![],
+        },
+        Case {
+            name: "named resources",
+            requests: vec![(vec!["a", "b"], vec![])],
+            wildcard: false,
+            subscribed: vec!["a", "b"],
+        },
+        Case {
+            name: "legacy wildcard ends when resources are named",
+            requests: vec![(vec![], vec![]), (vec!["a"], vec![])],
+            wildcard: false,
+            subscribed: vec!["a"],
+        },
+        Case {
+            name: "legacy wildcard continues when named with *",
+            requests: vec![(vec![], vec![]), (vec!["*", "a"], vec![])],
+            wildcard: true,
+            subscribed: vec!["a"],
+        },
+        Case {
+            name: "legacy wildcard continues on empty requests",
+            requests: vec![(vec![], vec![]), (vec![], vec![])],
+            wildcard: true,
+            subscribed: vec![],
+        },
+        Case {
+            name: "legacy wildcard ends when * is unsubscribed",
+            requests: vec![(vec![], vec![]), (vec![], vec!["*"])],
+            wildcard: false,
+            subscribed: vec![],
+        },
+        Case {
+            name: "explicit wildcard continues when resources are named",
+            requests: vec![(vec!["*"], vec![]), (vec!["a"], vec![])],
+            wildcard: true,
+            subscribed: vec!["a"],
+        },
+        Case {
+            name: "named resources kept when * is unsubscribed",
+            requests: vec![(vec!["*", "a"], vec![]), (vec![], vec!["*"])],
+            wildcard: false,
+            subscribed: vec!["a"],
+        },
+        Case {
+            name: "wildcard kept when named resource is unsubscribed",
+            requests: vec![(vec!["*", "a"], vec![]), (vec![], vec!["a"])],
+            wildcard: true,
+            subscribed: vec![],
+        },
+        Case {
+            name: "wildcard resubscribed",
+            requests: vec![
+                (vec!["*"], vec![]),
+                (vec![], vec!["*"]),
+                (vec!["*"], vec![]),
+            ],
+            wildcard: true,
+            subscribed: vec![],
+        },
+        Case {
+            name: "named resources added to wildcard",
+            requests: vec![(vec!["a"], vec![]), (vec!["*"], vec![])],
+            wildcard: true,
+            subscribed: vec!["a"],
+        },
+    ];
+    for case in cases {
+        let reqs: Vec<_> = case
+            .requests
+            .iter()
+            .map(|(subscribe, unsubscribe)| request(subscribe, unsubscribe))
+            .collect();
+        let mut stream = DeltaStreamHandle::new(&reqs[0]);
+        for req in &reqs {
+            stream.apply_subscriptions(req);
+        }
+        assert_eq!(stream.is_wildcard(), case.wildcard, "{}", case.name);
+        let subscribed: HashSet<String> = case.subscribed.iter().map(|s| s.to_string()).collect();
+        assert_eq!(
+            stream.subscribed_resource_names(),
+            &subscribed,
+            "{}",
+            case.name
+        );
+    }
+}
+
+#[test]
+fn test_delta_stream_handle_forgets_unsubscribed_versions() {
+    let req = request(&["a", "b"], &[]);
+    let mut stream = DeltaStreamHandle::new(&req);
+    stream.apply_subscriptions(&req);
+    let versions = HashMap::from([
+        ("a".to_string(), "1".to_string()),
+        ("b".to_string(), "1".to_string()),
+    ]);
+    stream.set_resource_versions(versions);
+    stream.apply_subscriptions(&request(&[], &["b"]));
+    let expected = HashMap::from([("a".to_string(), "1".to_string())]);
+    assert_eq!(stream.resource_versions(), &expected);
+}
+
+#[test]
+fn test_delta_stream_handle_is_first_until_responded() {
+    let req = request(&[], &[]);
+    let mut stream = DeltaStreamHandle::new(&req);
+    stream.apply_subscriptions(&req);
+    assert!(stream.is_first());
+    stream.set_resource_versions(HashMap::new());
+    assert!(!stream.is_first());
+}
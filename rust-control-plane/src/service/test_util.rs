@@ -0,0 +1,25 @@
+use std::future::Future;
+use tokio::sync::mpsc;
+use tonic::Status;
+
+pub(crate) type Requests<T> = tokio_stream::Iter<std::vec::IntoIter<Result<T, Status>>>;
+
+// Runs a stream handler over the given requests until it returns, collecting the responses it
+// sent.
+pub(crate) async fn run_stream<Req, Rep, F, Fut>(
+    requests: Vec<Req>,
+    handle: F,
+) -> Vec<Result<Rep, Status>>
+where
+    F: FnOnce(Requests<Req>, mpsc::Sender<Result<Rep, Status>>) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let (tx, mut rx) = mpsc::channel(16);
+    let requests: Vec<Result<Req, Status>> = requests.into_iter().map(Ok).collect();
+    handle(tokio_stream::iter(requests), tx).await;
+    let mut responses = Vec::new();
+    while let Ok(rep) = rx.try_recv() {
+        responses.push(rep);
+    }
+    responses
+}
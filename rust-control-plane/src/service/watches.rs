@@ -38,6 +38,11 @@ impl<C: Cache> Watches<C> {
             },
         );
     }
+
+    // Cancels every active watch.
+    pub async fn cancel_all(&mut self) {
+        cancel_all(std::mem::take(&mut self.active), self.cache.clone()).await;
+    }
 }
 
 pub async fn cancel_all<C: Cache>(active: HashMap<String, Watch>, cache: Arc<C>) {
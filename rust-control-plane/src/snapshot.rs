@@ -1,22 +1,33 @@
+pub mod builder;
+#[cfg(test)]
+mod test;
 pub mod type_url;
 
+use data_plane_api::envoy::config::cluster::v3::cluster::{ClusterDiscoveryType, DiscoveryType};
 use data_plane_api::envoy::config::cluster::v3::Cluster;
 use data_plane_api::envoy::config::core::v3::TypedExtensionConfig;
 use data_plane_api::envoy::config::endpoint::v3::ClusterLoadAssignment;
+use data_plane_api::envoy::config::listener::v3::filter::ConfigType;
 use data_plane_api::envoy::config::listener::v3::Listener;
-use data_plane_api::envoy::config::route::v3::RouteConfiguration;
+use data_plane_api::envoy::config::route::v3::{RouteConfiguration, VirtualHost};
 use data_plane_api::envoy::config::route::v3::ScopedRouteConfiguration;
+use data_plane_api::envoy::extensions::filters::network::http_connection_manager::v3::http_connection_manager::RouteSpecifier;
+use data_plane_api::envoy::extensions::filters::network::http_connection_manager::v3::HttpConnectionManager;
 use data_plane_api::envoy::extensions::transport_sockets::tls::v3::Secret;
 use data_plane_api::envoy::service::runtime::v3::Runtime;
 use data_plane_api::google::protobuf::Any;
 use prost::Message;
 use sha2::{Digest, Sha256};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, OnceLock};
+
+const HTTP_CONNECTION_MANAGER: &str = "type.googleapis.com/envoy.extensions.filters.network.http_connection_manager.v3.HttpConnectionManager";
 
 #[derive(Debug, Clone)]
 pub struct Snapshot {
     pub resources: HashMap<String, Resources>,
-    pub version_map: Option<HashMap<String, HashMap<String, String>>>,
 }
 
 impl Default for Snapshot {
@@ -29,11 +40,11 @@ impl Snapshot {
     pub fn new() -> Self {
         Self {
             resources: HashMap::new(),
-            version_map: None,
         }
     }
 
-    pub fn insert(&mut self, type_url: String, resources: Resources) {
+    pub fn insert(&mut self, type_url: String, mut resources: Resources) {
+        resources.update_version();
         self.resources.insert(type_url, resources);
     }
 
@@ -47,22 +58,140 @@ impl Snapshot {
         self.resources.get(type_url)
     }
 
-    pub fn build_version_map(&mut self) {
-        if self.version_map.is_none() {
-            let mut map: HashMap<String, HashMap<String, String>> = HashMap::new();
-            for (type_url, resources) in &self.resources {
-                let entry = map.entry(type_url.clone()).or_insert_with(HashMap::new);
-                for (name, resource) in &resources.items {
-                    let hash = Sha256::digest(resource.encode_to_vec());
-                    entry.insert(name.clone(), format!("{:x}", hash));
+    // Brings the versions of content addressed resources up to date, in case items were
+    // inserted or removed directly rather than through Resources::insert.
+    pub(crate) fn update_versions(&mut self) {
+        for resources in self.resources.values_mut() {
+            resources.update_version();
+        }
+    }
+
+    // Checks that every resource referenced by another resource in the snapshot is present.
+    // That is, every EDS cluster has a load assignment, and every listener's RDS route
+    // configuration exists. Envoy will otherwise stall warming the referencing resource.
+    pub fn consistent(&self) -> Result<(), ConsistencyError> {
+        let mut references: HashMap<&str, HashSet<String>> = HashMap::new();
+        for resource in self.items(type_url::CLUSTER) {
+            if let Resource::Cluster(cluster) = resource {
+                if let Some(name) = eds_cluster_name(cluster) {
+                    references
+                        .entry(type_url::ENDPOINT)
+                        .or_default()
+                        .insert(name);
                 }
             }
-            self.version_map = Some(map);
         }
+        for resource in self.items(type_url::LISTENER) {
+            if let Resource::Listener(listener) = resource {
+                references
+                    .entry(type_url::ROUTE)
+                    .or_default()
+                    .extend(rds_route_names(listener));
+            }
+        }
+
+        let mut dangling = HashMap::new();
+        for (type_url, names) in references {
+            let mut missing: Vec<String> = names
+                .into_iter()
+                .filter(|name| {
+                    !matches!(
+                        self.resources(type_url),
+                        Some(resources) if resources.items.contains_key(name)
+                    )
+                })
+                .collect();
+            if !missing.is_empty() {
+                missing.sort();
+                dangling.insert(type_url.to_string(), missing);
+            }
+        }
+        if dangling.is_empty() {
+            Ok(())
+        } else {
+            Err(ConsistencyError { dangling })
+        }
+    }
+
+    fn items(&self, type_url: &str) -> impl Iterator<Item = &Resource> {
+        self.resources(type_url)
+            .into_iter()
+            .flat_map(|resources| resources.items.values())
+            .map(|item| &item.resource)
+    }
+}
+
+// The name of the load assignment an EDS cluster expects, or None for other cluster types.
+fn eds_cluster_name(cluster: &Cluster) -> Option<String> {
+    let eds = Some(ClusterDiscoveryType::Type(DiscoveryType::Eds as i32));
+    if cluster.cluster_discovery_type != eds {
+        return None;
+    }
+    match &cluster.eds_cluster_config {
+        Some(config) if !config.service_name.is_empty() => Some(config.service_name.clone()),
+        _ => Some(cluster.name.clone()),
+    }
+}
+
+// The names of the route configurations fetched over RDS by a listener's
+// HttpConnectionManagers.
+fn rds_route_names(listener: &Listener) -> Vec<String> {
+    let mut configs: Vec<&Any> = listener
+        .filter_chains
+        .iter()
+        .chain(listener.default_filter_chain.iter())
+        .flat_map(|chain| chain.filters.iter())
+        .filter_map(|filter| match &filter.config_type {
+            Some(ConfigType::TypedConfig(config)) => Some(config),
+            _ => None,
+        })
+        .collect();
+    if let Some(config) = listener
+        .api_listener
+        .as_ref()
+        .and_then(|api_listener| api_listener.api_listener.as_ref())
+    {
+        configs.push(config);
+    }
+    configs
+        .into_iter()
+        .filter(|config| config.type_url == HTTP_CONNECTION_MANAGER)
+        .filter_map(|config| HttpConnectionManager::decode(config.value.clone()).ok())
+        .filter_map(|manager| match manager.route_specifier {
+            Some(RouteSpecifier::Rds(rds)) => Some(rds.route_config_name),
+            _ => None,
+        })
+        .collect()
+}
+
+// Lists the names referenced by resources in a snapshot which are missing from it, keyed by the
+// type URL of the missing resources.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConsistencyError {
+    pub dangling: HashMap<String, Vec<String>>,
+}
+
+impl fmt::Display for ConsistencyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut type_urls: Vec<&String> = self.dangling.keys().collect();
+        type_urls.sort();
+        write!(f, "snapshot has dangling references:")?;
+        for type_url in type_urls {
+            write!(
+                f,
+                " {} {:?}",
+                type_url::shorten(type_url),
+                self.dangling[type_url]
+            )?;
+        }
+        Ok(())
     }
 }
 
-pub fn hash_resource(resource: Resource) -> String {
+impl Error for ConsistencyError {}
+
+// Stable across processes, since the generated types encode maps in key order.
+pub fn hash_resource(resource: &Resource) -> String {
     let hash = Sha256::digest(resource.encode_to_vec());
     format!("{:x}", hash)
 }
@@ -70,7 +199,8 @@ pub fn hash_resource(resource: Resource) -> String {
 #[derive(Clone, Debug)]
 pub struct Resources {
     pub version: String,
-    pub items: HashMap<String, Resource>,
+    pub items: HashMap<String, VersionedResource>,
+    content_addressed: bool,
 }
 
 impl Resources {
@@ -78,71 +208,216 @@ impl Resources {
         Self {
             version,
             items: HashMap::new(),
+            content_addressed: false,
+        }
+    }
+
+    // Resources versioned by a hash of their names and content, rather than by the caller.
+    // Identical resources always have the same version, so setting them again doesn't push
+    // anything to clients. The version is computed when the resources are inserted into a
+    // snapshot, or set on a cache.
+    pub fn content_addressed() -> Self {
+        Self {
+            version: String::new(),
+            items: HashMap::new(),
+            content_addressed: true,
+        }
+    }
+
+    pub fn is_content_addressed(&self) -> bool {
+        self.content_addressed
+    }
+
+    // A hash of the resources' names and versions, independent of insertion order.
+    pub fn content_version(&self) -> String {
+        let mut names: Vec<&String> = self.items.keys().collect();
+        names.sort();
+        let mut hasher = Sha256::new();
+        for name in names {
+            // Names and versions can't contain NUL, so it keeps entries unambiguous.
+            hasher.update(name.as_bytes());
+            hasher.update([0]);
+            hasher.update(self.items[name].version.as_bytes());
+            hasher.update([0]);
+        }
+        format!("{:x}", hasher.finalize())
+    }
+
+    // Recomputes the version of content addressed resources. Others are left alone.
+    pub(crate) fn update_version(&mut self) {
+        if self.content_addressed {
+            self.version = self.content_version();
+        }
+    }
+
+    // Inserts or replaces a resource, hashing it once for its version.
+    pub fn insert(&mut self, name: String, resource: Resource) {
+        self.items.insert(name, VersionedResource::new(resource));
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Resource> {
+        self.items.get(name).map(|item| &item.resource)
+    }
+}
+
+// A resource along with the hash of its encoding, which is its version on delta streams.
+// Computed when the resource enters a snapshot or cache, so that it's shared by every stream
+// rather than recomputed for every response.
+#[derive(Clone, Debug)]
+pub struct VersionedResource {
+    // Not pub, so that users can't modify the resource without updating its version and
+    // encoding.
+    pub(crate) resource: Resource,
+    pub(crate) version: String,
+    // Built the first time the resource is sent. Clones share it, so every stream sent the same
+    // snapshot shares one encoding.
+    any: Arc<OnceLock<Any>>,
+}
+
+impl VersionedResource {
+    pub fn new(resource: Resource) -> Self {
+        let version = hash_resource(&resource);
+        Self {
+            resource,
+            version,
+            any: Arc::new(OnceLock::new()),
         }
     }
+
+    pub fn resource(&self) -> &Resource {
+        &self.resource
+    }
+
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    // The encoded resource, as sent in responses. The returned Any references the shared
+    // encoding rather than copying it.
+    pub fn to_any(&self) -> Any {
+        self.any.get_or_init(|| self.resource.into_any()).clone()
+    }
+}
+
+// A type of resource which can be served over xDS. Implemented by the Envoy types the Resource
+// enum has variants for, and by custom types, which are wrapped in Resource::Custom.
+pub trait XdsResource: fmt::Debug + Send + Sync + 'static {
+    // The name a resource is keyed by in a snapshot, and subscribed to by clients.
+    fn name(&self) -> &str;
+
+    fn type_url(&self) -> &str;
+
+    // The encoded resource, which is sent as the value of an Any.
+    fn encode_value(&self) -> Vec<u8>;
+}
+
+macro_rules! impl_xds_resource {
+    ($type:ty, $type_url:expr, $name:ident) => {
+        impl XdsResource for $type {
+            fn name(&self) -> &str {
+                &self.$name
+            }
+
+            fn type_url(&self) -> &str {
+                $type_url
+            }
+
+            fn encode_value(&self) -> Vec<u8> {
+                self.encode_to_vec()
+            }
+        }
+    };
 }
 
+impl_xds_resource!(Cluster, type_url::CLUSTER, name);
+impl_xds_resource!(ClusterLoadAssignment, type_url::ENDPOINT, cluster_name);
+impl_xds_resource!(RouteConfiguration, type_url::ROUTE, name);
+impl_xds_resource!(VirtualHost, type_url::VIRTUAL_HOST, name);
+impl_xds_resource!(Listener, type_url::LISTENER, name);
+impl_xds_resource!(Secret, type_url::SECRET, name);
+impl_xds_resource!(Runtime, type_url::RUNTIME, name);
+impl_xds_resource!(ScopedRouteConfiguration, type_url::SCOPED_ROUTE, name);
+impl_xds_resource!(TypedExtensionConfig, type_url::EXTENSION_CONFIG, name);
+
 #[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone)]
 pub enum Resource {
     Cluster(Cluster),
     Endpoint(ClusterLoadAssignment),
     Route(RouteConfiguration),
+    // Served over VHDS, where virtual hosts are named <route_config_name>/<name>. So a virtual
+    // host's name must be prefixed with the name of the route configuration it belongs to.
+    VirtualHost(VirtualHost),
     Listener(Listener),
     Secret(Secret),
     Runtime(Runtime),
     ScopedRoute(ScopedRouteConfiguration),
     ExtensionConfig(TypedExtensionConfig),
+    // Any other type, e.g. a custom extension config.
+    Custom(Arc<dyn XdsResource>),
 }
 
 impl Resource {
+    pub fn custom<R: XdsResource>(resource: R) -> Self {
+        Resource::Custom(Arc::new(resource))
+    }
+
+    // The name a resource is keyed by in a snapshot, and subscribed to by clients.
+    pub fn name(&self) -> &str {
+        self.as_xds_resource().name()
+    }
+
+    pub fn type_url(&self) -> &str {
+        self.as_xds_resource().type_url()
+    }
+
     pub fn into_any(&self) -> Any {
-        match self {
-            Resource::Cluster(cluster) => Any {
-                type_url: type_url::CLUSTER.to_string(),
-                value: cluster.encode_to_vec(),
-            },
-            Resource::Endpoint(endpoint) => Any {
-                type_url: type_url::ENDPOINT.to_string(),
-                value: endpoint.encode_to_vec(),
-            },
-            Resource::Route(route) => Any {
-                type_url: type_url::ROUTE.to_string(),
-                value: route.encode_to_vec(),
-            },
-            Resource::Listener(listener) => Any {
-                type_url: type_url::LISTENER.to_string(),
-                value: listener.encode_to_vec(),
-            },
-            Resource::Secret(secret) => Any {
-                type_url: type_url::SECRET.to_string(),
-                value: secret.encode_to_vec(),
-            },
-            Resource::Runtime(runtime) => Any {
-                type_url: type_url::RUNTIME.to_string(),
-                value: runtime.encode_to_vec(),
-            },
-            Resource::ScopedRoute(route) => Any {
-                type_url: type_url::SCOPED_ROUTE.to_string(),
-                value: route.encode_to_vec(),
-            },
-            Resource::ExtensionConfig(config) => Any {
-                type_url: type_url::EXTENSION_CONFIG.to_string(),
-                value: config.encode_to_vec(),
-            },
+        let resource = self.as_xds_resource();
+        Any {
+            type_url: resource.type_url().to_string(),
+            value: resource.encode_value().into(),
         }
     }
 
+    // Decodes an encoded resource of one of the built-in types. Returns None for other types,
+    // which can't be decoded without knowing what they are.
+    pub fn from_any(any: &Any) -> Option<Resource> {
+        let value = any.value.clone();
+        let resource = match any.type_url.as_str() {
+            type_url::CLUSTER => Resource::Cluster(Cluster::decode(value).ok()?),
+            type_url::ENDPOINT => Resource::Endpoint(ClusterLoadAssignment::decode(value).ok()?),
+            type_url::ROUTE => Resource::Route(RouteConfiguration::decode(value).ok()?),
+            type_url::VIRTUAL_HOST => Resource::VirtualHost(VirtualHost::decode(value).ok()?),
+            type_url::LISTENER => Resource::Listener(Listener::decode(value).ok()?),
+            type_url::SECRET => Resource::Secret(Secret::decode(value).ok()?),
+            type_url::RUNTIME => Resource::Runtime(Runtime::decode(value).ok()?),
+            type_url::SCOPED_ROUTE => {
+                Resource::ScopedRoute(ScopedRouteConfiguration::decode(value).ok()?)
+            }
+            type_url::EXTENSION_CONFIG => {
+                Resource::ExtensionConfig(TypedExtensionConfig::decode(value).ok()?)
+            }
+            _ => return None,
+        };
+        Some(resource)
+    }
+
     fn encode_to_vec(&self) -> Vec<u8> {
+        self.as_xds_resource().encode_value()
+    }
+
+    fn as_xds_resource(&self) -> &dyn XdsResource {
         match self {
-            Resource::Cluster(cluster) => cluster.encode_to_vec(),
-            Resource::Endpoint(endpoint) => endpoint.encode_to_vec(),
-            Resource::Route(route) => route.encode_to_vec(),
-            Resource::Listener(listener) => listener.encode_to_vec(),
-            Resource::Secret(secret) => secret.encode_to_vec(),
-            Resource::Runtime(runtime) => runtime.encode_to_vec(),
-            Resource::ScopedRoute(route) => route.encode_to_vec(),
-            Resource::ExtensionConfig(config) => config.encode_to_vec(),
+            Resource::Cluster(cluster) => cluster,
+            Resource::Endpoint(endpoint) => endpoint,
+            Resource::Route(route) => route,
+            Resource::VirtualHost(virtual_host) => virtual_host,
+            Resource::Listener(listener) => listener,
+            Resource::Secret(secret) => secret,
+            Resource::Runtime(runtime) => runtime,
+            Resource::ScopedRoute(route) => route,
+            Resource::ExtensionConfig(config) => config,
+            Resource::Custom(resource) => resource.as_ref(),
         }
     }
 }
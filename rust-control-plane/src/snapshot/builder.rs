@@ -0,0 +1,142 @@
+#[cfg(test)]
+mod test;
+
+use crate::snapshot::{ConsistencyError, Resource, Resources, Snapshot, XdsResource};
+use data_plane_api::envoy::config::cluster::v3::Cluster;
+use data_plane_api::envoy::config::core::v3::TypedExtensionConfig;
+use data_plane_api::envoy::config::endpoint::v3::ClusterLoadAssignment;
+use data_plane_api::envoy::config::listener::v3::Listener;
+use data_plane_api::envoy::config::route::v3::{
+    RouteConfiguration, ScopedRouteConfiguration, VirtualHost,
+};
+use data_plane_api::envoy::extensions::transport_sockets::tls::v3::Secret;
+use data_plane_api::envoy::service::runtime::v3::Runtime;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+// Builds a snapshot from typed resources, keyed by their name, or cluster_name for load
+// assignments. Types without a version set are content addressed.
+#[derive(Debug, Default)]
+pub struct SnapshotBuilder {
+    resources: HashMap<String, Vec<Resource>>,
+    versions: HashMap<String, String>,
+}
+
+// Why a snapshot couldn't be built.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BuildError {
+    // A resource has no name, so clients can't subscribe to it.
+    UnnamedResource { type_url: String },
+    // More than one resource of a type has the same name.
+    DuplicateResource { type_url: String, name: String },
+    Inconsistent(ConsistencyError),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BuildError::UnnamedResource { type_url } => {
+                write!(f, "unnamed resource of type {}", type_url)
+            }
+            BuildError::DuplicateResource { type_url, name } => {
+                write!(f, "duplicate resource {} of type {}", name, type_url)
+            }
+            BuildError::Inconsistent(err) => err.fmt(f),
+        }
+    }
+}
+
+impl Error for BuildError {}
+
+impl SnapshotBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cluster(self, cluster: Cluster) -> Self {
+        self.resource(Resource::Cluster(cluster))
+    }
+
+    pub fn endpoint(self, endpoint: ClusterLoadAssignment) -> Self {
+        self.resource(Resource::Endpoint(endpoint))
+    }
+
+    pub fn route(self, route: RouteConfiguration) -> Self {
+        self.resource(Resource::Route(route))
+    }
+
+    // Virtual hosts must be named <route_config_name>/<name>, as VHDS expects.
+    pub fn virtual_host(self, virtual_host: VirtualHost) -> Self {
+        self.resource(Resource::VirtualHost(virtual_host))
+    }
+
+    pub fn listener(self, listener: Listener) -> Self {
+        self.resource(Resource::Listener(listener))
+    }
+
+    pub fn secret(self, secret: Secret) -> Self {
+        self.resource(Resource::Secret(secret))
+    }
+
+    pub fn runtime(self, runtime: Runtime) -> Self {
+        self.resource(Resource::Runtime(runtime))
+    }
+
+    pub fn scoped_route(self, route: ScopedRouteConfiguration) -> Self {
+        self.resource(Resource::ScopedRoute(route))
+    }
+
+    pub fn extension_config(self, config: TypedExtensionConfig) -> Self {
+        self.resource(Resource::ExtensionConfig(config))
+    }
+
+    // Adds a resource of a type without a typed method, keyed by its type URL.
+    pub fn custom<R: XdsResource>(self, resource: R) -> Self {
+        self.resource(Resource::custom(resource))
+    }
+
+    pub fn resource(mut self, resource: Resource) -> Self {
+        self.resources
+            .entry(resource.type_url().to_string())
+            .or_default()
+            .push(resource);
+        self
+    }
+
+    // Sets the version of a type. A type with a version but no resources is included in the
+    // snapshot, telling clients there are none.
+    pub fn version(mut self, type_url: &str, version: &str) -> Self {
+        self.versions
+            .insert(type_url.to_string(), version.to_string());
+        self
+    }
+
+    // Builds the snapshot, failing if any resource is unnamed or duplicated, or if the
+    // snapshot is inconsistent.
+    pub fn build(self) -> Result<Snapshot, BuildError> {
+        let mut snapshot = Snapshot::new();
+        for (type_url, version) in &self.versions {
+            snapshot.insert(type_url.clone(), Resources::new(version.clone()));
+        }
+        for (type_url, items) in self.resources {
+            let mut resources = match self.versions.get(&type_url) {
+                Some(version) => Resources::new(version.clone()),
+                None => Resources::content_addressed(),
+            };
+            for resource in items {
+                let name = resource.name().to_string();
+                if name.is_empty() {
+                    return Err(BuildError::UnnamedResource { type_url });
+                }
+                if resources.items.contains_key(&name) {
+                    return Err(BuildError::DuplicateResource { type_url, name });
+                }
+                resources.insert(name, resource);
+            }
+            snapshot.insert(type_url, resources);
+        }
+        snapshot.consistent().map_err(BuildError::Inconsistent)?;
+        Ok(snapshot)
+    }
+}
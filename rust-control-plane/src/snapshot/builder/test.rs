@@ -0,0 +1,141 @@
+use crate::snapshot::builder::{BuildError, SnapshotBuilder};
+use crate::snapshot::type_url::{CLUSTER, ENDPOINT, LISTENER, ROUTE};
+use crate::snapshot::XdsResource;
+use data_plane_api::envoy::config::cluster::v3::cluster::{ClusterDiscoveryType, DiscoveryType};
+use data_plane_api::envoy::config::cluster::v3::Cluster;
+use data_plane_api::envoy::config::endpoint::v3::ClusterLoadAssignment;
+use data_plane_api::envoy::config::route::v3::RouteConfiguration;
+use std::collections::HashMap;
+
+fn cluster(name: &str) -> Cluster {
+    Cluster {
+        name: name.to_string(),
+        cluster_discovery_type: Some(ClusterDiscoveryType::Type(DiscoveryType::Eds as i32)),
+        ..Cluster::default()
+    }
+}
+
+fn endpoint(name: &str) -> ClusterLoadAssignment {
+    ClusterLoadAssignment {
+        cluster_name: name.to_string(),
+        ..ClusterLoadAssignment::default()
+    }
+}
+
+#[test]
+fn test_snapshot_builder_keys_resources_by_name() {
+    let snapshot = SnapshotBuilder::new()
+        .cluster(cluster("a"))
+        .endpoint(endpoint("a"))
+        .route(RouteConfiguration {
+            name: "routes".to_string(),
+            ..RouteConfiguration::default()
+        })
+        .version(CLUSTER, "1")
+        .build()
+        .unwrap();
+    assert_eq!(snapshot.version(CLUSTER), "1");
+    assert!(snapshot.resources(CLUSTER).unwrap().get("a").is_some());
+    assert!(snapshot.resources(ENDPOINT).unwrap().get("a").is_some());
+    assert!(snapshot.resources(ROUTE).unwrap().get("routes").is_some());
+    assert!(snapshot.resources(LISTENER).is_none());
+}
+
+#[test]
+fn test_snapshot_builder_content_addresses_unversioned_types() {
+    let build = |names: &[&str]| {
+        let mut builder = SnapshotBuilder::new();
+        for name in names {
+            builder = builder.endpoint(endpoint(name));
+        }
+        builder.build().unwrap()
+    };
+    let snapshot = build(&["a", "b"]);
+    assert!(snapshot.resources(ENDPOINT).unwrap().is_content_addressed());
+    assert_eq!(
+        snapshot.version(ENDPOINT),
+        build(&["b", "a"]).version(ENDPOINT)
+    );
+    assert_ne!(snapshot.version(ENDPOINT), build(&["a"]).version(ENDPOINT));
+}
+
+#[test]
+fn test_snapshot_builder_includes_versioned_empty_types() {
+    let snapshot = SnapshotBuilder::new()
+        .version(LISTENER, "1")
+        .build()
+        .unwrap();
+    assert_eq!(snapshot.version(LISTENER), "1");
+    assert!(snapshot.resources(LISTENER).unwrap().items.is_empty());
+}
+
+#[test]
+fn test_snapshot_builder_validates() {
+    let unnamed = SnapshotBuilder::new().endpoint(endpoint("")).build();
+    assert_eq!(
+        unnamed.unwrap_err(),
+        BuildError::UnnamedResource {
+            type_url: ENDPOINT.to_string()
+        }
+    );
+
+    let duplicate = SnapshotBuilder::new()
+        .endpoint(endpoint("a"))
+        .endpoint(endpoint("a"))
+        .build();
+    assert_eq!(
+        duplicate.unwrap_err(),
+        BuildError::DuplicateResource {
+            type_url: ENDPOINT.to_string(),
+            name: "a".to_string()
+        }
+    );
+
+    let inconsistent = SnapshotBuilder::new().cluster(cluster("a")).build();
+    match inconsistent.unwrap_err() {
+        BuildError::Inconsistent(err) => assert_eq!(
+            err.dangling,
+            HashMap::from([(ENDPOINT.to_string(), vec!["a".to_string()])])
+        ),
+        err => panic!("unexpected error: {}", err),
+    }
+}
+
+#[derive(Debug)]
+struct Greeting {
+    name: String,
+    message: String,
+}
+
+impl XdsResource for Greeting {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn type_url(&self) -> &str {
+        "type.googleapis.com/example.Greeting"
+    }
+
+    fn encode_value(&self) -> Vec<u8> {
+        self.message.as_bytes().to_vec()
+    }
+}
+
+#[test]
+fn test_snapshot_builder_custom_resources() {
+    let snapshot = SnapshotBuilder::new()
+        .custom(Greeting {
+            name: "hello".to_string(),
+            message: "hello world".to_string(),
+        })
+        .version("type.googleapis.com/example.Greeting", "1")
+        .build()
+        .unwrap();
+    let resources = snapshot
+        .resources("type.googleapis.com/example.Greeting")
+        .unwrap();
+    assert_eq!(resources.version, "1");
+    let any = resources.get("hello").unwrap().into_any();
+    assert_eq!(any.type_url, "type.googleapis.com/example.Greeting");
+    assert_eq!(any.value, &b"hello world"[..]);
+}
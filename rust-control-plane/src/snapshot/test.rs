@@ -0,0 +1,181 @@
+use crate::snapshot::type_url::{CLUSTER, ENDPOINT, LISTENER, ROUTE};
+use crate::snapshot::{
+    hash_resource, ConsistencyError, Resource, Resources, Snapshot, VersionedResource,
+};
+use data_plane_api::envoy::config::cluster::v3::cluster::{
+    ClusterDiscoveryType, DiscoveryType, EdsClusterConfig,
+};
+use data_plane_api::envoy::config::cluster::v3::Cluster;
+use data_plane_api::envoy::config::core::v3::Metadata;
+use data_plane_api::envoy::config::endpoint::v3::ClusterLoadAssignment;
+use data_plane_api::envoy::config::listener::v3::filter::ConfigType;
+use data_plane_api::envoy::config::listener::v3::{Filter, FilterChain, Listener};
+use data_plane_api::envoy::config::route::v3::RouteConfiguration;
+use data_plane_api::envoy::extensions::filters::network::http_connection_manager::v3::http_connection_manager::RouteSpecifier;
+use data_plane_api::envoy::extensions::filters::network::http_connection_manager::v3::{
+    HttpConnectionManager, Rds,
+};
+use data_plane_api::google::protobuf::{value, Any, Struct, Value};
+use prost::Message;
+use std::collections::{BTreeMap, HashMap};
+
+fn eds_cluster(name: &str, service_name: &str) -> Resource {
+    Resource::Cluster(Cluster {
+        name: name.to_string(),
+        cluster_discovery_type: Some(ClusterDiscoveryType::Type(DiscoveryType::Eds as i32)),
+        eds_cluster_config: Some(EdsClusterConfig {
+            service_name: service_name.to_string(),
+            ..EdsClusterConfig::default()
+        }),
+        ..Cluster::default()
+    })
+}
+
+fn static_cluster(name: &str) -> Resource {
+    Resource::Cluster(Cluster {
+        name: name.to_string(),
+        cluster_discovery_type: Some(ClusterDiscoveryType::Type(DiscoveryType::Static as i32)),
+        ..Cluster::default()
+    })
+}
+
+fn endpoint(name: &str) -> Resource {
+    Resource::Endpoint(ClusterLoadAssignment {
+        cluster_name: name.to_string(),
+        ..ClusterLoadAssignment::default()
+    })
+}
+
+fn rds_listener(name: &str, route_config_name: &str) -> Resource {
+    let manager = HttpConnectionManager {
+        route_specifier: Some(RouteSpecifier::Rds(Rds {
+            route_config_name: route_config_name.to_string(),
+            ..Rds::default()
+        })),
+        ..HttpConnectionManager::default()
+    };
+    Resource::Listener(Listener {
+        name: name.to_string(),
+        filter_chains: vec![FilterChain {
+            filters: vec![Filter {
+                name: "envoy.filters.network.http_connection_manager".to_string(),
+                config_type: Some(ConfigType::TypedConfig(Any {
+                    type_url: "type.googleapis.com/envoy.extensions.filters.network.http_connection_manager.v3.HttpConnectionManager".to_string(),
+                    value: manager.encode_to_vec().into(),
+                })),
+            }],
+            ..FilterChain::default()
+        }],
+        ..Listener::default()
+    })
+}
+
+fn route(name: &str) -> Resource {
+    Resource::Route(RouteConfiguration {
+        name: name.to_string(),
+        ..RouteConfiguration::default()
+    })
+}
+
+fn snapshot(resources: Vec<(&str, &str, Resource)>) -> Snapshot {
+    let mut snapshot = Snapshot::new();
+    for (type_url, name, resource) in resources {
+        snapshot
+            .resources
+            .entry(type_url.to_string())
+            .or_insert_with(|| Resources::new("1".to_string()))
+            .insert(name.to_string(), resource);
+    }
+    snapshot
+}
+
+#[test]
+fn test_snapshot_consistent() {
+    let snapshot = snapshot(vec![
+        (CLUSTER, "a", eds_cluster("a", "")),
+        (CLUSTER, "b", eds_cluster("b", "b-service")),
+        (CLUSTER, "c", static_cluster("c")),
+        (ENDPOINT, "a", endpoint("a")),
+        (ENDPOINT, "b-service", endpoint("b-service")),
+        (LISTENER, "http", rds_listener("http", "routes")),
+        (ROUTE, "routes", route("routes")),
+    ]);
+    assert_eq!(snapshot.consistent(), Ok(()));
+}
+
+#[test]
+fn test_snapshot_inconsistent_lists_dangling_references() {
+    let snapshot = snapshot(vec![
+        (CLUSTER, "a", eds_cluster("a", "")),
+        (CLUSTER, "b", eds_cluster("b", "b-service")),
+        (ENDPOINT, "b", endpoint("b")),
+        (LISTENER, "http", rds_listener("http", "routes")),
+        (LISTENER, "https", rds_listener("https", "routes")),
+    ]);
+    assert_eq!(
+        snapshot.consistent(),
+        Err(ConsistencyError {
+            dangling: HashMap::from([
+                (
+                    ENDPOINT.to_string(),
+                    vec!["a".to_string(), "b-service".to_string()]
+                ),
+                (ROUTE.to_string(), vec!["routes".to_string()]),
+            ]),
+        })
+    );
+}
+
+#[test]
+fn test_versioned_resource_shares_encoding_between_clones() {
+    let resource = VersionedResource::new(route("routes"));
+    let clone = resource.clone();
+    assert_eq!(clone.to_any(), route("routes").into_any());
+    assert!(std::ptr::eq(
+        resource.any.get().unwrap(),
+        clone.any.get().unwrap()
+    ));
+}
+
+#[test]
+fn test_resource_from_any() {
+    let any = route("routes").into_any();
+    let resource = Resource::from_any(&any).unwrap();
+    assert_eq!(resource.name(), "routes");
+    assert_eq!(resource.into_any(), any);
+
+    let unknown = Any {
+        type_url: "type.googleapis.com/example.Unknown".to_string(),
+        value: any.value,
+    };
+    assert!(Resource::from_any(&unknown).is_none());
+}
+
+fn cluster_with_metadata(keys: &[&str]) -> Resource {
+    let mut fields = BTreeMap::new();
+    for key in keys {
+        let value = Value {
+            kind: Some(value::Kind::StringValue(key.to_string())),
+        };
+        fields.insert(key.to_string(), value);
+    }
+    Resource::Cluster(Cluster {
+        name: "a".to_string(),
+        metadata: Some(Metadata {
+            filter_metadata: BTreeMap::from([("test".to_string(), Struct { fields })]),
+            ..Metadata::default()
+        }),
+        ..Cluster::default()
+    })
+}
+
+#[test]
+fn test_hash_resource_ignores_map_insertion_order() {
+    let keys: Vec<String> = (0..32).map(|i| format!("key-{}", i)).collect();
+    let forwards: Vec<&str> = keys.iter().map(String::as_str).collect();
+    let backwards: Vec<&str> = forwards.iter().rev().copied().collect();
+    assert_eq!(
+        hash_resource(&cluster_with_metadata(&forwards)),
+        hash_resource(&cluster_with_metadata(&backwards))
+    );
+}
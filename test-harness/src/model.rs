@@ -15,31 +15,22 @@ use data_plane_api::envoy::config::endpoint::v3::Endpoint as EndpointPb;
 use data_plane_api::envoy::config::endpoint::v3::{
     ClusterLoadAssignment, LbEndpoint, LocalityLbEndpoints,
 };
+use rust_control_plane::snapshot::builder::SnapshotBuilder;
 use rust_control_plane::snapshot::type_url;
-use rust_control_plane::snapshot::{Resource, Resources, Snapshot};
+use rust_control_plane::snapshot::Snapshot;
 use std::collections::HashMap;
 
 pub fn to_snapshot(clusters: &[Cluster], version: &str, ads: bool) -> Snapshot {
-    let mut snapshot = Snapshot::new();
-    let mut cluster_set = Resources::new(version.to_string());
-    let mut endpoint_set = Resources::new(version.to_string());
-
-    clusters.iter().for_each(|cluster| {
+    let mut builder = SnapshotBuilder::new()
+        .version(type_url::CLUSTER, version)
+        .version(type_url::ENDPOINT, version);
+    for cluster in clusters {
         if !cluster.hidden {
-            cluster_set.items.insert(
-                cluster.name.clone(),
-                Resource::Cluster(cluster.to_proto(ads)),
-            );
+            builder = builder.cluster(cluster.to_proto(ads));
         }
-        endpoint_set.items.insert(
-            cluster.name.clone(),
-            Resource::Endpoint(cluster.endpoints_to_proto()),
-        );
-    });
-
-    snapshot.insert(type_url::CLUSTER.to_string(), cluster_set);
-    snapshot.insert(type_url::ENDPOINT.to_string(), endpoint_set);
-    snapshot
+        builder = builder.endpoint(cluster.endpoints_to_proto());
+    }
+    builder.build().expect("invalid snapshot")
 }
 
 const XDS_CLUSTER_NAME: &str = "xds";
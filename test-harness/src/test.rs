@@ -6,6 +6,7 @@ use data_plane_api::envoy::service::discovery::v3::aggregated_discovery_service_
 use data_plane_api::envoy::service::endpoint::v3::endpoint_discovery_service_server::EndpointDiscoveryServiceServer;
 use futures::future::FutureExt;
 use rust_control_plane::cache::snapshot::SnapshotCache;
+use rust_control_plane::service::callbacks::NoopCallbacks;
 use rust_control_plane::service::common::Service;
 use std::future::Future;
 use std::mem;
@@ -52,9 +53,9 @@ impl Test {
     fn serve_with_shutdown(&mut self) {
         let (tx, rx) = oneshot::channel::<()>();
         let addr = self.addr.parse().unwrap();
-        let cds_service = Service::new(self.cache.clone());
-        let eds_service = Service::new(self.cache.clone());
-        let ads_service = Service::new(self.cache.clone());
+        let cds_service = Service::new(self.cache.clone(), Arc::new(NoopCallbacks));
+        let eds_service = Service::new(self.cache.clone(), Arc::new(NoopCallbacks));
+        let ads_service = Service::new(self.cache.clone(), Arc::new(NoopCallbacks));
         let cds = ClusterDiscoveryServiceServer::new(cds_service);
         let eds = EndpointDiscoveryServiceServer::new(eds_service);
         let ads = AggregatedDiscoveryServiceServer::new(ads_service);